@@ -0,0 +1,732 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use disasm::ORIGIN;
+use cpu::MEMORY_SIZE;
+
+// Includes nested deeper than this are assumed to include themselves.
+const MAX_INCLUDE_DEPTH: usize = 16;
+// Likewise for defines that refer to other defines.
+const MAX_DEFINE_DEPTH: usize = 32;
+
+const PUNCTUATION: &'static [&'static str] =
+    &["<<", ">>", ",", ":", "(", ")", "[", "]", "+", "-", "*", "/", "%", "&", "|", "^", "~"];
+
+// Binary operators from the loosest binding to the tightest.
+const PRECEDENCE: &'static [&'static [&'static str]] =
+    &[&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/", "%"]];
+
+// An error in the source, at a 1-based line and column of a file. Source that
+// didn't come from a file is called `<input>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.file, self.line, self.column, self.message)
+    }
+}
+
+impl Error for AsmError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+// Assembles source written in the syntax the disassembler produces into a ROM
+// to be loaded at 0x200. Besides instructions, the source can have:
+//
+//   label:                 the address of the next instruction or data
+//   define NAME expr       a named constant
+//   db expr, ...           bytes
+//   dw expr, ...           big endian words
+//   include "file"         the lines of another file
+//
+// Numbers are decimal, 0x hex or 0b binary, and can be combined with
+// + - * / % & | ^ << >> ~ and parentheses. Comments start with `;`.
+// Included files are found relative to the working directory.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut assembler = Assembler::new();
+    assembler.read_source(source, "<input>", None, 0)?;
+    assembler.finish()
+}
+
+// Assembles a file. Files it includes are found relative to it.
+pub fn assemble_file(path: &Path) -> Result<Vec<u8>, AsmError> {
+    let name = path.display().to_string();
+    let source = read_file(path).map_err(|message| AsmError { file: name.clone(), line: 0, column: 0, message: message })?;
+    let mut assembler = Assembler::new();
+    assembler.read_source(&source, &name, path.parent(), 0)?;
+    assembler.finish()
+}
+
+fn read_file(path: &Path) -> Result<String, String> {
+    let mut source = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut source))
+        .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+    Ok(source)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(i64),
+    Str(String),
+    Punct(&'static str),
+}
+
+#[derive(Debug, Clone)]
+struct Lexeme {
+    token: Token,
+    column: usize,
+}
+
+// Splits one line of source into tokens, stopping at a comment. Errors are
+// returned as the column and a message.
+fn lex(line: &str) -> Result<Vec<Lexeme>, (usize, String)> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut lexemes = Vec::new();
+    let mut position = 0;
+    while position < chars.len() {
+        let c = chars[position];
+        let column = position + 1;
+        if c.is_whitespace() {
+            position += 1;
+            continue;
+        }
+        if c == ';' {
+            break;
+        }
+        let token = if c == '"' {
+            let start = position + 1;
+            position = start;
+            while position < chars.len() && chars[position] != '"' {
+                position += 1;
+            }
+            if position == chars.len() {
+                return Err((column, "unterminated string".to_string()));
+            }
+            position += 1;
+            Token::Str(chars[start .. position - 1].iter().collect())
+        } else if c.is_ascii_digit() {
+            let start = position;
+            while position < chars.len() && chars[position].is_ascii_alphanumeric() {
+                position += 1;
+            }
+            let text: String = chars[start .. position].iter().collect();
+            match parse_number(&text) {
+                Some(value) => Token::Number(value),
+                None => return Err((column, format!("invalid number {}", text))),
+            }
+        } else if c.is_alphabetic() || c == '_' || c == '.' {
+            let start = position;
+            while position < chars.len()
+                && (chars[position].is_alphanumeric() || chars[position] == '_' || chars[position] == '.') {
+                position += 1;
+            }
+            Token::Ident(chars[start .. position].iter().collect())
+        } else {
+            let rest: String = chars[position .. (position + 2).min(chars.len())].iter().collect();
+            match PUNCTUATION.iter().find(|punct| rest.starts_with(*punct)) {
+                Some(punct) => {
+                    position += punct.len();
+                    Token::Punct(*punct)
+                }
+                None => return Err((column, format!("unexpected character {:?}", c))),
+            }
+        };
+        lexemes.push(Lexeme { token: token, column: column });
+    }
+    Ok(lexemes)
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let lower = text.to_lowercase();
+    if lower.starts_with("0x") {
+        i64::from_str_radix(&lower[2..], 16).ok()
+    } else if lower.starts_with("0b") {
+        i64::from_str_radix(&lower[2..], 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+// Splits an instruction's operands at the commas that aren't in parentheses.
+fn split_operands(tokens: &[Lexeme]) -> Result<Vec<&[Lexeme]>, (usize, String)> {
+    let mut operands = Vec::new();
+    if tokens.is_empty() {
+        return Ok(operands);
+    }
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, lexeme) in tokens.iter().enumerate() {
+        match lexeme.token {
+            Token::Punct("(") => depth += 1,
+            Token::Punct(")") => depth -= 1,
+            Token::Punct(",") if depth == 0 => {
+                if index == start {
+                    return Err((lexeme.column, "missing operand".to_string()));
+                }
+                operands.push(&tokens[start .. index]);
+                start = index + 1;
+            }
+            _ => { }
+        }
+    }
+    if start == tokens.len() {
+        return Err((tokens[tokens.len() - 1].column, "missing operand".to_string()));
+    }
+    operands.push(&tokens[start ..]);
+    Ok(operands)
+}
+
+enum Kind {
+    Instruction(String),
+    Bytes,
+    Words,
+}
+
+// A line that produces output, kept from the first pass until all the labels
+// are known.
+struct Statement {
+    file: String,
+    line: usize,
+    column: usize,
+    kind: Kind,
+    operands: Vec<Vec<Lexeme>>,
+}
+
+enum Symbol {
+    Address(usize),
+    // An expression, and the file and line it was defined on.
+    Define(Vec<Lexeme>, String, usize),
+}
+
+// One of an instruction's operands, once registers have been told apart from
+// expressions.
+enum Operand<'a> {
+    V(u16),
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    Long(&'a [Lexeme]),
+    Value(&'a [Lexeme]),
+}
+
+struct Assembler {
+    statements: Vec<Statement>,
+    symbols: HashMap<String, Symbol>,
+    address: usize,
+}
+
+impl Assembler {
+    fn new() -> Assembler {
+        Assembler { statements: Vec::new(), symbols: HashMap::new(), address: ORIGIN }
+    }
+
+    // The first pass: records every statement and works out the address of
+    // every label.
+    fn read_source(&mut self, source: &str, file: &str, dir: Option<&Path>, depth: usize) -> Result<(), AsmError> {
+        for (index, text) in source.lines().enumerate() {
+            let line = index + 1;
+            let error = |column: usize, message: String| AsmError {
+                file: file.to_string(), line: line, column: column, message: message
+            };
+            let tokens = lex(text).map_err(|(column, message)| error(column, message))?;
+
+            let mut rest = &tokens[..];
+            while rest.len() >= 2 && rest[1].token == Token::Punct(":") {
+                match rest[0].token {
+                    Token::Ident(ref name) => {
+                        let address = self.address;
+                        self.define(name, Symbol::Address(address)).map_err(|message| error(rest[0].column, message))?;
+                    }
+                    _ => return Err(error(rest[0].column, "expected a label".to_string())),
+                }
+                rest = &rest[2..];
+            }
+            if rest.is_empty() {
+                continue;
+            }
+
+            let word = match rest[0].token {
+                Token::Ident(ref word) => word.clone(),
+                _ => return Err(error(rest[0].column, "expected an instruction".to_string())),
+            };
+            let column = rest[0].column;
+            let operands: Vec<Vec<Lexeme>> = split_operands(&rest[1..])
+                .map_err(|(column, message)| error(column, message))?
+                .into_iter().map(|operand| operand.to_vec()).collect();
+
+            let (kind, len) = match word.to_lowercase().as_str() {
+                "define" => {
+                    match (rest.get(1).map(|lexeme| &lexeme.token), rest.len() > 2) {
+                        (Some(&Token::Ident(ref name)), true) => {
+                            let symbol = Symbol::Define(rest[2..].to_vec(), file.to_string(), line);
+                            self.define(name, symbol).map_err(|message| error(rest[1].column, message))?;
+                        }
+                        _ => return Err(error(column, "expected define NAME value".to_string())),
+                    }
+                    continue;
+                }
+                "include" => {
+                    let name = match (rest.len(), rest.get(1).map(|lexeme| &lexeme.token)) {
+                        (2, Some(&Token::Str(ref name))) => name.clone(),
+                        _ => return Err(error(column, "expected include \"file\"".to_string())),
+                    };
+                    if depth == MAX_INCLUDE_DEPTH {
+                        return Err(error(column, "includes are nested too deeply".to_string()));
+                    }
+                    let path = match dir {
+                        Some(dir) => dir.join(&name),
+                        None => Path::new(&name).to_path_buf(),
+                    };
+                    let source = read_file(&path).map_err(|message| error(rest[1].column, message))?;
+                    self.read_source(&source, &path.display().to_string(), path.parent(), depth + 1)?;
+                    continue;
+                }
+                "db" => (Kind::Bytes, operands.len()),
+                "dw" => (Kind::Words, operands.len() * 2),
+                _ => {
+                    let long = operands.iter().any(|operand| match operand[0].token {
+                        Token::Ident(ref name) => name.eq_ignore_ascii_case("long"),
+                        _ => false,
+                    });
+                    (Kind::Instruction(word.to_uppercase()), if long { 4 } else { 2 })
+                }
+            };
+            if operands.is_empty() && len == 0 {
+                return Err(error(column, format!("{} needs at least one value", word)));
+            }
+            self.address += len;
+            if self.address > MEMORY_SIZE {
+                return Err(error(column, "program doesn't fit in memory".to_string()));
+            }
+            self.statements.push(Statement {
+                file: file.to_string(), line: line, column: column, kind: kind, operands: operands
+            });
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, symbol: Symbol) -> Result<(), String> {
+        if self.symbols.contains_key(name) {
+            return Err(format!("{} is already defined", name));
+        }
+        self.symbols.insert(name.to_string(), symbol);
+        Ok(())
+    }
+
+    // The second pass: encodes every statement now that labels are known.
+    fn finish(self) -> Result<Vec<u8>, AsmError> {
+        let mut rom = Vec::new();
+        for statement in &self.statements {
+            let error = |column: usize, message: String| AsmError {
+                file: statement.file.clone(), line: statement.line, column: column, message: message
+            };
+            match statement.kind {
+                Kind::Bytes => for operand in &statement.operands {
+                    rom.push(self.value(operand, -0x80, 0xFF, statement)? as u8);
+                },
+                Kind::Words => for operand in &statement.operands {
+                    let value = self.value(operand, -0x8000, 0xFFFF, statement)?;
+                    rom.push((value >> 8) as u8);
+                    rom.push(value as u8);
+                },
+                Kind::Instruction(ref mnemonic) => {
+                    let mut operands = Vec::new();
+                    for operand in &statement.operands {
+                        operands.push(classify(operand).map_err(|(column, message)| error(column, message))?);
+                    }
+                    let (opcode, long) = self.encode(mnemonic, &operands, statement)?;
+                    rom.push((opcode >> 8) as u8);
+                    rom.push(opcode as u8);
+                    if let Some(long) = long {
+                        rom.push((long >> 8) as u8);
+                        rom.push(long as u8);
+                    }
+                }
+            }
+        }
+        Ok(rom)
+    }
+
+    // Encodes an instruction, returning its opcode and, for LD I, long, the
+    // address that follows it.
+    fn encode(&self, mnemonic: &str, operands: &[Operand], statement: &Statement) -> Result<(u16, Option<u16>), AsmError> {
+        use self::Operand::*;
+        let byte = |tokens: &[Lexeme]| self.value(tokens, -0x80, 0xFF, statement).map(|value| value as u16 & 0xFF);
+        let address = |tokens: &[Lexeme]| self.value(tokens, 0, 0xFFF, statement).map(|value| value as u16);
+        let nibble = |tokens: &[Lexeme]| self.value(tokens, 0, 0xF, statement).map(|value| value as u16);
+        let xy = |x: u16, y: u16| x << 8 | y << 4;
+
+        let opcode = match (mnemonic, operands) {
+            ("CLS", &[]) => 0x00E0,
+            ("RET", &[]) => 0x00EE,
+            ("SCR", &[]) => 0x00FB,
+            ("SCL", &[]) => 0x00FC,
+            ("EXIT", &[]) => 0x00FD,
+            ("LOW", &[]) => 0x00FE,
+            ("HIGH", &[]) => 0x00FF,
+            ("AUDIO", &[]) => 0xF002,
+            ("SCD", &[Value(n)]) => 0x00C0 | nibble(n)?,
+            ("SCU", &[Value(n)]) => 0x00D0 | nibble(n)?,
+            ("JP", &[Value(nnn)]) => 0x1000 | address(nnn)?,
+            ("JP", &[V(0), Value(nnn)]) => 0xB000 | address(nnn)?,
+            ("CALL", &[Value(nnn)]) => 0x2000 | address(nnn)?,
+            ("SE", &[V(x), Value(kk)]) => 0x3000 | x << 8 | byte(kk)?,
+            ("SNE", &[V(x), Value(kk)]) => 0x4000 | x << 8 | byte(kk)?,
+            ("SE", &[V(x), V(y)]) => 0x5000 | xy(x, y),
+            ("SAVE", &[V(x), V(y)]) => 0x5002 | xy(x, y),
+            ("LOAD", &[V(x), V(y)]) => 0x5003 | xy(x, y),
+            ("LD", &[V(x), Value(kk)]) => 0x6000 | x << 8 | byte(kk)?,
+            ("ADD", &[V(x), Value(kk)]) => 0x7000 | x << 8 | byte(kk)?,
+            ("LD", &[V(x), V(y)]) => 0x8000 | xy(x, y),
+            ("OR", &[V(x), V(y)]) => 0x8001 | xy(x, y),
+            ("AND", &[V(x), V(y)]) => 0x8002 | xy(x, y),
+            ("XOR", &[V(x), V(y)]) => 0x8003 | xy(x, y),
+            ("ADD", &[V(x), V(y)]) => 0x8004 | xy(x, y),
+            ("SUB", &[V(x), V(y)]) => 0x8005 | xy(x, y),
+            ("SHR", &[V(x), V(y)]) => 0x8006 | xy(x, y),
+            ("SHR", &[V(x)]) => 0x8006 | xy(x, x),
+            ("SUBN", &[V(x), V(y)]) => 0x8007 | xy(x, y),
+            ("SHL", &[V(x), V(y)]) => 0x800E | xy(x, y),
+            ("SHL", &[V(x)]) => 0x800E | xy(x, x),
+            ("SNE", &[V(x), V(y)]) => 0x9000 | xy(x, y),
+            ("LD", &[I, Value(nnn)]) => 0xA000 | address(nnn)?,
+            ("LD", &[I, Long(nnnn)]) => {
+                let long = self.value(nnnn, 0, 0xFFFF, statement)?;
+                return Ok((0xF000, Some(long as u16)));
+            }
+            ("RND", &[V(x), Value(kk)]) => 0xC000 | x << 8 | byte(kk)?,
+            ("DRW", &[V(x), V(y), Value(n)]) => 0xD000 | xy(x, y) | nibble(n)?,
+            ("SKP", &[V(x)]) => 0xE09E | x << 8,
+            ("SKNP", &[V(x)]) => 0xE0A1 | x << 8,
+            ("PLANE", &[Value(n)]) => 0xF001 | (self.value(n, 0, 3, statement)? as u16) << 8,
+            ("LD", &[V(x), Dt]) => 0xF007 | x << 8,
+            ("LD", &[V(x), K]) => 0xF00A | x << 8,
+            ("LD", &[Dt, V(x)]) => 0xF015 | x << 8,
+            ("LD", &[St, V(x)]) => 0xF018 | x << 8,
+            ("ADD", &[I, V(x)]) => 0xF01E | x << 8,
+            ("LD", &[F, V(x)]) => 0xF029 | x << 8,
+            ("LD", &[Hf, V(x)]) => 0xF030 | x << 8,
+            ("LD", &[B, V(x)]) => 0xF033 | x << 8,
+            ("PITCH", &[V(x)]) => 0xF03A | x << 8,
+            ("LD", &[IndirectI, V(x)]) => 0xF055 | x << 8,
+            ("LD", &[V(x), IndirectI]) => 0xF065 | x << 8,
+            ("LD", &[R, V(x)]) => 0xF075 | x << 8,
+            ("LD", &[V(x), R]) => 0xF085 | x << 8,
+            _ => {
+                let known = ["CLS", "RET", "SCR", "SCL", "EXIT", "LOW", "HIGH", "AUDIO", "SCD", "SCU",
+                             "JP", "CALL", "SE", "SNE", "SAVE", "LOAD", "LD", "ADD", "OR", "AND", "XOR",
+                             "SUB", "SHR", "SUBN", "SHL", "RND", "DRW", "SKP", "SKNP", "PLANE", "PITCH"];
+                let message = if known.contains(&mnemonic) {
+                    format!("invalid operands for {}", mnemonic)
+                } else {
+                    format!("unknown instruction {}", mnemonic)
+                };
+                return Err(AsmError {
+                    file: statement.file.clone(), line: statement.line, column: statement.column, message: message
+                });
+            }
+        };
+        Ok((opcode, None))
+    }
+
+    // Evaluates an expression, checking it's between min and max.
+    fn value(&self, tokens: &[Lexeme], min: i64, max: i64, statement: &Statement) -> Result<i64, AsmError> {
+        let value = self.evaluate(tokens, &statement.file, statement.line, 0)?;
+        if value < min || value > max {
+            return Err(AsmError {
+                file: statement.file.clone(), line: statement.line, column: tokens[0].column,
+                message: format!("{} doesn't fit, it must be from {} to {:#X}", value, min, max),
+            });
+        }
+        Ok(value)
+    }
+
+    fn evaluate(&self, tokens: &[Lexeme], file: &str, line: usize, depth: usize) -> Result<i64, AsmError> {
+        let mut parser = Expression { assembler: self, tokens: tokens, position: 0, file: file, line: line, depth: depth };
+        let value = parser.binary(0)?;
+        match tokens.get(parser.position) {
+            Some(lexeme) => Err(parser.error(lexeme.column, "unexpected token in expression".to_string())),
+            None => Ok(value),
+        }
+    }
+
+    fn symbol(&self, name: &str, depth: usize) -> Option<Result<i64, AsmError>> {
+        match self.symbols.get(name) {
+            Some(&Symbol::Address(address)) => Some(Ok(address as i64)),
+            Some(&Symbol::Define(ref tokens, ref file, line)) => {
+                if depth == MAX_DEFINE_DEPTH {
+                    return Some(Err(AsmError {
+                        file: file.clone(), line: line, column: tokens[0].column,
+                        message: format!("{} refers to itself", name),
+                    }));
+                }
+                Some(self.evaluate(tokens, file, line, depth + 1))
+            }
+            None => None,
+        }
+    }
+}
+
+fn classify<'a>(tokens: &'a [Lexeme]) -> Result<Operand<'a>, (usize, String)> {
+    let column = tokens[0].column;
+    if let Token::Ident(ref name) = tokens[0].token {
+        let upper = name.to_uppercase();
+        if upper == "LONG" {
+            if tokens.len() == 1 {
+                return Err((column, "expected an address after long".to_string()));
+            }
+            return Ok(Operand::Long(&tokens[1..]));
+        }
+        if tokens.len() == 1 {
+            let register = match upper.as_str() {
+                "I" => Some(Operand::I),
+                "DT" => Some(Operand::Dt),
+                "ST" => Some(Operand::St),
+                "K" => Some(Operand::K),
+                "F" => Some(Operand::F),
+                "HF" => Some(Operand::Hf),
+                "B" => Some(Operand::B),
+                "R" => Some(Operand::R),
+                _ if upper.len() == 2 && upper.starts_with('V') =>
+                    u16::from_str_radix(&upper[1..], 16).ok().map(Operand::V),
+                _ => None,
+            };
+            if let Some(register) = register {
+                return Ok(register);
+            }
+        }
+    }
+    let indirect = tokens.len() == 3 && tokens[0].token == Token::Punct("[")
+        && tokens[2].token == Token::Punct("]")
+        && match tokens[1].token { Token::Ident(ref name) => name.eq_ignore_ascii_case("I"), _ => false };
+    if indirect {
+        return Ok(Operand::IndirectI);
+    }
+    Ok(Operand::Value(tokens))
+}
+
+// A precedence climbing parser for one expression.
+struct Expression<'a> {
+    assembler: &'a Assembler,
+    tokens: &'a [Lexeme],
+    position: usize,
+    file: &'a str,
+    line: usize,
+    depth: usize,
+}
+
+impl<'a> Expression<'a> {
+    fn error(&self, column: usize, message: String) -> AsmError {
+        AsmError { file: self.file.to_string(), line: self.line, column: column, message: message }
+    }
+
+    fn punct(&self) -> Option<(&'static str, usize)> {
+        match self.tokens.get(self.position) {
+            Some(&Lexeme { token: Token::Punct(punct), column }) => Some((punct, column)),
+            _ => None,
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, AsmError> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut value = self.binary(level + 1)?;
+        while let Some((op, column)) = self.punct() {
+            if !PRECEDENCE[level].contains(&op) {
+                break;
+            }
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            value = match op {
+                "|" => value | right,
+                "^" => value ^ right,
+                "&" => value & right,
+                "+" => value.wrapping_add(right),
+                "-" => value.wrapping_sub(right),
+                "*" => value.wrapping_mul(right),
+                "/" | "%" if right == 0 => return Err(self.error(column, "division by zero".to_string())),
+                "/" => value / right,
+                "%" => value % right,
+                _ if right < 0 || right > 63 => return Err(self.error(column, "shift out of range".to_string())),
+                "<<" => value << right,
+                _ => value >> right,
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<i64, AsmError> {
+        match self.punct() {
+            Some(("-", _)) => { self.position += 1; Ok(self.unary()?.wrapping_neg()) }
+            Some(("~", _)) => { self.position += 1; Ok(!self.unary()?) }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64, AsmError> {
+        let lexeme = match self.tokens.get(self.position) {
+            Some(lexeme) => lexeme,
+            None => {
+                let column = self.tokens.last().map(|lexeme| lexeme.column + 1).unwrap_or(1);
+                return Err(self.error(column, "expected a value".to_string()));
+            }
+        };
+        self.position += 1;
+        match lexeme.token {
+            Token::Number(value) => Ok(value),
+            Token::Ident(ref name) => match self.assembler.symbol(name, self.depth) {
+                Some(value) => value,
+                None => Err(self.error(lexeme.column, format!("{} is not defined", name))),
+            },
+            Token::Punct("(") => {
+                let value = self.binary(0)?;
+                match self.punct() {
+                    Some((")", _)) => { self.position += 1; Ok(value) }
+                    _ => Err(self.error(lexeme.column, "unclosed parenthesis".to_string())),
+                }
+            }
+            _ => Err(self.error(lexeme.column, "expected a value".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use disasm;
+    use cpu::Cpu;
+    use quirks::Quirks;
+
+    // Every instruction, written the way the disassembler writes it.
+    const ALL_INSTRUCTIONS: &'static str = "\
+CLS\nRET\nSCR\nSCL\nEXIT\nLOW\nHIGH\nAUDIO\nSCD 4\nSCU 15
+JP 0x2A4\nJP V0, 0x300\nCALL 0xFFF
+SE V3, 0x10\nSNE VA, 0xFF\nSE V1, V2\nSAVE V2, V5\nLOAD V5, V2
+LD V3, 0x10\nADD V0, 0x01\nLD V1, V2\nOR V1, V2\nAND V1, V2\nXOR V1, V2
+ADD V1, V2\nSUB V1, V2\nSHR V1, V2\nSUBN V1, V2\nSHL V1, V2\nSNE VE, VF
+LD I, 0x123\nRND V7, 0x0F\nDRW V0, V1, 5\nDRW V0, V1, 0\nSKP V4\nSKNP V4
+PLANE 3\nLD V2, DT\nLD V2, K\nLD DT, V2\nLD ST, V2\nADD I, V2\nLD F, V2
+LD HF, V2\nLD B, V2\nPITCH V2\nLD [I], V2\nLD V2, [I]\nLD R, V2\nLD V2, R
+LD I, long 0x1234";
+
+    fn decode_all(rom: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut position = 0;
+        while position < rom.len() {
+            let word = |at: usize| (rom[at] as u16) << 8 | *rom.get(at + 1).unwrap_or(&0) as u16;
+            let opcode = word(position);
+            let next = if position + 3 < rom.len() { word(position + 2) } else { 0 };
+            lines.push(disasm::decode(opcode, next).unwrap());
+            position += disasm::instruction_len(opcode);
+        }
+        lines
+    }
+
+    fn error_at(source: &str) -> (usize, usize) {
+        let error = assemble(source).unwrap_err();
+        (error.line, error.column)
+    }
+
+    #[test]
+    fn test_round_trip_through_disassembler() {
+        let rom = assemble(ALL_INSTRUCTIONS).unwrap();
+        let expected: Vec<&str> = ALL_INSTRUCTIONS.lines().collect();
+        assert_eq!(decode_all(&rom), expected);
+    }
+
+    #[test]
+    fn test_case_and_spacing() {
+        let rom = assemble("  ld v3,0x10 ; comment\n\tdrw V0 , V1 , 5").unwrap();
+        assert_eq!(rom, vec![0x63, 0x10, 0xD0, 0x15]);
+    }
+
+    #[test]
+    fn test_labels_and_defines() {
+        let source = "\
+define SPEED 3
+define DOUBLE SPEED * 2
+start:  LD V0, DOUBLE
+        CALL draw
+        JP end
+draw:   LD I, sprite
+        RET
+sprite: db 0b11110000, 0x90
+end:    JP end";
+        let rom = assemble(source).unwrap();
+        assert_eq!(decode_all(&rom[..10]),
+                   vec!["LD V0, 0x06", "CALL 0x206", "JP 0x20C", "LD I, 0x20A", "RET"]);
+        assert_eq!(&rom[10..12], &[0xF0, 0x90]);
+        assert_eq!(&rom[12..], &[0x12, 0x0C]);
+    }
+
+    #[test]
+    fn test_expressions() {
+        let rom = assemble("db (1 + 2) * 4, 0x100 >> 4, ~0 & 0x0F, 7 % 4 | 8, -1\ndw 0x1234, 2 ^ 3").unwrap();
+        assert_eq!(rom, vec![12, 0x10, 0x0F, 11, 0xFF, 0x12, 0x34, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn test_long_load_of_label() {
+        let rom = assemble("LD I, long data + 1\nEXIT\ndata: db 1, 2").unwrap();
+        assert_eq!(rom, vec![0xF0, 0x00, 0x02, 0x07, 0x00, 0xFD, 1, 2]);
+    }
+
+    #[test]
+    fn test_error_positions() {
+        assert_eq!(error_at("CLS\n  FOO V1"), (2, 3));
+        assert_eq!(error_at("LD V1, missing"), (1, 8));
+        assert_eq!(error_at("LD V1, 0x100"), (1, 8));
+        assert_eq!(error_at("DRW V0, V1"), (1, 1));
+        assert_eq!(error_at("a: CLS\na: CLS"), (2, 1));
+        assert_eq!(error_at("db 1,, 2"), (1, 6));
+        assert_eq!(error_at("db 1 / 0"), (1, 6));
+        assert_eq!(error_at("define A B\ndefine B A\ndb A"), (1, 10));
+        assert_eq!(error_at("LD V1, 1 @ 2"), (1, 10));
+        let error = assemble("CLS\nBAD").unwrap_err();
+        assert_eq!(error.to_string(), "<input>:2:1: unknown instruction BAD");
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = env::temp_dir().join(format!("chip8-asm-test-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("sprites.asm")).unwrap().write_all(b"digit: db 0xF0, 0x90\n").unwrap();
+        File::create(dir.join("main.asm")).unwrap()
+            .write_all(b"LD I, digit\nEXIT\ninclude \"sprites.asm\"\n").unwrap();
+        let rom = assemble_file(&dir.join("main.asm"));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(rom.unwrap(), vec![0xA2, 0x04, 0x00, 0xFD, 0xF0, 0x90]);
+    }
+
+    #[test]
+    fn test_assembled_program_runs() {
+        let rom = assemble("LD V0, 5\nloop: ADD V1, 2\nADD V0, -1\nSE V0, 0\nJP loop\nEXIT").unwrap();
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, rom);
+        while cpu.emulate_cycle().is_ok() { }
+        assert_eq!(cpu.v(1), 10);
+    }
+}
@@ -0,0 +1,311 @@
+use std::f64::consts::PI;
+use cpu::Cpu;
+
+// What the cpu wants to play, taken at the end of each frame before the timers
+// tick. It is a plain copy, so it can be handed to an audio thread.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SoundState {
+    // The sound timer is running.
+    pub playing: bool,
+    // XO-CHIP's audio pattern, if the program has set one.
+    pub pattern: Option<[u8; 16]>,
+    pub playback_rate: f64,
+}
+
+impl SoundState {
+    pub fn of(cpu: &Cpu) -> SoundState {
+        SoundState {
+            playing: cpu.sound_timer() > 0,
+            pattern: cpu.audio_pattern().cloned(),
+            playback_rate: cpu.playback_rate(),
+        }
+    }
+
+    pub fn silent() -> SoundState {
+        SoundState { playing: false, pattern: None, playback_rate: 4000.0 }
+    }
+}
+
+// Plays back XO-CHIP's 1-bit audio pattern while the sound timer is running.
+// Call fill() with each buffer the audio output wants, after running the frame
+// the buffer belongs to.
+pub struct PatternPlayer {
+    sample_rate: u32,
+    // Position in the 128 bit pattern, in bits.
+    position: f64,
+    pub volume: f32,
+}
+
+impl PatternPlayer {
+    pub fn new(sample_rate: u32) -> PatternPlayer {
+        PatternPlayer { sample_rate: sample_rate, position: 0.0, volume: 0.25 }
+    }
+
+    // Writes samples for the current audio pattern and pitch into `out`.
+    // Outputs silence if the sound timer has run out or the program never set a
+    // pattern. Returns whether anything was played.
+    pub fn fill(&mut self, sound: &SoundState, out: &mut [f32]) -> bool {
+        let pattern = match sound.pattern {
+            Some(pattern) if sound.playing => pattern,
+            _ => {
+                self.position = 0.0;
+                for sample in out.iter_mut() { *sample = 0.0; }
+                return false;
+            }
+        };
+
+        let step = sound.playback_rate / self.sample_rate as f64;
+        for sample in out.iter_mut() {
+            let bit = self.position as usize;
+            let on = pattern[bit / 8] & (0x80 >> (bit % 8)) != 0;
+            *sample = if on { self.volume } else { -self.volume };
+            self.position = (self.position + step) % 128.0;
+        }
+        true
+    }
+}
+
+// The shape of the beep played for programs without an audio pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    Square,
+    Triangle,
+    Sawtooth,
+    Sine,
+}
+
+impl Waveform {
+    // Looks up a waveform by name, as given on the command line.
+    pub fn from_name(name: &str) -> Option<Waveform> {
+        match name.to_lowercase().as_str() {
+            "square" => Some(Waveform::Square),
+            "triangle" => Some(Waveform::Triangle),
+            "saw" | "sawtooth" => Some(Waveform::Sawtooth),
+            "sine" => Some(Waveform::Sine),
+            _ => None,
+        }
+    }
+
+    // The wave's value, from -1 to 1, at `phase` through a cycle.
+    fn sample(&self, phase: f64) -> f64 {
+        match *self {
+            Waveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Sawtooth => 2.0 * phase - 1.0,
+            Waveform::Sine => (2.0 * PI * phase).sin(),
+        }
+    }
+}
+
+// Everything the emulator plays: a tone while the sound timer runs, or the
+// audio pattern instead once an XO-CHIP program has set one.
+pub struct ToneGenerator {
+    sample_rate: u32,
+    // How far through a cycle of the tone we are, from 0 to 1.
+    phase: f64,
+    pattern: PatternPlayer,
+    // The tone's pitch, in Hz.
+    pub frequency: f64,
+    // From 0 to 1.
+    pub volume: f32,
+    pub waveform: Waveform,
+    pub muted: bool,
+}
+
+impl ToneGenerator {
+    pub fn new(sample_rate: u32) -> ToneGenerator {
+        ToneGenerator {
+            sample_rate: sample_rate,
+            phase: 0.0,
+            pattern: PatternPlayer::new(sample_rate),
+            frequency: 440.0,
+            volume: 0.25,
+            waveform: Waveform::Square,
+            muted: false,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Writes the samples for `sound` into `out`. Returns whether anything was
+    // played.
+    pub fn fill(&mut self, sound: &SoundState, out: &mut [f32]) -> bool {
+        if self.muted || !sound.playing {
+            // Start the next beep from the beginning of a cycle, so it doesn't
+            // click.
+            self.phase = 0.0;
+            self.pattern.fill(&SoundState::silent(), out);
+            return false;
+        }
+        if sound.pattern.is_some() {
+            self.pattern.volume = self.volume;
+            return self.pattern.fill(sound, out);
+        }
+
+        let step = self.frequency / self.sample_rate as f64;
+        for sample in out.iter_mut() {
+            *sample = self.waveform.sample(self.phase) as f32 * self.volume;
+            self.phase = (self.phase + step) % 1.0;
+        }
+        true
+    }
+}
+
+// Renders the sound of a run a frame at a time, so it can be saved as a WAV
+// file and checked without a sound card.
+pub struct WavRecorder {
+    generator: ToneGenerator,
+    samples: Vec<f32>,
+    frames: u64,
+}
+
+impl WavRecorder {
+    pub fn new(generator: ToneGenerator) -> WavRecorder {
+        WavRecorder { generator: generator, samples: Vec::new(), frames: 0 }
+    }
+
+    // Renders one 60Hz frame. Call it after running the frame's instructions
+    // and before ticking the timers.
+    pub fn record_frame(&mut self, cpu: &Cpu) {
+        // Frames don't always hold a whole number of samples, so work out where
+        // each one starts from the frame count rather than rounding each one.
+        let rate = self.generator.sample_rate() as u64;
+        let start = self.frames * rate / 60;
+        let end = (self.frames + 1) * rate / 60;
+        let mut buffer = vec![0.0; (end - start) as usize];
+        self.generator.fill(&SoundState::of(cpu), &mut buffer);
+        self.samples.extend_from_slice(&buffer);
+        self.frames += 1;
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn to_wav(&self) -> Vec<u8> {
+        wav_bytes(self.generator.sample_rate(), &self.samples)
+    }
+}
+
+// Encodes samples from -1 to 1 as a mono, 16 bit PCM WAV file.
+pub fn wav_bytes(sample_rate: u32, samples: &[f32]) -> Vec<u8> {
+    fn u16_le(out: &mut Vec<u8>, n: u16) { out.push(n as u8); out.push((n >> 8) as u8); }
+    fn u32_le(out: &mut Vec<u8>, n: u32) { u16_le(out, n as u16); u16_le(out, (n >> 16) as u16); }
+
+    let data_len = samples.len() as u32 * 2;
+    let mut out = Vec::with_capacity(44 + data_len as usize);
+    out.extend_from_slice(b"RIFF");
+    u32_le(&mut out, 36 + data_len);
+    out.extend_from_slice(b"WAVE");
+    out.extend_from_slice(b"fmt ");
+    u32_le(&mut out, 16);
+    u16_le(&mut out, 1); // PCM
+    u16_le(&mut out, 1); // mono
+    u32_le(&mut out, sample_rate);
+    u32_le(&mut out, sample_rate * 2); // bytes per second
+    u16_le(&mut out, 2); // bytes per sample
+    u16_le(&mut out, 16); // bits per sample
+    out.extend_from_slice(b"data");
+    u32_le(&mut out, data_len);
+    for &sample in samples {
+        let sample = (sample.max(-1.0).min(1.0) * 32767.0) as i16;
+        u16_le(&mut out, sample as u16);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::cpu_with;
+
+    fn cpu_with_pattern(pattern: [u8; 16]) -> Cpu {
+        // LD I 0x300, AUDIO
+        let mut cpu = cpu_with(vec![0xA3, 0x00, 0xF0, 0x02]);
+        cpu.memory_mut()[0x300 .. 0x310].copy_from_slice(&pattern);
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        cpu
+    }
+
+    #[test]
+    fn test_silent_without_sound_timer() {
+        let cpu = cpu_with_pattern([0xFF; 16]);
+        let mut player = PatternPlayer::new(4000);
+        let mut out = [1.0; 8];
+        assert!(!player.fill(&SoundState::of(&cpu), &mut out));
+        assert_eq!(out, [0.0; 8]);
+    }
+
+    #[test]
+    fn test_plays_pattern_bits() {
+        let mut cpu = cpu_with_pattern([0b10100000; 16]);
+        cpu.set_sound_timer(10);
+        // At the default pitch, the pattern plays at 4000 bits per second.
+        let mut player = PatternPlayer::new(4000);
+        let mut out = [0.0; 4];
+        assert!(player.fill(&SoundState::of(&cpu), &mut out));
+        assert_eq!(out, [0.25, -0.25, 0.25, -0.25]);
+    }
+
+    #[test]
+    fn test_tone_waveforms() {
+        let sound = SoundState { playing: true, pattern: None, playback_rate: 4000.0 };
+        // Four samples per cycle.
+        let mut generator = ToneGenerator::new(1000);
+        generator.frequency = 250.0;
+        generator.volume = 1.0;
+        let mut out = [0.0; 4];
+        assert!(generator.fill(&sound, &mut out));
+        assert_eq!(out, [1.0, 1.0, -1.0, -1.0]);
+
+        generator.waveform = Waveform::Sawtooth;
+        generator.fill(&sound, &mut out);
+        assert_eq!(out, [-1.0, -0.5, 0.0, 0.5]);
+
+        generator.waveform = Waveform::Triangle;
+        generator.fill(&sound, &mut out);
+        assert_eq!(out, [-1.0, 0.0, 1.0, 0.0]);
+        assert_eq!(Waveform::from_name("Saw"), Some(Waveform::Sawtooth));
+    }
+
+    #[test]
+    fn test_tone_mute_and_pattern() {
+        let mut cpu = cpu_with_pattern([0xFF; 16]);
+        let mut generator = ToneGenerator::new(4000);
+        let mut out = [1.0; 4];
+        assert!(!generator.fill(&SoundState::of(&cpu), &mut out));
+        assert_eq!(out, [0.0; 4]);
+
+        // With a pattern set, it plays instead of the tone.
+        cpu.set_sound_timer(1);
+        assert!(generator.fill(&SoundState::of(&cpu), &mut out));
+        assert_eq!(out, [0.25; 4]);
+
+        generator.muted = true;
+        assert!(!generator.fill(&SoundState::of(&cpu), &mut out));
+        assert_eq!(out, [0.0; 4]);
+    }
+
+    #[test]
+    fn test_wav_recorder() {
+        let mut cpu = cpu_with(vec![]);
+        // 44100 / 60 is 735 samples a frame.
+        let mut recorder = WavRecorder::new(ToneGenerator::new(44100));
+        recorder.record_frame(&cpu);
+        cpu.set_sound_timer(1);
+        recorder.record_frame(&cpu);
+        assert_eq!(recorder.samples().len(), 735 * 2);
+        assert!(recorder.samples()[.. 735].iter().all(|&s| s == 0.0));
+        assert_eq!(recorder.samples()[735], 0.25);
+
+        let wav = recorder.to_wav();
+        assert_eq!(wav.len(), 44 + 735 * 4);
+        assert_eq!(&wav[0 .. 4], b"RIFF");
+        assert_eq!(&wav[36 .. 40], b"data");
+        // 0.25 * 32767, little endian.
+        assert_eq!(&wav[44 + 735 * 2 .. 44 + 735 * 2 + 2], &[0xFF, 0x1F]);
+    }
+}
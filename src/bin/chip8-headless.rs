@@ -0,0 +1,34 @@
+// Runs a ROM without opening a window, for CI and scripted testing. It takes
+// the same options as `chip8 --headless`, parsed by `chip8::cli`.
+//
+// Exit codes: 0 when the run finished or the ROM exited, 1 for an execution
+// error, 2 when --until-exit was given and the ROM didn't exit in time, and 3
+// for bad arguments or files that can't be read or written.
+extern crate chip8;
+
+use std::env;
+use std::process;
+use chip8::cli::{self, Command};
+use chip8::headless;
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(cli::EXIT_BAD_ARGUMENTS);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match cli::parse_headless(&args) {
+        Ok(Command::Run(options)) => options,
+        Ok(_) => {
+            println!("{}", cli::HEADLESS_USAGE);
+            return;
+        }
+        Err(e) => fail(&format!("{}\n\n{}", e, cli::HEADLESS_USAGE)),
+    };
+    let movie = options.read_movie().unwrap_or_else(|e| fail(&e));
+    let mut cpu = options.load_cpu(movie.as_ref()).unwrap_or_else(|e| fail(&e));
+    let report = headless::run_options(&options, &mut cpu, movie.as_ref()).unwrap_or_else(|e| fail(&e));
+    eprintln!("{}", report.summary());
+    process::exit(report.outcome.exit_code());
+}
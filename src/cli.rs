@@ -0,0 +1,555 @@
+use audio::{ToneGenerator, Waveform};
+use cpu::{Cpu, FONT_END};
+use debugger::parse_number;
+use headless::{self, KeyScript};
+use keymap::Keymap;
+use movie::Movie;
+use palette::Palette;
+use quirks::Quirks;
+use render::Filter;
+use rng::VipStyleRandom;
+
+// The command lines of the `chip8` frontend and `chip8-headless`. They're
+// parsed here rather than in the binaries so they can be tested without a
+// window, and so both take the same options for a headless run.
+
+pub const USAGE: &'static str = "\
+Usage: chip8 [run] [options] <rom>
+       chip8 disasm [--start ADDRESS] <rom>
+       chip8 asm <source> <rom>
+       chip8 inspect [--start ADDRESS] <rom>
+
+Run options:
+  --ipf N              instructions per frame (default 10)
+  --scale N            window pixels per CHIP-8 pixel (default 10)
+  --integer-scale      keep pixels a whole number of window pixels across
+  --fullscreen         start in fullscreen, which F11 toggles
+  --grid               draw lines between pixels, which Ctrl+G toggles
+  --filter NAME        hide flicker: blend[:FRAMES] draws pixels lit in any of
+                       the last 2 frames, phosphor[:PERSISTENCE] fades them out
+                       keeping half their brightness each frame, or none
+  --vblank             only draw whole frames, never one half drawn
+  --palette COLOURS    default, inverted, green, amber or lcd, or the background
+                       and foreground as hex, like #000000,#33FF66
+  --quirks NAME        default, vip, chip48 or schip
+  --start ADDRESS      load and start the ROM at ADDRESS (default 0x200)
+  --seed N             seed for the random number generator (default random,
+                       or 0 with --headless)
+  --vip-style-random   generate random numbers the way the COSMAC VIP did, but
+                       from a built-in table, so not the VIP's exact numbers
+  --layout NAME        qwerty, azerty or dvorak
+  --keymap FILE        read key bindings from a TOML file
+  --mute               start with the sound off
+  --tone HZ            pitch of the beep (default 440)
+  --volume N           volume of the beep, from 0 to 100 (default 25)
+  --waveform NAME      square, triangle, sawtooth or sine (default square)
+  --record FILE        record the keys pressed to a movie file
+  --play FILE          play a movie back, with the settings it was recorded with
+  --capture FILE       record every frame as an animated GIF if FILE ends in
+                       .gif, or else as raw 24-bit RGB frames, 64x32 times
+                       --scale, with odd scales rounded down, `-` for stdout
+  --screenshot FILE    save the last frame as a PNG when the run ends, drawn at
+                       --scale in the --palette colours, `-` for stdout
+  --headless           run without a window, as chip8-headless does, with the
+                       options below
+  -h, --help           show this help
+
+Headless options:
+  --frames N             run for N frames (default 600)
+  --cycles N             run for N instructions instead of a number of frames
+  --until-exit           time out if the ROM hasn't exited by the end
+  --press FRAME:KEY[:N]  hold hex KEY for N frames (default 1) from FRAME
+  --keys FILE            read key presses from FILE, as `<frame> <key> down|up`
+  --dump-display FILE    write the display as text, `-` for stdout
+  --dump-registers FILE  write the registers as text, `-` for stdout
+  --dump-memory FILE     write all of memory as raw bytes, `-` for stdout
+  --wav FILE             write the sound the ROM made as a WAV file
+With none of --dump-*, --screenshot or --capture, the registers and display
+are written to stdout.
+
+Exit codes: 0 on success, 1 when the ROM can't be loaded or hits an error, 2
+when --until-exit was given and the ROM didn't exit in time, and 3 for bad
+arguments.";
+
+pub const HEADLESS_USAGE: &'static str = "\
+Usage: chip8-headless [options] <rom>
+
+Runs a ROM without opening a window, for CI and scripted testing. It takes the
+same options as `chip8 --headless`:
+
+  --frames N             run for N frames (default 600)
+  --cycles N             run for N instructions instead of a number of frames
+  --ipf N                instructions per frame (default 10)
+  --quirks NAME          default, vip, chip48 or schip
+  --start ADDRESS        load and start the ROM at ADDRESS (default 0x200)
+  --seed N               seed for the random number generator (default 0)
+  --vip-style-random     generate random numbers the way the COSMAC VIP did, but
+                         from a built-in table, so not the VIP's exact numbers
+  --until-exit           time out if the ROM hasn't exited by the end
+  --press FRAME:KEY[:N]  hold hex KEY for N frames (default 1) from FRAME
+  --keys FILE            read key presses from FILE, as `<frame> <key> down|up`
+  --play FILE            replay a movie recorded with `chip8 --record`, using
+                         its settings, keys and length; --movie does the same
+  --dump-display FILE    write the display as text, `-` for stdout
+  --dump-registers FILE  write the registers as text, `-` for stdout
+  --dump-memory FILE     write all of memory as raw bytes, `-` for stdout
+  --wav FILE             write the sound the ROM made as a WAV file
+  --tone HZ              pitch of the beep (default 440)
+  --volume N             volume of the beep, from 0 to 100 (default 25)
+  --waveform NAME        square, triangle, sawtooth or sine (default square)
+  --screenshot FILE      write the last frame as a PNG, `-` for stdout
+  --capture FILE         record every frame as an animated GIF if FILE ends in
+                         .gif, or else as raw 24-bit RGB frames, `-` for stdout,
+                         64x32 times --scale with odd scales rounded down
+  --scale N              image pixels per display pixel for --screenshot, and
+                         per low resolution pixel for --capture (default 10)
+  --palette COLOURS      a palette name or hex colours, as for `chip8 --palette`
+
+With none of --dump-*, --screenshot or --capture, the registers and display are
+written to stdout.
+
+Exit codes: 0 when the run finished or the ROM exited, 1 for an execution
+error, 2 when --until-exit was given and the ROM didn't exit in time, and 3 for
+bad arguments or files that can't be read or written.";
+
+pub const EXIT_ERROR: i32 = 1;
+pub const EXIT_BAD_ARGUMENTS: i32 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run(RunOptions),
+    Disasm { rom: String, start: usize },
+    Asm { source: String, rom: String },
+    Inspect { rom: String, start: usize },
+    Help,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunOptions {
+    pub rom: String,
+    pub cycles_per_frame: u64,
+    pub scale: u32,
+    pub integer_scale: bool,
+    pub fullscreen: bool,
+    pub grid: bool,
+    pub filter: Filter,
+    pub vblank: bool,
+    pub palette: Palette,
+    pub quirks: Quirks,
+    pub start: usize,
+    // None for a different seed every run.
+    pub seed: Option<u64>,
+    pub vip_style_random: bool,
+    pub layout: Option<String>,
+    pub keymap: Option<String>,
+    pub mute: bool,
+    // The sound settings are None to leave ToneGenerator's defaults.
+    pub tone: Option<f64>,
+    pub volume: Option<f32>,
+    pub waveform: Option<Waveform>,
+    pub record: Option<String>,
+    pub play: Option<String>,
+    pub screenshot: Option<String>,
+    pub capture: Option<String>,
+    // The rest are only for headless runs.
+    pub headless: bool,
+    pub frames: u64,
+    // Run for this many instructions instead of `frames`.
+    pub cycles: Option<u64>,
+    pub until_exit: bool,
+    // The presses given with --press, and the files given with --keys.
+    pub keys: KeyScript,
+    pub key_files: Vec<String>,
+    pub dump_display: Option<String>,
+    pub dump_registers: Option<String>,
+    pub dump_memory: Option<String>,
+    pub wav: Option<String>,
+}
+
+impl RunOptions {
+    pub fn new(rom: &str) -> RunOptions {
+        RunOptions {
+            rom: rom.to_string(),
+            cycles_per_frame: 10,
+            scale: 10,
+            integer_scale: false,
+            fullscreen: false,
+            grid: false,
+            filter: Filter::None,
+            vblank: false,
+            palette: Palette::default(),
+            quirks: Quirks::default(),
+            start: 0x200,
+            seed: None,
+            vip_style_random: false,
+            layout: None,
+            keymap: None,
+            mute: false,
+            tone: None,
+            volume: None,
+            waveform: None,
+            record: None,
+            play: None,
+            screenshot: None,
+            capture: None,
+            headless: false,
+            frames: 600,
+            cycles: None,
+            until_exit: false,
+            keys: KeyScript::new(),
+            key_files: Vec::new(),
+            dump_display: None,
+            dump_registers: None,
+            dump_memory: None,
+            wav: None,
+        }
+    }
+
+    // Reads the movie given with --play, if there is one.
+    pub fn read_movie(&self) -> Result<Option<Movie>, String> {
+        match self.play {
+            Some(ref path) => {
+                let text = headless::read_text(path)?;
+                Movie::parse(&text).map(Some).map_err(|e| format!("Error in movie {}: {}", path, e))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Makes the cpu for the run, with the ROM read and loaded. A movie being
+    // played brings its own quirks, seed, generator and load address.
+    pub fn load_cpu(&self, movie: Option<&Movie>) -> Result<Cpu, String> {
+        let (quirks, seed, vip_style_random, start) = match movie {
+            Some(movie) => (movie.quirks, Some(movie.seed), movie.rng == 1, movie.start),
+            None => (self.quirks, self.seed, self.vip_style_random, self.start),
+        };
+        let mut cpu = match seed {
+            Some(seed) => Cpu::with_seed(quirks, seed),
+            None => Cpu::new(quirks),
+        };
+        if vip_style_random {
+            let seed = cpu.seed();
+            cpu.set_rng(Box::new(VipStyleRandom::new(seed)));
+        }
+        let rom = headless::read_file(&self.rom)?;
+        headless::load_rom(&mut cpu, &self.rom, rom, start)?;
+        if let Some(movie) = movie {
+            movie.check(&cpu).map_err(|e| format!("Can't play movie: {}", e))?;
+        }
+        Ok(cpu)
+    }
+
+    // Sets the sound options that were given.
+    pub fn apply_sound(&self, generator: &mut ToneGenerator) {
+        generator.muted = self.mute;
+        if let Some(tone) = self.tone { generator.frequency = tone; }
+        if let Some(volume) = self.volume { generator.volume = volume; }
+        if let Some(waveform) = self.waveform { generator.waveform = waveform; }
+    }
+}
+
+// Options that only make sense without a window. The window's own options are
+// allowed in headless runs, and do nothing there.
+const HEADLESS_OPTIONS: [&'static str; 9] = [
+    "--frames", "--cycles", "--until-exit", "--press", "--keys",
+    "--dump-display", "--dump-registers", "--dump-memory", "--wav",
+];
+
+// Parses the arguments after the program name. A ROM on its own is run, as
+// `chip8 rom.ch8` always has been.
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        return Ok(Command::Help);
+    }
+    let rest = if args.is_empty() { args } else { &args[1 ..] };
+    match args.first().map(|arg| arg.as_str()) {
+        Some("help") => Ok(Command::Help),
+        Some("run") => parse_run(rest, false).map(Command::Run),
+        Some("disasm") => {
+            let (start, rest) = take_start(rest)?;
+            let files = positional("disasm", &rest, &["rom"])?;
+            Ok(Command::Disasm { rom: files[0].clone(), start: start })
+        }
+        Some("asm") => {
+            let files = positional("asm", rest, &["source", "rom"])?;
+            Ok(Command::Asm { source: files[0].clone(), rom: files[1].clone() })
+        }
+        Some("inspect") => {
+            let (start, rest) = take_start(rest)?;
+            let files = positional("inspect", &rest, &["rom"])?;
+            Ok(Command::Inspect { rom: files[0].clone(), start: start })
+        }
+        _ => parse_run(args, false).map(Command::Run),
+    }
+}
+
+// Parses the arguments to chip8-headless, which are those of
+// `chip8 run --headless`.
+pub fn parse_headless(args: &[String]) -> Result<Command, String> {
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        return Ok(Command::Help);
+    }
+    parse_run(args, true).map(Command::Run)
+}
+
+// Checks a subcommand was given just the files it needs.
+fn positional<'a>(command: &str, args: &'a [String], names: &[&str]) -> Result<&'a [String], String> {
+    if let Some(option) = args.iter().find(|arg| arg.starts_with('-')) {
+        return Err(format!("unknown option {} for {}", option, command));
+    }
+    if args.len() != names.len() {
+        let names: Vec<String> = names.iter().map(|name| format!("<{}>", name)).collect();
+        return Err(format!("usage: chip8 {} {}", command, names.join(" ")));
+    }
+    Ok(args)
+}
+
+// Takes `--start ADDRESS` out of a subcommand's arguments, for the ROM's load
+// address, returning it with the arguments that are left.
+fn take_start(args: &[String]) -> Result<(usize, Vec<String>), String> {
+    let mut start = 0x200;
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--start" {
+            let value = args.next().ok_or(format!("{} needs a value", arg))?;
+            start = parse_start(arg, value)?;
+        } else {
+            rest.push(arg.clone());
+        }
+    }
+    Ok((start, rest))
+}
+
+fn parse_start(option: &str, value: &str) -> Result<usize, String> {
+    let start = parse_number(value).ok_or(format!("bad value for {}: {}", option, value))? as usize;
+    if start < FONT_END {
+        return Err(format!("{} must be from 0x{:03X}, after the fonts", option, FONT_END));
+    }
+    Ok(start)
+}
+
+fn parse_run(args: &[String], headless: bool) -> Result<RunOptions, String> {
+    let mut options = RunOptions::new("");
+    options.headless = headless;
+    let mut rom = None;
+    let mut headless_option = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let option = arg.as_str();
+        if !option.starts_with('-') {
+            if rom.is_some() {
+                return Err(format!("unexpected argument {}, only one ROM can be run", arg));
+            }
+            rom = Some(arg.clone());
+            continue;
+        }
+        if HEADLESS_OPTIONS.contains(&option) {
+            headless_option = Some(option);
+        }
+        match option {
+            "--vip-style-random" => options.vip_style_random = true,
+            "--mute" => options.mute = true,
+            "--headless" => options.headless = true,
+            "--until-exit" => options.until_exit = true,
+            "--integer-scale" => options.integer_scale = true,
+            "--fullscreen" => options.fullscreen = true,
+            "--grid" => options.grid = true,
+            "--vblank" => options.vblank = true,
+            "--ipf" | "--scale" | "--filter" | "--palette" | "--quirks" | "--start" | "--seed" | "--layout"
+            | "--keymap" | "--tone" | "--volume" | "--waveform" | "--record" | "--play" | "--movie" | "--frames"
+            | "--screenshot" | "--capture" | "--cycles" | "--press" | "--keys" | "--dump-display"
+            | "--dump-registers" | "--dump-memory" | "--wav" => {
+                let value = args.next().ok_or(format!("{} needs a value", option))?;
+                set_option(&mut options, option, value)?;
+            }
+            _ => return Err(format!("unknown option {}", option)),
+        }
+    }
+    options.rom = rom.ok_or("no ROM given".to_string())?;
+    if options.record.is_some() && options.play.is_some() {
+        return Err("--record and --play can't be used together".to_string());
+    }
+    if let (false, Some(option)) = (options.headless, headless_option) {
+        return Err(format!("{} needs --headless", option));
+    }
+    // Headless runs are for testing, so they're the same every time unless
+    // asked otherwise.
+    if options.headless && options.seed.is_none() {
+        options.seed = Some(0);
+    }
+    Ok(options)
+}
+
+fn set_option(options: &mut RunOptions, option: &str, value: &str) -> Result<(), String> {
+    let bad_value = || format!("bad value for {}: {}", option, value);
+    match option {
+        "--ipf" => {
+            options.cycles_per_frame = value.parse().ok().filter(|&n| n > 0)
+                .ok_or(format!("{} needs a number of at least 1", option))?;
+        }
+        "--scale" => {
+            options.scale = value.parse().ok().filter(|&n| n > 0 && n <= 100)
+                .ok_or(format!("{} needs a number from 1 to 100", option))?;
+        }
+        "--filter" => options.filter = Filter::parse(value).map_err(|e| format!("{}: {}", option, e))?,
+        "--palette" => options.palette = Palette::parse(value).map_err(|e| format!("{}: {}", option, e))?,
+        "--quirks" => {
+            options.quirks = Quirks::from_name(value)
+                .ok_or(format!("unknown quirks preset {:?}, expected default, vip, chip48 or schip", value))?;
+        }
+        "--start" => options.start = parse_start(option, value)?,
+        "--seed" => options.seed = Some(value.parse().map_err(|_| bad_value())?),
+        "--layout" => {
+            if Keymap::layout(value).is_none() {
+                return Err(format!("unknown keyboard layout {:?}, expected qwerty, azerty or dvorak", value));
+            }
+            options.layout = Some(value.to_string());
+        }
+        "--keymap" => options.keymap = Some(value.to_string()),
+        "--tone" => options.tone = Some(value.parse().ok().filter(|&hz: &f64| hz > 0.0).ok_or_else(&bad_value)?),
+        "--volume" => {
+            let volume: u32 = value.parse().ok().filter(|&n| n <= 100)
+                .ok_or(format!("{} needs a number from 0 to 100", option))?;
+            options.volume = Some(volume as f32 / 100.0);
+        }
+        "--waveform" => {
+            options.waveform = Some(Waveform::from_name(value)
+                .ok_or(format!("unknown waveform {:?}, expected square, triangle, sawtooth or sine", value))?);
+        }
+        "--record" => options.record = Some(value.to_string()),
+        "--play" | "--movie" => options.play = Some(value.to_string()),
+        "--frames" => {
+            options.frames = value.parse().map_err(|_| bad_value())?;
+            options.cycles = None;
+        }
+        "--cycles" => options.cycles = Some(value.parse().map_err(|_| bad_value())?),
+        "--press" => {
+            let (frame, key, frames) = parse_press(value)
+                .ok_or(format!("{} needs FRAME:KEY[:FRAMES], not {:?}", option, value))?;
+            options.keys.press(frame, key, frames);
+        }
+        "--keys" => options.key_files.push(value.to_string()),
+        "--dump-display" => options.dump_display = Some(value.to_string()),
+        "--dump-registers" => options.dump_registers = Some(value.to_string()),
+        "--dump-memory" => options.dump_memory = Some(value.to_string()),
+        "--wav" => options.wav = Some(value.to_string()),
+        "--screenshot" => options.screenshot = Some(value.to_string()),
+        "--capture" => options.capture = Some(value.to_string()),
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+// Parses `FRAME:KEY[:FRAMES]` for --press.
+fn parse_press(value: &str) -> Option<(u64, usize, u64)> {
+    let parts: Vec<&str> = value.split(':').collect();
+    let frame = parts.get(0)?.parse().ok()?;
+    let key = usize::from_str_radix(parts.get(1)?, 16).ok().filter(|&key| key < 16)?;
+    let frames = match parts.get(2) {
+        Some(frames) => frames.parse().ok()?,
+        None => 1,
+    };
+    if parts.len() > 3 { None } else { Some((frame, key, frames)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &str) -> Result<Command, String> {
+        let args: Vec<String> = args.split_whitespace().map(|arg| arg.to_string()).collect();
+        parse(&args)
+    }
+
+    #[test]
+    fn test_bare_rom_runs() {
+        assert_eq!(parse_str("pong.ch8"), Ok(Command::Run(RunOptions::new("pong.ch8"))));
+        assert_eq!(parse_str("run pong.ch8"), Ok(Command::Run(RunOptions::new("pong.ch8"))));
+    }
+
+    #[test]
+    fn test_run_options() {
+        let command = parse_str("run --ipf 20 --scale 4 --palette #000,#0F0 --grid --filter blend:3 --quirks schip \
+                                 --start 0x600 --seed 7 --layout dvorak --mute --volume 50 --waveform sine \
+                                 --headless --frames 60 --screenshot end.png --capture run.gif game.ch8").unwrap();
+        let options = match command {
+            Command::Run(options) => options,
+            _ => panic!("expected run"),
+        };
+        assert_eq!(options.rom, "game.ch8");
+        assert_eq!(options.cycles_per_frame, 20);
+        assert_eq!(options.scale, 4);
+        assert_eq!(options.palette.colors[1], [0, 255, 0]);
+        assert_eq!(options.filter, Filter::Blend(3));
+        assert_eq!(options.quirks, Quirks::superchip());
+        assert_eq!(options.start, 0x600);
+        assert_eq!(options.seed, Some(7));
+        assert_eq!(options.layout, Some("dvorak".to_string()));
+        assert!(options.mute && options.headless && options.grid);
+        assert!(!options.fullscreen);
+        assert_eq!(options.volume, Some(0.5));
+        assert_eq!(options.waveform, Some(Waveform::Sine));
+        assert_eq!(options.frames, 60);
+        assert_eq!(options.screenshot, Some("end.png".to_string()));
+        assert_eq!(options.capture, Some("run.gif".to_string()));
+    }
+
+    #[test]
+    fn test_subcommands() {
+        assert_eq!(parse_str("disasm a.ch8"), Ok(Command::Disasm { rom: "a.ch8".to_string(), start: 0x200 }));
+        assert_eq!(parse_str("disasm --start 0x600 a.ch8"),
+                   Ok(Command::Disasm { rom: "a.ch8".to_string(), start: 0x600 }));
+        assert_eq!(parse_str("asm a.asm a.ch8"),
+                   Ok(Command::Asm { source: "a.asm".to_string(), rom: "a.ch8".to_string() }));
+        assert_eq!(parse_str("inspect a.ch8 --start 0x300"),
+                   Ok(Command::Inspect { rom: "a.ch8".to_string(), start: 0x300 }));
+        assert_eq!(parse_str("inspect --start 0x10 a.ch8"),
+                   Err("--start must be from 0x0F0, after the fonts".to_string()));
+        assert_eq!(parse_str("asm a.asm"), Err("usage: chip8 asm <source> <rom>".to_string()));
+        assert_eq!(parse_str("help"), Ok(Command::Help));
+        assert_eq!(parse_str("run --ipf 5 --help"), Ok(Command::Help));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse_str(""), Err("no ROM given".to_string()));
+        assert_eq!(parse_str("--ipf"), Err("--ipf needs a value".to_string()));
+        assert_eq!(parse_str("--ipf 0 a.ch8"), Err("--ipf needs a number of at least 1".to_string()));
+        assert_eq!(parse_str("--fast a.ch8"), Err("unknown option --fast".to_string()));
+        assert_eq!(parse_str("a.ch8 b.ch8"), Err("unexpected argument b.ch8, only one ROM can be run".to_string()));
+        assert_eq!(parse_str("--start 0x10 a.ch8"), Err("--start must be from 0x0F0, after the fonts".to_string()));
+        assert_eq!(parse_str("--seed x a.ch8"), Err("bad value for --seed: x".to_string()));
+        assert!(parse_str("--quirks modern a.ch8").is_err());
+        assert!(parse_str("--record a --play b a.ch8").is_err());
+        assert_eq!(parse_str("--until-exit a.ch8"), Err("--until-exit needs --headless".to_string()));
+    }
+
+    #[test]
+    fn test_headless_options() {
+        let args: Vec<String> = "--cycles 500 --until-exit --press 3:a:2 --keys k.txt --movie m.movie \
+                                 --dump-display - --dump-memory mem.bin --wav out.wav game.ch8"
+            .split_whitespace().map(|arg| arg.to_string()).collect();
+        let options = match parse_headless(&args).unwrap() {
+            Command::Run(options) => options,
+            _ => panic!("expected run"),
+        };
+        assert!(options.headless && options.until_exit);
+        assert_eq!(options.seed, Some(0));
+        assert_eq!(options.cycles, Some(500));
+        assert_eq!(options.keys.events().len(), 2);
+        assert_eq!(options.key_files, vec!["k.txt".to_string()]);
+        assert_eq!(options.play, Some("m.movie".to_string()));
+        assert_eq!(options.dump_display, Some("-".to_string()));
+        assert_eq!(options.dump_memory, Some("mem.bin".to_string()));
+        assert_eq!(options.wav, Some("out.wav".to_string()));
+
+        // `chip8 --headless` takes the same options and defaults.
+        let command = parse_str("--headless --cycles 500 --until-exit --press 3:a:2 --keys k.txt --movie m.movie \
+                                 --dump-display - --dump-memory mem.bin --wav out.wav game.ch8");
+        assert_eq!(command, Ok(Command::Run(options)));
+        assert_eq!(parse_headless(&["--press".to_string(), "3:g".to_string(), "a.ch8".to_string()]),
+                   Err("--press needs FRAME:KEY[:FRAMES], not \"3:g\"".to_string()));
+        assert_eq!(parse_headless(&["--help".to_string()]), Ok(Command::Help));
+    }
+}
@@ -1,7 +1,49 @@
-extern crate rand;
+use rand;
+use error::{ExecutionError, LoadError};
+use quirks::Quirks;
+use rng::{RandomSource, XorShift};
+use state::{self, StateError, StateReader, StateWriter, STATE_MAGIC, STATE_VERSION};
+
+// The display buffer is sized for SUPER-CHIP's high resolution mode. In low
+// resolution mode only the top left 64x32 pixels are used.
+pub const DISPLAY_WIDTH: usize = 128;
+pub const DISPLAY_HEIGHT: usize = 64;
+
+// XO-CHIP extends the address space to 64KiB.
+pub const MEMORY_SIZE: usize = 0x10000;
+
+// Where the large 8x10 SUPER-CHIP font is loaded, right after the 4x5 font.
+const BIG_FONT_ADDRESS: usize = 80;
+// Programs can be loaded anywhere from here on, after both fonts.
+pub const FONT_END: usize = BIG_FONT_ADDRESS + 160;
+
+// Where save_state() writes the PC, followed by the SP: after the 15 byte
+// header, and the quirks, opcode, V, I and timers.
+const STATE_PC_OFFSET: usize = 37;
+
+// A rectangle of the display, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl DirtyRect {
+    // The whole display, in both resolutions.
+    pub fn full() -> DirtyRect {
+        DirtyRect { x: 0, y: 0, width: DISPLAY_WIDTH, height: DISPLAY_HEIGHT }
+    }
 
-use std::time::{Duration, Instant};
-use std::process;
+    // The smallest rectangle covering both.
+    pub fn union(&self, other: DirtyRect) -> DirtyRect {
+        let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        DirtyRect { x: x, y: y, width: right - x, height: bottom - y }
+    }
+}
 
 pub struct Cpu {
     opcode: u16,
@@ -12,14 +54,44 @@ pub struct Cpu {
     pc: usize,
     sp: usize,
     stack: [u16; 16],
-    memory: [u8; 4096],
+    memory: Vec<u8>,
     pub key_buff: [bool; 16],
-    pub disp_buff: [[bool; 64]; 32],
-    time_at_last_timer_count: Instant
+    // Each pixel holds one bit per XO-CHIP bitplane: bit 0 is plane 1 and bit 1
+    // is plane 2. Plain CHIP-8 programs only ever draw to plane 1.
+    pub disp_buff: [[u8; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+    // The bitplanes affected by drawing, clearing and scrolling, selected by Fn01.
+    plane: u8,
+    hires: bool,
+    // SUPER-CHIP's RPL user flags, saved and restored by Fx75 and Fx85.
+    rpl: [u8; 16],
+    // XO-CHIP's 1-bit, 128 sample audio pattern, loaded by F002.
+    audio_pattern: [u8; 16],
+    has_audio_pattern: bool,
+    // XO-CHIP's audio pattern playback pitch, set by Fx3A.
+    pitch: u8,
+    quirks: Quirks,
+    // Identifies the loaded ROM, so save states can't be loaded into another game.
+    rom_hash: u64,
+    // Where the ROM was loaded, and started running from.
+    rom_start: usize,
+    // Set on every timer tick and cleared by a draw, for the display_wait quirk.
+    vblank: bool,
+    // The part of the display changed since take_dirty() was last called, or
+    // None if it hasn't changed. Writing to disp_buff directly doesn't set it.
+    dirty: Option<DirtyRect>,
+    // Where Cxkk gets its numbers, and the seed it was created with.
+    rng: Box<dyn RandomSource>,
+    seed: u64
 }
 
 impl Cpu {
-    pub fn new() -> Cpu {
+    // Creates a cpu whose random numbers are seeded differently every time.
+    pub fn new(quirks: Quirks) -> Cpu {
+        Cpu::with_seed(quirks, rand::random())
+    }
+
+    // Creates a cpu whose random numbers come from a XorShift with this seed.
+    pub fn with_seed(quirks: Quirks, seed: u64) -> Cpu {
 
         let cpu = Cpu {
             opcode: 0,
@@ -30,31 +102,135 @@ impl Cpu {
             pc: 0x200,
             sp: 0,
             stack: [0; 16],
-            memory: [0; 4096],
+            memory: vec![0; MEMORY_SIZE],
             key_buff: [false; 16],
-            disp_buff: [[false; 64]; 32],
-            time_at_last_timer_count: Instant::now()
+            disp_buff: [[0; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+            plane: 1,
+            hires: false,
+            rpl: [0; 16],
+            audio_pattern: [0; 16],
+            has_audio_pattern: false,
+            pitch: 64,
+            quirks: quirks,
+            rom_hash: state::hash_rom(&[]),
+            rom_start: 0x200,
+            vblank: false,
+            // Nothing has been drawn yet, but whatever shows the display
+            // needs to start from a blank one.
+            dirty: Some(DirtyRect::full()),
+            rng: Box::new(XorShift::new(seed)),
+            seed: seed
         };
 
         return cpu;
     }
 
-    pub fn emulate_cycle(&mut self) {
-        self.fetch_opcode();
-        self.opcode_execute();
-        self.count_timers();
+    // Fetches and executes one instruction. Once an error has been returned the
+    // program counter is left pointing at the offending instruction, so calling
+    // this again will return the same error. The timers aren't touched; they
+    // count down when tick_timers() is called.
+    pub fn emulate_cycle(&mut self) -> Result<(), ExecutionError> {
+        self.fetch_opcode()?;
+        self.opcode_execute()?;
+        Ok(())
     }
 
-    fn count_timers(&mut self) {
-        if Instant::now() - self.time_at_last_timer_count >= Duration::from_millis(17) {
-            self.time_at_last_timer_count = Instant::now();
-            if self.sound_timer > 0 {
-                self.sound_timer = self.sound_timer - 1;
-            }
-            if self.delay_timer > 0 {
-                self.delay_timer = self.delay_timer - 1;
-            }
+    // Runs one 60Hz frame: `cycles` instructions followed by a timer tick. On
+    // error the rest of the frame, including the tick, is skipped.
+    pub fn run_frame(&mut self, cycles: usize) -> Result<(), ExecutionError> {
+        for _ in 0 .. cycles {
+            self.emulate_cycle()?;
         }
+        self.tick_timers();
+        Ok(())
+    }
+
+    // Counts the delay and sound timers down by one. This should be called 60
+    // times per second of emulated time, between frames of instructions, so the
+    // same program with the same input always runs the same way.
+    pub fn tick_timers(&mut self) {
+        self.vblank = true;
+        if self.sound_timer > 0 {
+            self.sound_timer = self.sound_timer - 1;
+        }
+        if self.delay_timer > 0 {
+            self.delay_timer = self.delay_timer - 1;
+        }
+    }
+
+    // Register, memory and timer accessors for code embedding the emulator.
+
+    pub fn v(&self, x: usize) -> u8 { self.v[x] }
+    pub fn set_v(&mut self, x: usize, value: u8) { self.v[x] = value; }
+    pub fn i(&self) -> u16 { self.i }
+    pub fn set_i(&mut self, value: u16) { self.i = value; }
+    pub fn pc(&self) -> usize { self.pc }
+    pub fn set_pc(&mut self, value: usize) { self.pc = value; }
+    pub fn sp(&self) -> usize { self.sp }
+    pub fn stack(&self) -> &[u16] { &self.stack }
+    pub fn opcode(&self) -> u16 { self.opcode }
+    pub fn memory(&self) -> &[u8] { &self.memory }
+    pub fn memory_mut(&mut self) -> &mut [u8] { &mut self.memory }
+    pub fn delay_timer(&self) -> u8 { self.delay_timer }
+    pub fn set_delay_timer(&mut self, value: u8) { self.delay_timer = value; }
+    pub fn sound_timer(&self) -> u8 { self.sound_timer }
+    pub fn set_sound_timer(&mut self, value: u8) { self.sound_timer = value; }
+    pub fn quirks(&self) -> Quirks { self.quirks }
+    pub fn set_quirks(&mut self, quirks: Quirks) { self.quirks = quirks; }
+    pub fn rom_hash(&self) -> u64 { self.rom_hash }
+    pub fn rom_start(&self) -> usize { self.rom_start }
+    pub fn seed(&self) -> u64 { self.seed }
+    pub fn rng_kind(&self) -> u8 { self.rng.kind() }
+
+    // Replaces the random number generator, for instance with a VipStyleRandom.
+    pub fn set_rng(&mut self, rng: Box<dyn RandomSource>) { self.rng = rng; }
+
+    // Sets the state of one of the 16 keys on the hex keypad.
+    pub fn set_key(&mut self, key: usize, pressed: bool) { self.key_buff[key] = pressed; }
+    pub fn is_key_pressed(&self, key: usize) -> bool { self.key_buff[key] }
+
+    // Returns the display, indexed as [row][column]. Only the top left
+    // display_width() x display_height() pixels are in use. Each pixel is a
+    // colour from 0 to 3, made of its bitplanes.
+    pub fn display(&self) -> &[[u8; DISPLAY_WIDTH]; DISPLAY_HEIGHT] { &self.disp_buff }
+    pub fn is_hires(&self) -> bool { self.hires }
+    pub fn display_width(&self) -> usize { if self.hires { DISPLAY_WIDTH } else { 64 } }
+    pub fn display_height(&self) -> usize { if self.hires { DISPLAY_HEIGHT } else { 32 } }
+    pub fn rpl_flags(&self) -> &[u8] { &self.rpl }
+    pub fn selected_planes(&self) -> u8 { self.plane }
+
+    // Whether the display has changed since take_dirty() was last called.
+    pub fn is_display_dirty(&self) -> bool { self.dirty.is_some() }
+
+    // Returns the part of the display that has changed since this was last
+    // called, or None if nothing has, so the display only needs drawing again
+    // when it has. If several things show the display, one of them should
+    // call this and tell the others.
+    pub fn take_dirty(&mut self) -> Option<DirtyRect> { self.dirty.take() }
+
+    fn mark_dirty(&mut self, rect: DirtyRect) {
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => dirty.union(rect),
+            None => rect,
+        });
+    }
+
+    // Marks the whole of the display in use as changed.
+    fn mark_display_dirty(&mut self) {
+        let rect = DirtyRect { x: 0, y: 0, width: self.display_width(), height: self.display_height() };
+        self.mark_dirty(rect);
+    }
+
+    // The audio pattern set by F002, or None if the program hasn't set one.
+    pub fn audio_pattern(&self) -> Option<&[u8; 16]> {
+        if self.has_audio_pattern { Some(&self.audio_pattern) } else { None }
+    }
+    pub fn pitch(&self) -> u8 { self.pitch }
+
+    // The rate, in bits per second, at which the audio pattern is played back.
+    // A pitch of 64 is 4000Hz, and every 48 steps either way doubles or halves it.
+    pub fn playback_rate(&self) -> f64 {
+        4000.0 * 2f64.powf((self.pitch as f64 - 64.0) / 48.0)
     }
 
     fn load_bytes(&mut self, data: Vec<u8>) {
@@ -63,66 +239,227 @@ impl Cpu {
         }
     }
 
+    // Loads a program at 0x200. Panics if it's too big for memory, which
+    // load_data_at() reports as an error instead.
     pub fn load_data(cpu: &mut Cpu, data_to_load: Vec<u8>) {
-        let mut data = vec![0; 0x200];
+        Cpu::load_data_at(cpu, data_to_load, 0x200).expect("program too big to load");
+    }
+
+    // Loads a program somewhere other than 0x200, such as 0x600 for ETI 660
+    // programs, and starts running it from there. The program must fit in
+    // memory after `start`, and `start` must leave room for the fonts. If not,
+    // the cpu is left as it was.
+    pub fn load_data_at(cpu: &mut Cpu, data_to_load: Vec<u8>, start: usize) -> Result<(), LoadError> {
+        if start < FONT_END {
+            return Err(LoadError::StartInFonts { start: start });
+        }
+        if start + data_to_load.len() > cpu.memory.len() {
+            return Err(LoadError::TooBig { start: start, len: data_to_load.len() });
+        }
+        cpu.rom_hash = state::hash_rom(&data_to_load);
+        cpu.rom_start = start;
+        let mut data = vec![0; start];
         for i in 0..80 {
             data[i] = FONT_SPRITES[i];
         }
+        for i in 0..160 {
+            data[BIG_FONT_ADDRESS + i] = BIG_FONT_SPRITES[i];
+        }
         for byte in data_to_load {
             data.push(byte)
         }
         cpu.load_bytes(data);
-    }
-
-    fn fetch_opcode(&mut self) {
+        cpu.pc = start;
+        Ok(())
+    }
+
+    // Snapshots the complete machine state. The result can be passed to
+    // load_state() to return to this point, as long as the same ROM is loaded.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.bytes(STATE_MAGIC);
+        w.u16(STATE_VERSION);
+        w.u64(self.rom_hash);
+        w.u8(self.quirks.to_bits());
+
+        w.u16(self.opcode);
+        w.bytes(&self.v);
+        w.u16(self.i);
+        w.u8(self.sound_timer);
+        w.u8(self.delay_timer);
+        w.u32(self.pc as u32);
+        w.u8(self.sp as u8);
+        for &address in self.stack.iter() {
+            w.u16(address);
+        }
+        w.bytes(&self.memory);
+        for &pressed in self.key_buff.iter() {
+            w.bool(pressed);
+        }
+        for row in self.disp_buff.iter() {
+            w.bytes(row);
+        }
+        w.u8(self.plane);
+        w.bool(self.hires);
+        w.bytes(&self.rpl);
+        w.bytes(&self.audio_pattern);
+        w.bool(self.has_audio_pattern);
+        w.u8(self.pitch);
+        w.bool(self.vblank);
+        w.u8(self.rng.kind());
+        w.u64(self.rng.state());
+        w.finish()
+    }
+
+    // Restores a state made by save_state(), including the quirks it was saved
+    // with. The state is checked before anything is changed, so on error the cpu
+    // carries on as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(data);
+        let mut magic = [0; 4];
+        r.bytes(&mut magic).map_err(|_| StateError::BadMagic)?;
+        if &magic != STATE_MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = r.u16()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let rom_hash = r.u64()?;
+        if rom_hash != self.rom_hash {
+            return Err(StateError::RomMismatch { expected: self.rom_hash, found: rom_hash });
+        }
+        if data.len() != self.state_len() {
+            return Err(StateError::WrongLength);
+        }
+        // The generator's kind comes just before its 8 byte state, at the end.
+        let rng_kind = data[data.len() - 9];
+        if rng_kind != self.rng.kind() {
+            return Err(StateError::RngMismatch { expected: self.rng.kind(), found: rng_kind });
+        }
+        // The PC and SP, after the quirks, opcode, V, I and timers, index
+        // memory and the stack, so they must be in range.
+        let mut registers = StateReader::new(&data[STATE_PC_OFFSET ..]);
+        let pc = registers.u32()? as usize;
+        let sp = registers.u8()? as usize;
+        if pc >= self.memory.len() || sp > self.stack.len() {
+            return Err(StateError::BadRegisters);
+        }
+        // The resolution may change too.
+        self.mark_dirty(DirtyRect::full());
+
+        self.quirks = Quirks::from_bits(r.u8()?);
+        self.opcode = r.u16()?;
+        r.bytes(&mut self.v)?;
+        self.i = r.u16()?;
+        self.sound_timer = r.u8()?;
+        self.delay_timer = r.u8()?;
+        self.pc = r.u32()? as usize;
+        self.sp = r.u8()? as usize;
+        for address in self.stack.iter_mut() {
+            *address = r.u16()?;
+        }
+        r.bytes(&mut self.memory)?;
+        for pressed in self.key_buff.iter_mut() {
+            *pressed = r.bool()?;
+        }
+        for row in self.disp_buff.iter_mut() {
+            r.bytes(row)?;
+        }
+        self.plane = r.u8()?;
+        self.hires = r.bool()?;
+        r.bytes(&mut self.rpl)?;
+        r.bytes(&mut self.audio_pattern)?;
+        self.has_audio_pattern = r.bool()?;
+        self.pitch = r.u8()?;
+        self.vblank = r.bool()?;
+        r.u8()?;
+        self.rng.set_state(r.u64()?);
+        r.finish()
+    }
+
+    // The length of what save_state() writes: the fixed size registers and
+    // header, plus memory and the display.
+    fn state_len(&self) -> usize {
+        136 + self.memory.len() + DISPLAY_WIDTH * DISPLAY_HEIGHT
+    }
+
+    fn fetch_opcode(&mut self) -> Result<(), ExecutionError> {
+        if self.pc + 1 >= self.memory.len() {
+            return Err(ExecutionError::MemoryOutOfBounds { address: self.pc, target: self.pc + 1 });
+        }
         self.opcode = (self.memory[self.pc] as u16) << 8 | (self.memory[self.pc + 1] as u16);
+        Ok(())
     }
 
     fn inc_pc(&mut self) {
         self.pc += 2;
     }
 
-    fn opcode_unimplemented(&self) {
-        println!("opcode {:X} is not implemented.", self.opcode);
-        println!("Emulator exiting.");
-        process::exit(0);
+    // Skips over the instruction after the current one. That's usually 2 bytes,
+    // but XO-CHIP's F000 nnnn is 4 bytes long.
+    fn skip_next(&mut self) {
+        let next = self.pc + 2;
+        if next + 1 < self.memory.len() && self.memory[next] == 0xF0 && self.memory[next + 1] == 0x00 {
+            self.pc += 4;
+        } else {
+            self.pc += 2;
+        }
+    }
+
+    fn opcode_unimplemented(&self) -> Result<(), ExecutionError> {
+        Err(ExecutionError::UnknownOpcode { opcode: self.opcode, address: self.pc })
+    }
+
+    // Checks that the `len` bytes starting at `start` are all in memory.
+    fn check_memory(&self, start: usize, len: usize) -> Result<(), ExecutionError> {
+        if start + len > self.memory.len() {
+            return Err(ExecutionError::MemoryOutOfBounds { address: self.pc, target: start + len - 1 });
+        }
+        Ok(())
     }
 
-    fn opcode_execute(&mut self) {
+    fn opcode_execute(&mut self) -> Result<(), ExecutionError> {
         match self.opcode & 0xf000 {
-            0x0000 => self.op_0xxx(),
+            0x0000 => return self.op_0xxx(),
             0x1000 => self.op_jp(),
-            0x2000 => self.op_call(),
+            0x2000 => return self.op_call(),
             0x3000 => self.op_se(),
             0x4000 => self.op_sne(),
-            0x5000 => self.op_se_vx_vy(),
+            0x5000 => return self.op_5xxx(),
             0x6000 => self.op_ld_vx_byte(),
             0x7000 => self.op_add_vx_byte(),
-            0x8000 => self.op_8xxx(),
+            0x8000 => return self.op_8xxx(),
             0x9000 => self.op_sne_vx_vy(),
             0xA000 => self.op_ld_i_addr(),
             0xB000 => self.op_jp_v0_addr(),
             0xC000 => self.op_rnd_vx_byte(),
-            0xD000 => self.op_drw_vx_vy_n(),
-            0xE000 => self.op_exxx(),
-            0xF000 => self.op_fxxx(),
-            _      => self.opcode_unimplemented()
+            0xD000 => return self.op_drw_vx_vy_n(),
+            0xE000 => return self.op_exxx(),
+            0xF000 => return self.op_fxxx(),
+            _      => return self.opcode_unimplemented()
         }
+        Ok(())
     }
 
-    fn op_0xxx(&mut self) {
+    fn op_0xxx(&mut self) -> Result<(), ExecutionError> {
         match self.opcode {
+            0x00C0 ..= 0x00CF => self.op_scd_n(),
+            0x00D0 ..= 0x00DF => self.op_scu_n(),
             0x00E0 => self.op_cls(),
-            0x00EE => self.op_ret(),
-            0x0000 => {
-                println!("Reached a 0000 instruction. Emulation terminated.");
-                process::exit(0);
-            }
-            _      => self.opcode_unimplemented()
+            0x00EE => return self.op_ret(),
+            0x00FB => self.op_scr(),
+            0x00FC => self.op_scl(),
+            0x00FD => return Err(ExecutionError::Exited { address: self.pc }),
+            0x0000 => return Err(ExecutionError::Halted { address: self.pc }),
+            0x00FE => self.op_low(),
+            0x00FF => self.op_high(),
+            _      => return self.opcode_unimplemented()
         }
+        Ok(())
     }
 
-    fn op_8xxx(&mut self) {
+    fn op_8xxx(&mut self) -> Result<(), ExecutionError> {
         match self.opcode & 0x000f {
             0   => self.op_ld_vx_vy(),
             1   => self.op_or(),
@@ -133,47 +470,146 @@ impl Cpu {
             6   => self.op_shr_vx_vy(),
             7   => self.op_subn_vx_vy(),
             0xE => self.op_shl_vx_vy(),
-            _   => self.opcode_unimplemented()
+            _   => return self.opcode_unimplemented()
+        }
+        Ok(())
+    }
+
+    fn op_5xxx(&mut self) -> Result<(), ExecutionError> {
+        match self.opcode & 0x000f {
+            0 => self.op_se_vx_vy(),
+            2 => return self.op_save_vx_vy(),
+            3 => return self.op_load_vx_vy(),
+            _ => return self.opcode_unimplemented()
         }
+        Ok(())
     }
 
-    fn op_fxxx(&mut self) {
+    fn op_fxxx(&mut self) -> Result<(), ExecutionError> {
+        if self.opcode == 0xF000 {
+            return self.op_ld_i_long();
+        }
+        if self.opcode == 0xF002 {
+            return self.op_audio();
+        }
         match self.opcode & 0x00FF {
+            0x01 => self.op_plane_n(),
             0x07 => self.op_ld_vx_dt(),
             0x0A => self.op_ld_vx_k(),
             0x15 => self.op_ld_dt_vx(),
             0x18 => self.op_ld_st_vx(),
             0x1E => self.op_add_i_vx(),
             0x29 => self.op_ld_f_vx(),
-            0x33 => self.op_ld_b_vx(),
-            0x55 => self.op_ld_i_vx(),
-            0x65 => self.op_ld_vx_i(),
-            _    => self.opcode_unimplemented()
+            0x30 => self.op_ld_hf_vx(),
+            0x33 => return self.op_ld_b_vx(),
+            0x3A => self.op_pitch_vx(),
+            0x55 => return self.op_ld_i_vx(),
+            0x65 => return self.op_ld_vx_i(),
+            0x75 => self.op_ld_r_vx(),
+            0x85 => self.op_ld_vx_r(),
+            _    => return self.opcode_unimplemented()
         }
+        Ok(())
     }
 
-    fn op_exxx(&mut self) {
+    fn op_exxx(&mut self) -> Result<(), ExecutionError> {
         match self.opcode & 0x00FF {
             0x9E => self.op_skp_vx(),
             0xA1 => self.op_sknp_vx(),
-            _    => self.opcode_unimplemented()
+            _    => return self.opcode_unimplemented()
         }
+        Ok(())
+    }
+
+    // Moves the selected bitplanes of the active display area by (dx, dy).
+    // Pixels scrolled in from the edges are blank.
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let width = self.display_width() as isize;
+        let height = self.display_height() as isize;
+        let plane = self.plane;
+        let old = self.disp_buff;
+        for y in 0 .. height {
+            for x in 0 .. width {
+                let (from_x, from_y) = (x - dx, y - dy);
+                let moved = if from_x >= 0 && from_x < width && from_y >= 0 && from_y < height {
+                    old[from_y as usize][from_x as usize] & plane
+                } else {
+                    0
+                };
+                let pixel = &mut self.disp_buff[y as usize][x as usize];
+                *pixel = (*pixel & !plane) | moved;
+            }
+        }
+        self.mark_display_dirty();
+    }
+
+    // 00Cn - SCD nibble -- Scroll the display down by n pixels.
+    fn op_scd_n(&mut self) {
+        let n = self.get_n() as isize;
+        self.scroll(0, n);
+        self.inc_pc();
+    }
+
+    // 00Dn - SCU nibble -- XO-CHIP: Scroll the display up by n pixels.
+    fn op_scu_n(&mut self) {
+        let n = self.get_n() as isize;
+        self.scroll(0, -n);
+        self.inc_pc();
     }
 
     // 00E0 - CLS -- Clear the display.
+    // Only the selected bitplanes are cleared.
     fn op_cls(&mut self) {
-        self.disp_buff = [[false; 64]; 32];
+        let plane = self.plane;
+        for row in self.disp_buff.iter_mut() {
+            for pixel in row.iter_mut() {
+                *pixel &= !plane;
+            }
+        }
+        self.mark_display_dirty();
+
+        self.inc_pc();
+    }
 
+    // 00FB - SCR -- Scroll the display right by 4 pixels.
+    fn op_scr(&mut self) {
+        self.scroll(4, 0);
+        self.inc_pc();
+    }
+
+    // 00FC - SCL -- Scroll the display left by 4 pixels.
+    fn op_scl(&mut self) {
+        self.scroll(-4, 0);
+        self.inc_pc();
+    }
+
+    // 00FE - LOW -- Switch to the 64x32 low resolution mode, clearing the display.
+    fn op_low(&mut self) {
+        self.hires = false;
+        self.disp_buff = [[0; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+        self.mark_dirty(DirtyRect::full());
+        self.inc_pc();
+    }
+
+    // 00FF - HIGH -- Switch to the 128x64 high resolution mode, clearing the display.
+    fn op_high(&mut self) {
+        self.hires = true;
+        self.disp_buff = [[0; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+        self.mark_dirty(DirtyRect::full());
         self.inc_pc();
     }
 
     // 00EE - RET -- Return from a subroutine.
     // Sets program counter to address at the top of the stack, then subtracts 1 from
     // the stack pointer.
-    fn op_ret(&mut self) {
+    fn op_ret(&mut self) -> Result<(), ExecutionError> {
+        if self.sp == 0 {
+            return Err(ExecutionError::StackUnderflow { address: self.pc });
+        }
         self.sp -= 1;
         self.pc = self.stack[self.sp] as usize;
         self.inc_pc();
+        Ok(())
     }
 
     // 1nnn - JP addr -- Jump to location nnn
@@ -185,17 +621,21 @@ impl Cpu {
     // 2nnn - CALL addr -- Call subroutine at nnn
     // Increments the stack pointer, then puts the current PC on the top of the stack.
     // The PC is then set to nnn.
-    fn op_call(&mut self) {
+    fn op_call(&mut self) -> Result<(), ExecutionError> {
+        if self.sp == self.stack.len() {
+            return Err(ExecutionError::StackOverflow { address: self.pc });
+        }
         self.stack[self.sp] = self.pc as u16;
         self.sp += 1;
         self.pc = self.get_nnn() as usize;
+        Ok(())
     }
 
     // 3xkk - SE Vx, byte -- Skip next instruction if Vx = kk
     // Compare register Vx to kk, and if equal, increment the program counter by 2.
     fn op_se(&mut self) {
         if self.v[self.get_x() as usize] == self.get_kk() {
-            self.inc_pc();
+            self.skip_next();
         }
 
         self.inc_pc();
@@ -205,7 +645,7 @@ impl Cpu {
     // Compare register Vx to kk, and if not equal, increment the program counter by 2.
     fn op_sne(&mut self) {
         if self.v[self.get_x() as usize] != self.get_kk() {
-            self.inc_pc();
+            self.skip_next();
         }
 
         self.inc_pc();
@@ -214,15 +654,48 @@ impl Cpu {
     // 5xy0 - SE Vx, Vy -- Skip next instruction if Vx = Vy
     // Compare register Vx to register Vy, and if they are equal, increment
     // the program counter by 2.
-    // For now we're gonna be lazy. 5xyz is considered equivalent to 5xy0 for all z.
     fn op_se_vx_vy(&mut self) {
         if self.v[self.get_x() as usize] == self.v[self.get_y() as usize] {
-            self.inc_pc();
+            self.skip_next();
         }
 
         self.inc_pc();
     }
 
+    // 5xy2 - SAVE Vx, Vy -- XO-CHIP: Store registers Vx through Vy in memory
+    // starting at location I.
+    // Works in either direction, so Vx may come after Vy. I is not changed.
+    fn op_save_vx_vy(&mut self) -> Result<(), ExecutionError> {
+        let x = self.get_x() as usize;
+        let y = self.get_y() as usize;
+        let i = self.i as usize;
+        let count = if x > y { x - y } else { y - x } + 1;
+        self.check_memory(i, count)?;
+        for n in 0 .. count {
+            let register = if x > y { x - n } else { x + n };
+            self.memory[i + n] = self.v[register];
+        }
+        self.inc_pc();
+        Ok(())
+    }
+
+    // 5xy3 - LOAD Vx, Vy -- XO-CHIP: Read registers Vx through Vy from memory
+    // starting at location I.
+    // Works in either direction, so Vx may come after Vy. I is not changed.
+    fn op_load_vx_vy(&mut self) -> Result<(), ExecutionError> {
+        let x = self.get_x() as usize;
+        let y = self.get_y() as usize;
+        let i = self.i as usize;
+        let count = if x > y { x - y } else { y - x } + 1;
+        self.check_memory(i, count)?;
+        for n in 0 .. count {
+            let register = if x > y { x - n } else { x + n };
+            self.v[register] = self.memory[i + n];
+        }
+        self.inc_pc();
+        Ok(())
+    }
+
     // 6xkk - LD Vx, byte -- Set Vx = kk
     // Puts the value kk into register Vx.
     fn op_ld_vx_byte(&mut self) {
@@ -257,6 +730,7 @@ impl Cpu {
         let x = self.get_x() as usize;
         let y = self.get_y() as usize;
         self.v[x] = self.v[x] | self.v[y];
+        if self.quirks.vf_reset { self.v[0xF] = 0; }
         self.inc_pc();
     }
 
@@ -266,6 +740,7 @@ impl Cpu {
         let x = self.get_x() as usize;
         let y = self.get_y() as usize;
         self.v[x] = self.v[x] & self.v[y];
+        if self.quirks.vf_reset { self.v[0xF] = 0; }
         self.inc_pc();
     }
 
@@ -275,6 +750,7 @@ impl Cpu {
         let x = self.get_x() as usize;
         let y = self.get_y() as usize;
         self.v[x] = self.v[x] ^ self.v[y];
+        if self.quirks.vf_reset { self.v[0xF] = 0; }
         self.inc_pc();
     }
 
@@ -318,13 +794,15 @@ impl Cpu {
 
     // 8xy6 - SHR Vx, Vy -- Set Vx = Vy SHR 1
     // Set VF to least significant bit of Vy, shift value of Vy right by one,
-    // and store the result to Vx.
+    // and store the result to Vx. Without the shift_uses_vy quirk, Vx is
+    // shifted instead of Vy.
     fn op_shr_vx_vy(&mut self) {
         let x = self.get_x() as usize;
-        let y = self.get_y() as usize;
+        let y = if self.quirks.shift_uses_vy { self.get_y() as usize } else { x };
 
-        self.v[0xf] = self.v[y] & 1;
-        self.v[x] = self.v[y] >> 1;
+        let shifted = self.v[y];
+        self.v[x] = shifted >> 1;
+        self.v[0xf] = shifted & 1;
         self.inc_pc();
     }
 
@@ -347,13 +825,15 @@ impl Cpu {
 
     // 8xyE - SHL Vx, Vy -- Set Vx = Vy SHL 1
     // Set VF to most significant bit of Vy, shift value of Vy left by one,
-    // and store the result to Vx.
+    // and store the result to Vx. Without the shift_uses_vy quirk, Vx is
+    // shifted instead of Vy.
     fn op_shl_vx_vy(&mut self) {
         let x = self.get_x() as usize;
-        let y = self.get_y() as usize;
+        let y = if self.quirks.shift_uses_vy { self.get_y() as usize } else { x };
 
-        self.v[0xf] = self.v[y]>> 7;
-        self.v[x] = self.v[y] << 1;
+        let shifted = self.v[y];
+        self.v[x] = shifted << 1;
+        self.v[0xf] = shifted >> 7;
         self.inc_pc();
     }
 
@@ -362,7 +842,7 @@ impl Cpu {
     // is increased by two.
     fn op_sne_vx_vy(&mut self) {
         if self.v[self.get_x() as usize] != self.v[self.get_y() as usize] {
-            self.inc_pc();
+            self.skip_next();
         }
 
         self.inc_pc();
@@ -377,15 +857,19 @@ impl Cpu {
     }
 
     // Bnnn - JP V0, addr -- Jump to location nnn + V0
-    // Program counter set to nnn plus the value of V0.
+    // Program counter set to nnn plus the value of V0. With the jump_uses_vx
+    // quirk this is Bxnn instead, jumping to xnn plus the value of Vx.
     fn op_jp_v0_addr(&mut self) {
-        self.pc = self.v[0] as usize + self.get_nnn() as usize;
+        let x = if self.quirks.jump_uses_vx { self.get_x() as usize } else { 0 };
+        self.pc = self.v[x] as usize + self.get_nnn() as usize;
     }
 
     // Cxkk - RND Vx, byte -- Set Vx = random byte AND kk
     // Generate random value from 0 to 255, AND with value kk. Store result in Vx.
+    // The random byte comes from the cpu's RandomSource.
     fn op_rnd_vx_byte(&mut self) {
-        self.v[self.get_x() as usize] = rand::random::<u8>() & self.get_kk();
+        let random = self.rng.next_byte();
+        self.v[self.get_x() as usize] = random & self.get_kk();
         self.inc_pc();
     }
 
@@ -394,53 +878,83 @@ impl Cpu {
     // Reads n bytes from memory, starting at address stored in I. These bytes
     // are then displayed as sprites on the screen at coords (Vx, Vy).
     // Sprites are XOR'd onto the screen. If this causes any pixels to be erased,
-    // VF is set to 1, else 0. If the sprite is positioned so part of it is outside
-    // of the coordinates of the display, it wraps around to the other side of the
-    // screen. If sprite is to be displayted on the screen, Vx must be between
-    // 00 and 3F and Vy must be between 00 and 1F.
-    fn op_drw_vx_vy_n(&mut self) {
-        let vx = self.v[self.get_x() as usize] as usize;
-        let vy = self.v[self.get_y() as usize] as usize;
+    // VF is set to 1, else 0. The starting coordinates always wrap around the
+    // display; whether the parts of the sprite that go past the edge wrap around
+    // to the other side or are clipped depends on the wrap_sprites quirk.
+    // With the display_wait quirk, the instruction is retried until the next
+    // timer tick has happened, as the VIP waited for vertical blank to draw.
+    // Dxy0 - DRW Vx, Vy, 0 -- SUPER-CHIP: as above, but draws a 16x16 sprite
+    // made of 32 bytes, two per row.
+    // XO-CHIP: the sprite is drawn to each selected bitplane, and VF is set if
+    // a pixel was erased on any of them.
+    fn op_drw_vx_vy_n(&mut self) -> Result<(), ExecutionError> {
         let n = self.get_n() as usize;
+        let (sprite_width, rows) = if n == 0 { (16, 16) } else { (8, n) };
+        let bytes_per_row = sprite_width / 8;
+        let sprite_len = rows * bytes_per_row;
+        let planes: Vec<u8> = [1, 2].iter().cloned().filter(|p| self.plane & p != 0).collect();
         let i = self.i as usize;
-        let mut flipped = false;
+        self.check_memory(i, sprite_len * planes.len())?;
+
+        if self.quirks.display_wait {
+            if !self.vblank { return Ok(()); }
+            self.vblank = false;
+        }
 
-        if (vx > 0x3F) | (vy > 0x1F) { return; }
-        {
-            // Read n bytes from memory -- this is the sprite.
-            // n is number of bytes, where each row of the sprite is 1 byte.
-            let sprite = &self.memory[i .. i + n];
-
-            // find our (x, y) to display pixel of sprite at
-            // This gets the row we're on...
-            for row_index in 0 .. n {
-                if vy + row_index > 31 { break; }
-                let row = &mut self.disp_buff[row_index + vy];
-
-                // get the slice of the row we'll be modifying, starting at x = Vx
-                let vxp8; // this clips "vx + 8" so that the maximum value is 64.
-                if vx + 8 > 64 { vxp8 = 64; } else { vxp8 = vx + 8; }
-                let row_slice = &mut row[vx .. vxp8];
-                // Get the current row of the slice
-                let cur_row_sprite = &sprite[row_index];
-
-                // now apply it to display buffer's rows by XOR, flipping if necessary
-                for pixel in 0..row_slice.len() {
-                        // mask and shift to get current bit of sprite
-                        let sprite_pixel = cur_row_sprite & (0x80 >> pixel) != 0;
-
-                        if row_slice[pixel] & sprite_pixel {
-                            flipped = true;
-                        }
-
-                        row_slice[pixel] = row_slice[pixel] ^ sprite_pixel;
+        let width = self.display_width();
+        let height = self.display_height();
+        let vx = self.v[self.get_x() as usize] as usize % width;
+        let vy = self.v[self.get_y() as usize] as usize % height;
+        let mut flipped = false;
+        // Around the pixels changed. A sprite that wraps around the edges
+        // makes it span the display.
+        let mut changed: Option<DirtyRect> = None;
+
+        // With both XO-CHIP bitplanes selected, the sprite for the second plane
+        // follows straight after the one for the first.
+        for (plane_index, &plane) in planes.iter().enumerate() {
+            let sprite = i + plane_index * sprite_len;
+
+            for row_index in 0 .. rows {
+                let mut y = vy + row_index;
+                if y >= height {
+                    if !self.quirks.wrap_sprites { break; }
+                    y %= height;
+                }
+                // Each row of the sprite is 1 or 2 bytes. Left align it in 16
+                // bits so both sizes are masked the same way.
+                let row_address = sprite + row_index * bytes_per_row;
+                let mut sprite_row = (self.memory[row_address] as u16) << 8;
+                if bytes_per_row == 2 {
+                    sprite_row |= self.memory[row_address + 1] as u16;
                 }
 
+                for pixel in 0 .. sprite_width {
+                    let mut x = vx + pixel;
+                    if x >= width {
+                        if !self.quirks.wrap_sprites { break; }
+                        x %= width;
+                    }
+                    // mask and shift to get current bit of sprite
+                    if sprite_row & (0x8000 >> pixel) == 0 { continue; }
+
+                    if self.disp_buff[y][x] & plane != 0 {
+                        flipped = true;
+                    }
+
+                    self.disp_buff[y][x] ^= plane;
+                    let pixel = DirtyRect { x: x, y: y, width: 1, height: 1 };
+                    changed = Some(changed.map_or(pixel, |changed| changed.union(pixel)));
+                }
             }
         }
 
+        if let Some(changed) = changed {
+            self.mark_dirty(changed);
+        }
         if flipped { self.v[0xF] = 1} else { self.v[0xF] = 0 }
         self.inc_pc();
+        Ok(())
     }
 
     // Ex9E - SKP Vx -- Skip next instruction if key with value of Vx is pressed.
@@ -448,9 +962,9 @@ impl Cpu {
     // Vx is currently in the down position, the PC is incremented by two
     // (but since each instruction is manually incrementing pc, four)
     fn op_skp_vx(&mut self) {
-        let key = self.v[self.get_x() as usize] as usize;
+        let key = (self.v[self.get_x() as usize] & 0xF) as usize;
         if self.key_buff[key] {
-            self.inc_pc();
+            self.skip_next();
         }
         self.inc_pc();
     }
@@ -460,13 +974,41 @@ impl Cpu {
     // Vx is currently in the up position, the PC is incremented by two
     // (but since each instruction is manually incrementing pc, four)
     fn op_sknp_vx(&mut self) {
-        let key = self.v[self.get_x() as usize] as usize;
+        let key = (self.v[self.get_x() as usize] & 0xF) as usize;
         if !self.key_buff[key] {
-            self.inc_pc();
+            self.skip_next();
         }
         self.inc_pc();
     }
 
+    // F000 nnnn - LD I, long nnnn -- XO-CHIP: Set I = nnnn.
+    // The 16 bit address is read from the two bytes after the instruction, making
+    // this the only 4 byte instruction.
+    fn op_ld_i_long(&mut self) -> Result<(), ExecutionError> {
+        let pc = self.pc;
+        self.check_memory(pc + 2, 2)?;
+        self.i = (self.memory[pc + 2] as u16) << 8 | self.memory[pc + 3] as u16;
+        self.pc += 4;
+        Ok(())
+    }
+
+    // Fn01 - PLANE n -- XO-CHIP: Select bitplanes n for drawing, clearing and scrolling.
+    // n is a mask of the planes: 0 is none, 1 is the first, 2 the second and 3 both.
+    fn op_plane_n(&mut self) {
+        self.plane = self.get_x() & 0x3;
+        self.inc_pc();
+    }
+
+    // F002 - AUDIO -- XO-CHIP: Load the 16 byte audio pattern starting at I.
+    fn op_audio(&mut self) -> Result<(), ExecutionError> {
+        let i = self.i as usize;
+        self.check_memory(i, 16)?;
+        self.audio_pattern.copy_from_slice(&self.memory[i .. i + 16]);
+        self.has_audio_pattern = true;
+        self.inc_pc();
+        Ok(())
+    }
+
     // Fx07 - LD Vx, DT -- Set Vx = delay timer value.
     // Value of DT is placed into Vx.
     fn op_ld_vx_dt(&mut self) {
@@ -506,7 +1048,7 @@ impl Cpu {
     // Fx1E - ADD I, Vx -- Set I = I + Vx
     // Values of I and Vx are added, results stored in I.
     fn op_add_i_vx(&mut self) {
-        self.i = self.i + self.v[self.get_x() as usize] as u16;
+        self.i = self.i.wrapping_add(self.v[self.get_x() as usize] as u16);
         self.inc_pc();
     }
 
@@ -514,7 +1056,15 @@ impl Cpu {
     // Value of I is set to location for hex sprite corresponding to value of
     // Vx.
     fn op_ld_f_vx(&mut self) {
-        self.i = (self.v[self.get_x() as usize] * 5) as u16;
+        self.i = (self.v[self.get_x() as usize] & 0xF) as u16 * 5;
+        self.inc_pc();
+    }
+
+    // Fx30 - LD HF, Vx -- Set I = location of large sprite for digit Vx.
+    // Value of I is set to location for the 8x10 hex sprite corresponding to
+    // the value of Vx.
+    fn op_ld_hf_vx(&mut self) {
+        self.i = (BIG_FONT_ADDRESS + (self.v[self.get_x() as usize] & 0xF) as usize * 10) as u16;
         self.inc_pc();
     }
 
@@ -522,38 +1072,68 @@ impl Cpu {
     // I+1 and I+1.
     // Take the decimal value of Vx, place the hundreds digit in memory at location I,
     // the tens digit at I+1, and the ones digit at I+2.
-    fn op_ld_b_vx(&mut self) {
+    fn op_ld_b_vx(&mut self) -> Result<(), ExecutionError> {
         let vx = self.v[self.get_x() as usize];
         let i = self.i as usize;
+        self.check_memory(i, 3)?;
         self.memory[i] = vx / 100;
         self.memory[i + 1] = (vx / 10) % 10;
         self.memory[i + 2] = (vx %100) %10;
         self.inc_pc();
+        Ok(())
     }
 
     // Fx55 - LD [I], Vx -- Store registers V0 through Vx in memory starting at location I.
     // The interpreter copies the values of registers V0 through Vx into memory, starting at
-    // the address in I.
-    fn op_ld_i_vx(&mut self) {
+    // the address in I. Then set I to I + X + 1 if the load_store_increments_i quirk is on.
+    fn op_ld_i_vx(&mut self) -> Result<(), ExecutionError> {
         let x = self.get_x() as u16;
         let i = self.i;
+        self.check_memory(i as usize, x as usize + 1)?;
         for n in 0..=x {
             self.memory[(i + n) as usize] = self.v[n as usize];
         }
-        self.i = i + x + 1;
+        if self.quirks.load_store_increments_i {
+            self.i = i.wrapping_add(x + 1);
+        }
         self.inc_pc();
+        Ok(())
     }
     
     // Fx65 - LD Vx, [I] -- Read register V0 through Vx from memory starting @ I.
     // Reads values from memory starting at location I into register V0 through Vx.
-    // Then set I to I + X + 1.
-    fn op_ld_vx_i(&mut self) {
+    // Then set I to I + X + 1 if the load_store_increments_i quirk is on.
+    fn op_ld_vx_i(&mut self) -> Result<(), ExecutionError> {
         let x = self.get_x() as u16;
         let i = self.i;
+        self.check_memory(i as usize, x as usize + 1)?;
         for n in 0..=x {
             self.v[n as usize] = self.memory[(i + n) as usize];
         }
-        self.i = i + x + 1;
+        if self.quirks.load_store_increments_i {
+            self.i = i.wrapping_add(x + 1);
+        }
+        self.inc_pc();
+        Ok(())
+    }
+
+    // Fx3A - PITCH Vx -- XO-CHIP: Set the audio pattern playback pitch to Vx.
+    fn op_pitch_vx(&mut self) {
+        self.pitch = self.v[self.get_x() as usize];
+        self.inc_pc();
+    }
+
+    // Fx75 - LD R, Vx -- Store registers V0 through Vx in the RPL user flags.
+    fn op_ld_r_vx(&mut self) {
+        let x = self.get_x() as usize;
+        self.rpl[..= x].copy_from_slice(&self.v[..= x]);
+        self.inc_pc();
+    }
+
+    // Fx85 - LD Vx, R -- Read registers V0 through Vx from the RPL user flags.
+    fn op_ld_vx_r(&mut self) {
+        let x = self.get_x() as usize;
+        self.v[..= x].copy_from_slice(&self.rpl[..= x]);
         self.inc_pc();
     }
 
@@ -582,14 +1162,42 @@ static FONT_SPRITES: [u8; 80] = [0xF0, 0x90, 0x90, 0x90, 0xF0,  // 0
                                  0xF0, 0x80, 0xF0, 0x80, 0xF0,  // E
                                  0xF0, 0x80, 0xF0, 0x80, 0x80]; // F
 
+// SUPER-CHIP's 8x10 digits, extended with the A to F glyphs from XO-CHIP.
+static BIG_FONT_SPRITES: [u8; 160] = [0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF,  // 0
+                                      0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF,  // 1
+                                      0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF,  // 2
+                                      0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF,  // 3
+                                      0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03,  // 4
+                                      0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF,  // 5
+                                      0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF,  // 6
+                                      0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18,  // 7
+                                      0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF,  // 8
+                                      0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF,  // 9
+                                      0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3,  // A
+                                      0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC,  // B
+                                      0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C,  // C
+                                      0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC,  // D
+                                      0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF,  // E
+                                      0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0]; // F
+
+// A cpu with the default quirks and `program` loaded, for the tests of the
+// modules built on it.
+#[cfg(test)]
+pub fn cpu_with(program: Vec<u8>) -> Cpu {
+    let mut cpu = Cpu::new(Quirks::default());
+    Cpu::load_data(&mut cpu, program);
+    cpu
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rng::VipStyleRandom;
 
     #[test]
     fn test_loading_bytes_from_vector() {
         let data = vec![0x1, 0x2, 0x3, 0x4];
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         let mut results = [0; 4096];
         for (index, &byte) in data.iter().enumerate() {
             results[index] = byte;
@@ -603,27 +1211,27 @@ mod tests {
     #[test]
     fn test_fetching_opcode() {
         let data = vec![1, 1];
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, data);
-        cpu.fetch_opcode();
+        cpu.fetch_opcode().unwrap();
         assert_eq!(257, cpu.opcode)
     }
 
     #[test]
     fn test_op_cls() {
-        let mut cpu = Cpu::new();
-        cpu.disp_buff = [[true; 64]; 32];
+        let mut cpu = Cpu::new(Quirks::default());
+        cpu.disp_buff = [[1; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
         cpu.op_cls();
-        for i in 0..32 {
-            for ii in 0..64 {
-                assert_eq!(false, cpu.disp_buff[i][ii])
+        for i in 0..DISPLAY_HEIGHT {
+            for ii in 0..DISPLAY_WIDTH {
+                assert_eq!(0, cpu.disp_buff[i][ii])
             }
         }
     }
 
     #[test]
     fn test_execute_ret() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
 
         // Load the following program into the CPU.
         // 0x200: 00E0 
@@ -637,7 +1245,7 @@ mod tests {
         cpu.memory[0x386] = 0x00;
         cpu.memory[0x387] = 0xEE;
         for _ in 0..3 {
-            cpu.emulate_cycle();
+            cpu.emulate_cycle().unwrap();
         }
         assert_eq!(cpu.pc, 0x204);
         assert_eq!(cpu.sp, 0);
@@ -646,7 +1254,7 @@ mod tests {
 
     #[test]
     fn test_op_jp() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         cpu.opcode = 0x1386;
         cpu.op_jp();
         assert_eq!(cpu.pc, 0x386);
@@ -654,18 +1262,18 @@ mod tests {
 
     #[test]
     fn test_execute_jp() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x13, 0x86]);
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.pc, 0x386);
     }
 
     #[test]
     fn test_execute_call() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x00, 0xE0, 0x23, 0x86]);
-        cpu.emulate_cycle();
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.pc, 0x386);
         assert_eq!(cpu.sp, 1);
         assert_eq!(cpu.stack[0], 0x202);
@@ -673,315 +1281,349 @@ mod tests {
 
     #[test]
     fn test_se_if_true() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         cpu.v[3] = 0x88;
         Cpu::load_data(&mut cpu, vec![0x33, 0x88]);
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.pc, 0x200 + 4);
     }
 
     #[test]
     fn test_se_if_false() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         cpu.v[3] = 0x84;
         Cpu::load_data(&mut cpu, vec![0x33, 0x88]);
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.pc, 0x200 + 2);
     }
 
     #[test]
     fn test_sne_if_true() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         cpu.v[3] = 0x84;
         Cpu::load_data(&mut cpu, vec![0x43, 0x88]);
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.pc, 0x200 + 4);
     }
     
     #[test]
     fn test_sne_if_false() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         cpu.v[3] = 0x88;
         Cpu::load_data(&mut cpu, vec![0x43, 0x88]);
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.pc, 0x200 + 2);
     }
 
     #[test]
     fn test_se_vs_if_true() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         cpu.v[3] = 0x88;
         cpu.v[6] = 0x88;
         Cpu::load_data(&mut cpu, vec![0x53, 0x60]);
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.pc, 0x200 + 4);
     }
 
     #[test]
     fn test_se_vs_if_false() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         cpu.v[3] = 0x84;
         cpu.v[6] = 0x88;
         Cpu::load_data(&mut cpu, vec![0x33, 0x60]);
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.pc, 0x200 + 2);
     }
 
     #[test]
     fn test_ld_vx_byte() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x63, 0x92]);
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[3], 0x92);
     }
 
     #[test]
     fn test_add_vx_byte() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x73, 0x10]);
         cpu.v[3] = 0x70;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[3], 0x10 + 0x70);
     }
 
     #[test]
     fn test_add_vx_byte_overflow() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x73, 0x01]);
         cpu.v[3] = 0xff;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[3], 0);
     }
 
     #[test]
     fn test_ld_vx_vy() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x83, 0x70]);
         cpu.v[7] = 0x82;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[3], 0x82);
     }
 
     #[test]
     fn test_or() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x80, 0xA1]);
         cpu.v[0]   = 0b10110011;
         cpu.v[0xA] = 0b01101001;
         //      OR = 0b11111011
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[0], 0b11111011);
     }
 
     #[test]
     fn test_and() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x8B, 0xA2]);
         cpu.v[0xB] = 0b10110011;
         cpu.v[0xA] = 0b01101001;
         //     AND = 0b00100001;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[0xB], 0b00100001);
     }
 
     #[test]
     fn test_xor() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x8B, 0xA3]);
         cpu.v[0xB] = 0b10110011;
         cpu.v[0xA] = 0b01101001;
         //     XOR = 0b11011010;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[0xB], 0b11011010);
     }
 
     #[test]
     fn test_add_vx_vy() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x8A, 0xB4]);
         cpu.v[0xA] = 0x5;
         cpu.v[0xB] = 0x3;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[0xA], 0x8);
         assert_eq!(cpu.v[0xF], 0);
     }
 
     #[test]
     fn test_add_vx_vy_overflow() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x8A, 0xB4]);
         cpu.v[0xA] = 0xFF;
         cpu.v[0xB] = 0x1;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[0xA], 0);
         assert_eq!(cpu.v[0xF], 1);
     }
 
     #[test]
     fn test_sub_vx_vy() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x8A, 0xB5]);
         cpu.v[0xA] = 5;
         cpu.v[0xB] = 1;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[0xA], 4);
         assert_eq!(cpu.v[0xF], 1);
     }
 
     #[test]
     fn test_sub_vx_vy_overflow() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x8A, 0xB5]);
         cpu.v[0xA] = 0;
         cpu.v[0xB] = 1;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[0xA], 255);
         assert_eq!(cpu.v[0xF], 0);
     }
 
     #[test]
     fn test_shr_shift_x() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x84, 0x46]);
         cpu.v[4] = 0b11;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[4], 1);
     }
 
     #[test]
     fn test_shr_shift_y_to_x() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x84, 0x56]);
         cpu.v[5] = 0b10;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[4], 1);
     }
 
     #[test]
     fn test_shr_carry_0() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x84, 0x46]);
         cpu.v[4] = 0b10;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[0xf], 0);
     }
 
     #[test]
     fn test_shr_carry_1() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x84, 0x46]);
         cpu.v[4] = 0b11;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[0xf], 1);
     }
 
     #[test]
     fn test_subn_vx_vy() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x84, 0x57]);
         cpu.v[0x4] = 1;
         cpu.v[0x5] = 5;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[0x4], 4);
         assert_eq!(cpu.v[0xF], 1);
     }
 
     #[test]
     fn test_subn_vx_vy_overflow() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x84, 0x57]);
         cpu.v[0x4] = 1;
         cpu.v[0x5] = 0;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[0x4], 255);
         assert_eq!(cpu.v[0xF], 0);
     }
 
     #[test]
     fn test_shl_shift_x() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x84, 0x4E]);
         cpu.v[4] = 0b01;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[4], 0b10);
     }
 
     #[test]
     fn test_shl_shift_y_to_x() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x84, 0x5E]);
         cpu.v[5] = 0b10;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[4], 0b100);
     }
 
     #[test]
     fn test_shl_carry_0() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x84, 0x4E]);
         cpu.v[4] = 0b1;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[0xf], 0);
     }
 
     #[test]
     fn test_shl_carry_1() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x84, 0x4E]);
         cpu.v[4] = 0b11000000;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[0xf], 1);
     }
 
     #[test]
     fn test_sne_vx_vy_if_equal() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x93, 0x40]);
         cpu.v[3] = 0x88;
         cpu.v[4] = 0x88;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.pc, 0x200 + 2);
     }
 
     #[test]
     fn test_sne_vx_vy_if_not_equal() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x93, 0x40]);
         cpu.v[3] = 0x88;
         cpu.v[4] = 0x87;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.pc, 0x200 + 4);
     }
 
     #[test]
     fn test_ld_i_addr() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0xAA, 0xAA]);
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.i, 0xAAA);
     }
 
     #[test]
     fn test_op_jp_v0_addr() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0xB3, 0x86]);
         cpu.v[0] = 0x25;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.pc, 0x386 + 0x25);
     }
 
     #[test]
     fn test_rnd_vx_byte_masks_binary() {
-        let mut cpu = Cpu::new();
-        Cpu::load_data(&mut cpu, vec![0xC3, 0x01]);
+        let mut cpu = Cpu::with_seed(Quirks::default(), 4);
+        Cpu::load_data(&mut cpu, vec![0xC3, 0x01, 0xC3, 0x01]);
         cpu.v[3] = 2;
-        cpu.emulate_cycle();
-        assert!(cpu.v[3] <= 1);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.v[3], 0);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.v[3], 1);
+    }
+
+    #[test]
+    fn test_rnd_with_seed() {
+        let program = vec![0xC0, 0xFF, 0xC1, 0xFF, 0xC2, 0xFF];
+        let mut cpu = Cpu::with_seed(Quirks::default(), 42);
+        Cpu::load_data(&mut cpu, program.clone());
+        for _ in 0..3 {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!(&cpu.v[0..3], &[0x56, 0xC8, 0xCA]);
+
+        let mut again = Cpu::with_seed(Quirks::default(), 42);
+        Cpu::load_data(&mut again, program);
+        for _ in 0..3 {
+            again.emulate_cycle().unwrap();
+        }
+        assert_eq!(&again.v[0..3], &cpu.v[0..3]);
+    }
+
+    #[test]
+    fn test_rnd_with_vip_style_random() {
+        let mut cpu = Cpu::new(Quirks::cosmac_vip());
+        cpu.set_rng(Box::new(VipStyleRandom::new(0)));
+        // RND V0 FF, eight times.
+        Cpu::load_data(&mut cpu, [0xC0, 0xFF].iter().cloned().cycle().take(16).collect());
+        let mut numbers = Vec::new();
+        for _ in 0..8 {
+            cpu.emulate_cycle().unwrap();
+            numbers.push(cpu.v[0]);
+        }
+        assert!(numbers.iter().any(|&n| n != numbers[0]), "{:?}", numbers);
     }
 
     // #[test]
     // fn test_drw_vx_vy_n() {
-    //     let mut cpu = Cpu::new();
+    //     let mut cpu = Cpu::new(Quirks::default());
     //     Cpu::load_data(&mut cpu, vec![0x62, 0x02, 0x63, 0x03, 0xF3, 0x29, 0xD2, 0x35]);
     //     for _ in 0..4 {
     //         cpu.emulate_cycle();
@@ -1022,14 +1664,14 @@ mod tests {
 
     #[test]
     fn test_drw_vx_vy_n_erases() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x62, 0x02, 0x63, 0x03, 0xF3, 0x29, 0xD2, 0x35,
                                       0xD2, 0x35]);
         for _ in 0..5 {
-            cpu.emulate_cycle();
+            cpu.emulate_cycle().unwrap();
         }
 
-        let empty_disp = [[false; 64]; 32];
+        let empty_disp = [[0; 64]; 32];
         for i in 0..32 {
             for ii in 0..64 {
                 assert_eq!(cpu.disp_buff[i][ii], empty_disp[i][ii]);
@@ -1039,11 +1681,11 @@ mod tests {
 
     #[test]
     fn test_drw_vx_vy_n_sets_flip() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x62, 0x02, 0x63, 0x03, 0xF3, 0x29, 0xD2, 0x35,
                                       0xD2, 0x35]);
         for _ in 0..5 {
-            cpu.emulate_cycle();
+            cpu.emulate_cycle().unwrap();
         }
 
         assert_eq!(cpu.v[0xF], 1);
@@ -1051,11 +1693,11 @@ mod tests {
 
     #[test]
     fn test_drw_vx_vy_n_unsets_flip() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x62, 0x02, 0x63, 0x03, 0xF3, 0x29, 0xD2, 0x35,
                                       0xD2, 0x35, 0xD2, 0x35]);
         for _ in 0..6 {
-            cpu.emulate_cycle();
+            cpu.emulate_cycle().unwrap();
         }
 
         assert_eq!(cpu.v[0xF], 0);
@@ -1063,7 +1705,7 @@ mod tests {
 
     // #[test]
     // fn test_drw_vx_vy_n_clips() {
-    //     let mut cpu = Cpu::new();
+    //     let mut cpu = Cpu::new(Quirks::default());
     //     Cpu::load_data(&mut cpu, vec![0x62, 0x3F, 0x63, 0x1F, 0xF0, 0x29, 0xD2, 0x35]);
     //     for _ in 0..4 {
     //         cpu.emulate_cycle();
@@ -1084,7 +1726,7 @@ mod tests {
 
     // #[test]
     // fn test_ld_f_vx_0() {
-    //     let mut cpu = Cpu::new();
+    //     let mut cpu = Cpu::new(Quirks::default());
     //     Cpu::load_data(&mut cpu, vec![0x61, 0x00, 0xF1, 0x29, 0xD0, 0x05]);
     //     for _ in 0..3 {
     //         cpu.emulate_cycle();
@@ -1124,7 +1766,7 @@ mod tests {
 
     // #[test]
     // fn test_ld_f_vx_1() {
-    //     let mut cpu = Cpu::new();
+    //     let mut cpu = Cpu::new(Quirks::default());
     //     Cpu::load_data(&mut cpu, vec![0x61, 0x01, 0xF1, 0x29, 0xD0, 0x05]);
     //     for _ in 0..3 {
     //         cpu.emulate_cycle();
@@ -1165,62 +1807,82 @@ mod tests {
 
     #[test]
     fn test_timers() {
-        use std::thread;
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0x61, 0x01, 0x61, 0x01]);
         cpu.delay_timer = 120;
-        use std::{time};
-        let millis_18 = time::Duration::from_millis(18);
-        thread::sleep(millis_18);
-        cpu.emulate_cycle();
+        cpu.sound_timer = 1;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.delay_timer, 120);
+        cpu.tick_timers();
         assert_eq!(cpu.delay_timer, 119);
+        assert_eq!(cpu.sound_timer, 0);
+        cpu.tick_timers();
+        assert_eq!(cpu.sound_timer, 0);
+    }
+
+    #[test]
+    fn test_run_frame() {
+        // ADD V0 1, JP 200
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![0x70, 0x01, 0x12, 0x00]);
+        cpu.delay_timer = 10;
+        for _ in 0..3 {
+            cpu.run_frame(10).unwrap();
+        }
+        assert_eq!(cpu.v[0], 15);
+        assert_eq!(cpu.delay_timer, 7);
+    }
+
+    #[test]
+    fn test_run_frame_stops_on_error() {
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![0x00, 0xEE]);
+        cpu.delay_timer = 10;
+        assert!(cpu.run_frame(10).is_err());
+        assert_eq!(cpu.delay_timer, 10);
     }
 
     #[test]
     fn test_skp_vx_if_pressed() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0xE0, 0x9E]);
         cpu.key_buff[0] = true;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.pc, 0x204);
     }
     
     #[test]
     fn test_skp_vx_if_not_pressed() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0xE0, 0x9E]);
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.pc, 0x202);
     }
 
     #[test]
     fn test_sknp_vx_if_pressed() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0xE0, 0xA1]);
         cpu.key_buff[0] = true;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.pc, 0x202);
     }
     
     #[test]
     fn test_sknp_vx_if_not_pressed() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         Cpu::load_data(&mut cpu, vec![0xE0, 0xA1]);
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.pc, 0x204);
     }
 
     #[test]
     fn test_ld_b_vx() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         cpu.i = 0x500;
         cpu.v[0] = 136;
         Cpu::load_data(&mut cpu, vec![0xF0, 0x33]);
-        cpu.emulate_cycle();
-        println!("");
-        println!("0x500: {}", cpu.memory[0x500]);
-        println!("0x501: {}", cpu.memory[0x501]);
-        println!("0x502: {}", cpu.memory[0x502]);
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.memory[0x500], 1);
         assert_eq!(cpu.memory[0x501], 3);
         assert_eq!(cpu.memory[0x502], 6);
@@ -1228,14 +1890,14 @@ mod tests {
 
     #[test]
     fn test_ld_vx_i() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         cpu.i = 0x500;
         cpu.memory[0x500] = 0;
         cpu.memory[0x501] = 1;
         cpu.memory[0x502] = 2;
         cpu.memory[0x503] = 3;
         Cpu::load_data(&mut cpu, vec![0xF3, 0x65]);
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[0], 0);
         assert_eq!(cpu.v[1], 1);
         assert_eq!(cpu.v[2], 2);
@@ -1244,13 +1906,13 @@ mod tests {
 
     #[test]
     fn test_ld_i_vx() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         cpu.i = 0x500;
         cpu.v[0] = 0;
         cpu.v[1] = 1;
         cpu.v[2] = 2;
         Cpu::load_data(&mut cpu, vec![0xF2, 0x55]);
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.memory[0x500], 0);
         assert_eq!(cpu.memory[0x501], 1);
         assert_eq!(cpu.memory[0x502], 2);
@@ -1258,31 +1920,545 @@ mod tests {
 
     #[test]
     fn test_ld_dt_vx() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(Quirks::default());
         cpu.v[3] = 0x20;
         Cpu::load_data(&mut cpu, vec![0xF3, 0x15]);
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.delay_timer, 0x20);
     }
 
     #[test]
     fn test_ld_vx_k() {
-        let cpu = &mut Cpu::new();
+        let cpu = &mut Cpu::new(Quirks::default());
         Cpu::load_data(cpu, vec![0xFF, 0x0A]);
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.pc, 0x200);
         cpu.key_buff[3] = true;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.v[0xF], 3);
     }
 
     #[test]
     fn test_add_i_vx() {
-        let cpu = &mut Cpu::new();
+        let cpu = &mut Cpu::new(Quirks::default());
         Cpu::load_data(cpu, vec![0xFE, 0x1E]);
         cpu.i = 1;
         cpu.v[0xE] = 2;
-        cpu.emulate_cycle();
+        cpu.emulate_cycle().unwrap();
         assert_eq!(cpu.i, 3);
     }
+
+    #[test]
+    fn test_unknown_opcode_is_an_error() {
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![0x80, 0x0F]);
+        assert_eq!(cpu.emulate_cycle(),
+                   Err(ExecutionError::UnknownOpcode { opcode: 0x800F, address: 0x200 }));
+    }
+
+    #[test]
+    fn test_0000_halts() {
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![0x00, 0x00]);
+        assert_eq!(cpu.emulate_cycle(), Err(ExecutionError::Halted { address: 0x200 }));
+        assert_eq!(cpu.emulate_cycle(), Err(ExecutionError::Halted { address: 0x200 }));
+    }
+
+    #[test]
+    fn test_ret_with_empty_stack_underflows() {
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![0x00, 0xEE]);
+        assert_eq!(cpu.emulate_cycle(), Err(ExecutionError::StackUnderflow { address: 0x200 }));
+    }
+
+    #[test]
+    fn test_call_with_full_stack_overflows() {
+        let mut cpu = Cpu::new(Quirks::default());
+        // 0x200: CALL 200, forever.
+        Cpu::load_data(&mut cpu, vec![0x22, 0x00]);
+        for _ in 0..16 {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!(cpu.emulate_cycle(), Err(ExecutionError::StackOverflow { address: 0x200 }));
+    }
+
+    #[test]
+    fn test_ld_i_vx_out_of_bounds() {
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![0xF0, 0x00, 0xFF, 0xFE, 0xF3, 0x55]);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.emulate_cycle(),
+                   Err(ExecutionError::MemoryOutOfBounds { address: 0x204, target: 0x10001 }));
+    }
+
+    #[test]
+    fn test_fetch_past_end_of_memory() {
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![]);
+        cpu.pc = 0xFFFF;
+        assert_eq!(cpu.emulate_cycle(),
+                   Err(ExecutionError::MemoryOutOfBounds { address: 0xFFFF, target: 0x10000 }));
+    }
+
+    #[test]
+    fn test_shr_without_shift_uses_vy_shifts_x() {
+        let mut cpu = Cpu::new(Quirks::superchip());
+        Cpu::load_data(&mut cpu, vec![0x84, 0x56]);
+        cpu.v[4] = 0b111;
+        cpu.v[5] = 0b10;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.v[4], 0b11);
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_shl_without_shift_uses_vy_shifts_x() {
+        let mut cpu = Cpu::new(Quirks::superchip());
+        Cpu::load_data(&mut cpu, vec![0x84, 0x5E]);
+        cpu.v[4] = 0b10000001;
+        cpu.v[5] = 0b10;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.v[4], 0b10);
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_ld_i_vx_increments_i() {
+        let mut cpu = Cpu::new(Quirks::cosmac_vip());
+        cpu.i = 0x500;
+        Cpu::load_data(&mut cpu, vec![0xF2, 0x55]);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.i, 0x503);
+    }
+
+    #[test]
+    fn test_ld_i_vx_and_ld_vx_i_wrap_i_at_end_of_memory() {
+        let mut cpu = Cpu::new(Quirks::cosmac_vip());
+        Cpu::load_data(&mut cpu, vec![0xF0, 0x00, 0xFF, 0xFF, 0xF0, 0x55,
+                                      0xF0, 0x00, 0xFF, 0xFF, 0xF0, 0x65]);
+        cpu.v[0] = 0x2A;
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.memory[0xFFFF], 0x2A);
+        assert_eq!(cpu.i, 0);
+        cpu.v[0] = 0;
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.v[0], 0x2A);
+        assert_eq!(cpu.i, 0);
+    }
+
+    #[test]
+    fn test_ld_vx_i_leaves_i_without_quirk() {
+        let mut cpu = Cpu::new(Quirks::superchip());
+        cpu.i = 0x500;
+        Cpu::load_data(&mut cpu, vec![0xF2, 0x65]);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.i, 0x500);
+    }
+
+    #[test]
+    fn test_jp_vx_addr() {
+        let mut cpu = Cpu::new(Quirks::chip48());
+        Cpu::load_data(&mut cpu, vec![0xB3, 0x86]);
+        cpu.v[0] = 0x25;
+        cpu.v[3] = 0x10;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.pc, 0x386 + 0x10);
+    }
+
+    #[test]
+    fn test_vf_reset_on_logic_ops() {
+        // OR, AND and XOR of V0 and V1.
+        for &op in &[0x11, 0x12, 0x13] {
+            let mut cpu = Cpu::new(Quirks::cosmac_vip());
+            Cpu::load_data(&mut cpu, vec![0x80, op]);
+            cpu.v[0xF] = 1;
+            cpu.emulate_cycle().unwrap();
+            assert_eq!(cpu.v[0xF], 0);
+        }
+    }
+
+    #[test]
+    fn test_drw_vx_vy_n_clips() {
+        let mut cpu = Cpu::new(Quirks::default());
+        // Draw the 0 glyph at (62, 30).
+        Cpu::load_data(&mut cpu, vec![0x62, 0x3E, 0x63, 0x1E, 0xF0, 0x29, 0xD2, 0x35]);
+        for _ in 0..4 {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert!(cpu.disp_buff[30][62] == 1 && cpu.disp_buff[30][63] == 1);
+        assert!(cpu.disp_buff[30][0] == 0 && cpu.disp_buff[0][62] == 0);
+    }
+
+    #[test]
+    fn test_drw_vx_vy_n_wraps() {
+        let mut quirks = Quirks::default();
+        quirks.wrap_sprites = true;
+        let mut cpu = Cpu::new(quirks);
+        Cpu::load_data(&mut cpu, vec![0x62, 0x3E, 0x63, 0x1E, 0xF0, 0x29, 0xD2, 0x35]);
+        for _ in 0..4 {
+            cpu.emulate_cycle().unwrap();
+        }
+        // The top row of the 0 glyph is 0xF0, so it continues onto columns 0 and 1,
+        // and its last three rows continue onto rows 0 to 2.
+        assert!(cpu.disp_buff[30][62] == 1 && cpu.disp_buff[30][1] == 1);
+        assert!(cpu.disp_buff[0][62] == 1 && cpu.disp_buff[2][1] == 1);
+    }
+
+    #[test]
+    fn test_drw_waits_for_vblank() {
+        let mut cpu = Cpu::new(Quirks::cosmac_vip());
+        Cpu::load_data(&mut cpu, vec![0xD0, 0x05]);
+        cpu.vblank = false;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.pc, 0x200);
+        cpu.tick_timers();
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.pc, 0x202);
+        assert!(cpu.disp_buff[0][0] == 1);
+    }
+
+    #[test]
+    fn test_high_and_low_switch_resolution() {
+        let mut cpu = Cpu::new(Quirks::superchip());
+        Cpu::load_data(&mut cpu, vec![0x00, 0xFF, 0x00, 0xFE]);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!((cpu.display_width(), cpu.display_height()), (128, 64));
+        cpu.disp_buff[40][100] = 1;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!((cpu.display_width(), cpu.display_height()), (64, 32));
+        assert!(cpu.disp_buff[40][100] == 0);
+    }
+
+    #[test]
+    fn test_exit() {
+        let mut cpu = Cpu::new(Quirks::superchip());
+        Cpu::load_data(&mut cpu, vec![0x00, 0xFD]);
+        assert_eq!(cpu.emulate_cycle(), Err(ExecutionError::Exited { address: 0x200 }));
+    }
+
+    #[test]
+    fn test_scd_n() {
+        let mut cpu = Cpu::new(Quirks::superchip());
+        Cpu::load_data(&mut cpu, vec![0x00, 0xC3]);
+        cpu.disp_buff[0][5] = 1;
+        cpu.disp_buff[30][5] = 1;
+        cpu.emulate_cycle().unwrap();
+        assert!(cpu.disp_buff[0][5] == 0);
+        assert!(cpu.disp_buff[3][5] == 1);
+        // Scrolled off the bottom of the 64x32 display.
+        assert!(cpu.disp_buff[33][5] == 0);
+    }
+
+    #[test]
+    fn test_scr_and_scl() {
+        let mut cpu = Cpu::new(Quirks::superchip());
+        Cpu::load_data(&mut cpu, vec![0x00, 0xFB, 0x00, 0xFC, 0x00, 0xFC]);
+        cpu.disp_buff[2][1] = 1;
+        cpu.emulate_cycle().unwrap();
+        assert!(cpu.disp_buff[2][1] == 0 && cpu.disp_buff[2][5] == 1);
+        cpu.emulate_cycle().unwrap();
+        assert!(cpu.disp_buff[2][1] == 1 && cpu.disp_buff[2][5] == 0);
+        cpu.emulate_cycle().unwrap();
+        for x in 0..DISPLAY_WIDTH {
+            assert!(cpu.disp_buff[2][x] == 0);
+        }
+    }
+
+    #[test]
+    fn test_drw_16x16_sprite() {
+        let mut cpu = Cpu::new(Quirks::superchip());
+        // HIGH, LD I 0x300, DRW V0, V1, 0
+        Cpu::load_data(&mut cpu, vec![0x00, 0xFF, 0xA3, 0x00, 0xD0, 0x10]);
+        for row in 0..16 {
+            cpu.memory[0x300 + row * 2] = 0x80;
+            cpu.memory[0x300 + row * 2 + 1] = 0x01;
+        }
+        cpu.v[0] = 100;
+        cpu.v[1] = 40;
+        for _ in 0..3 {
+            cpu.emulate_cycle().unwrap();
+        }
+        for row in 40..56 {
+            assert!(cpu.disp_buff[row][100] == 1);
+            assert!(cpu.disp_buff[row][101] == 0);
+            assert!(cpu.disp_buff[row][115] == 1);
+        }
+        assert_eq!(cpu.v[0xF], 0);
+    }
+
+    #[test]
+    fn test_ld_hf_vx() {
+        let mut cpu = Cpu::new(Quirks::superchip());
+        Cpu::load_data(&mut cpu, vec![0xF3, 0x30]);
+        cpu.v[3] = 2;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.i, 100);
+        assert_eq!(&cpu.memory[100..110], &BIG_FONT_SPRITES[20..30]);
+    }
+
+    #[test]
+    fn test_ld_r_vx_and_ld_vx_r() {
+        let mut cpu = Cpu::new(Quirks::superchip());
+        Cpu::load_data(&mut cpu, vec![0xF2, 0x75, 0x60, 0x00, 0x61, 0x00, 0xF1, 0x85]);
+        cpu.v[0] = 7;
+        cpu.v[1] = 8;
+        cpu.v[2] = 9;
+        for _ in 0..4 {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!(&cpu.rpl[0..4], &[7, 8, 9, 0]);
+        assert_eq!((cpu.v[0], cpu.v[1]), (7, 8));
+    }
+
+    #[test]
+    fn test_memory_is_64k() {
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![0xF0, 0x00, 0xFF, 0x00, 0x60, 0x2A, 0xF0, 0x55]);
+        for _ in 0..3 {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!(cpu.memory.len(), MEMORY_SIZE);
+        assert_eq!(cpu.memory[0xFF00], 0x2A);
+    }
+
+    #[test]
+    fn test_ld_i_long() {
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![0xF0, 0x00, 0x12, 0x34]);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.i, 0x1234);
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn test_skip_over_ld_i_long() {
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![0x30, 0x00, 0xF0, 0x00, 0x12, 0x34]);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.pc, 0x206);
+    }
+
+    #[test]
+    fn test_save_vx_vy() {
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![0x52, 0x42, 0x54, 0x22]);
+        cpu.i = 0x500;
+        cpu.v[2] = 1;
+        cpu.v[3] = 2;
+        cpu.v[4] = 3;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(&cpu.memory[0x500..0x503], &[1, 2, 3]);
+        cpu.i = 0x600;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(&cpu.memory[0x600..0x603], &[3, 2, 1]);
+        assert_eq!(cpu.i, 0x600);
+    }
+
+    #[test]
+    fn test_load_vx_vy() {
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![0x53, 0x13]);
+        cpu.i = 0x500;
+        cpu.memory[0x500] = 7;
+        cpu.memory[0x501] = 8;
+        cpu.memory[0x502] = 9;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!((cpu.v[3], cpu.v[2], cpu.v[1]), (7, 8, 9));
+        assert_eq!(cpu.i, 0x500);
+    }
+
+    #[test]
+    fn test_drw_to_both_planes() {
+        let mut cpu = Cpu::new(Quirks::default());
+        // PLANE 3, LD I 0x300, DRW V0, V0, 1
+        Cpu::load_data(&mut cpu, vec![0xF3, 0x01, 0xA3, 0x00, 0xD0, 0x01]);
+        cpu.memory[0x300] = 0b11000000;
+        cpu.memory[0x301] = 0b10100000;
+        for _ in 0..3 {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!(&cpu.disp_buff[0][0..4], &[3, 1, 2, 0]);
+        assert_eq!(cpu.v[0xF], 0);
+    }
+
+    #[test]
+    fn test_cls_clears_only_selected_planes() {
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![0xF2, 0x01, 0x00, 0xE0]);
+        cpu.disp_buff[5][5] = 3;
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.disp_buff[5][5], 1);
+    }
+
+    #[test]
+    fn test_scu_n() {
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![0x00, 0xD2]);
+        cpu.disp_buff[2][5] = 1;
+        cpu.disp_buff[0][5] = 1;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.disp_buff[0][5], 1);
+        assert_eq!(cpu.disp_buff[2][5], 0);
+    }
+
+    #[test]
+    fn test_audio_and_pitch() {
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![0xF0, 0x02, 0x61, 0x70, 0xF1, 0x3A]);
+        cpu.i = 0x500;
+        for n in 0..16 {
+            cpu.memory[0x500 + n] = n as u8;
+        }
+        assert!(cpu.audio_pattern().is_none());
+        assert_eq!(cpu.playback_rate(), 4000.0);
+        for _ in 0..3 {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!(cpu.audio_pattern().unwrap()[15], 15);
+        assert_eq!(cpu.pitch(), 0x70);
+        assert_eq!(cpu.playback_rate(), 8000.0);
+    }
+
+    #[test]
+    fn test_save_and_load_state() {
+        let mut cpu = Cpu::new(Quirks::superchip());
+        // HIGH, LD V0 0x10, CALL 300, ... 0x300: DRW V0, V0, 5
+        Cpu::load_data(&mut cpu, vec![0x00, 0xFF, 0x60, 0x10, 0x23, 0x00]);
+        cpu.memory[0x300] = 0xD0;
+        cpu.memory[0x301] = 0x05;
+        for _ in 0..3 {
+            cpu.emulate_cycle().unwrap();
+        }
+        cpu.delay_timer = 30;
+        cpu.key_buff[7] = true;
+        let saved = cpu.save_state();
+
+        cpu.emulate_cycle().unwrap();
+        cpu.delay_timer = 0;
+        cpu.key_buff[7] = false;
+        cpu.quirks = Quirks::default();
+        cpu.load_state(&saved).unwrap();
+
+        assert_eq!(cpu.pc, 0x300);
+        assert_eq!(cpu.sp, 1);
+        assert_eq!(cpu.stack[0], 0x204);
+        assert_eq!(cpu.v[0], 0x10);
+        assert_eq!(cpu.delay_timer, 30);
+        assert!(cpu.key_buff[7]);
+        assert!(cpu.hires);
+        assert_eq!(cpu.disp_buff[0x10][0x10], 0);
+        assert_eq!(cpu.quirks, Quirks::superchip());
+        assert_eq!(cpu.save_state(), saved);
+        assert_eq!(saved.len(), cpu.state_len());
+    }
+
+    #[test]
+    fn test_load_data_at() {
+        let mut cpu = Cpu::new(Quirks::default());
+        // LD V0 7 at 0x600.
+        Cpu::load_data_at(&mut cpu, vec![0x60, 0x07], 0x600).unwrap();
+        assert_eq!(cpu.pc(), 0x600);
+        assert_eq!(cpu.memory()[0x200], 0);
+        assert_eq!(cpu.memory()[0], FONT_SPRITES[0]);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.v(0), 7);
+    }
+
+    #[test]
+    fn test_load_data_at_checks_bounds() {
+        let mut cpu = Cpu::new(Quirks::default());
+        assert_eq!(Cpu::load_data_at(&mut cpu, vec![0x12, 0x00], 0x10), Err(LoadError::StartInFonts { start: 0x10 }));
+        assert_eq!(Cpu::load_data_at(&mut cpu, vec![0; 0x201], 0xFE00), Err(LoadError::TooBig { start: 0xFE00, len: 0x201 }));
+        assert_eq!(cpu.pc(), 0x200);
+        assert_eq!(Cpu::load_data_at(&mut cpu, vec![0; 0x200], 0xFE00), Ok(()));
+    }
+
+    #[test]
+    fn test_load_state_restores_rng() {
+        let mut cpu = Cpu::with_seed(Quirks::default(), 7);
+        Cpu::load_data(&mut cpu, vec![0xC0, 0xFF]);
+        let saved = cpu.save_state();
+        cpu.emulate_cycle().unwrap();
+        let first = cpu.v[0];
+        cpu.load_state(&saved).unwrap();
+        cpu.v[0] = 0;
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.v[0], first);
+
+        cpu.set_rng(Box::new(VipStyleRandom::new(0)));
+        assert_eq!(cpu.load_state(&saved), Err(StateError::RngMismatch { expected: 1, found: 0 }));
+    }
+
+    #[test]
+    fn test_load_state_for_another_rom() {
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![0x00, 0xE0]);
+        let saved = cpu.save_state();
+        Cpu::load_data(&mut cpu, vec![0x00, 0xEE]);
+        match cpu.load_state(&saved) {
+            Err(StateError::RomMismatch { .. }) => { }
+            other => panic!("expected a ROM mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_load_state_rejects_bad_data() {
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![0x00, 0xE0]);
+        let mut saved = cpu.save_state();
+        assert_eq!(cpu.load_state(&saved[..saved.len() - 1]), Err(StateError::WrongLength));
+        assert_eq!(cpu.load_state(b"junk"), Err(StateError::BadMagic));
+        saved[5] = 99;
+        assert_eq!(cpu.load_state(&saved), Err(StateError::UnsupportedVersion(99)));
+    }
+
+    #[test]
+    fn test_load_state_rejects_bad_registers() {
+        let mut cpu = Cpu::new(Quirks::default());
+        // CALL 0x204, then a RET there.
+        Cpu::load_data(&mut cpu, vec![0x22, 0x04, 0x00, 0x00, 0x00, 0xEE]);
+        let saved = cpu.save_state();
+        let mut bad_sp = saved.clone();
+        bad_sp[STATE_PC_OFFSET + 4] = 17;
+        assert_eq!(cpu.load_state(&bad_sp), Err(StateError::BadRegisters));
+        let mut bad_pc = saved.clone();
+        bad_pc[STATE_PC_OFFSET + 1] = 1;
+        assert_eq!(cpu.load_state(&bad_pc), Err(StateError::BadRegisters));
+        // Nothing was changed, so the program still runs.
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.pc(), 0x202);
+    }
+
+    #[test]
+    fn test_dirty_rect() {
+        let mut cpu = Cpu::new(Quirks::default());
+        // LD V0 60; LD V1 2; LD I 0, the font's 0; DRW V0 V1 5; CLS
+        Cpu::load_data(&mut cpu, vec![0x60, 0x3C, 0x61, 0x02, 0xA0, 0x00, 0xD0, 0x15, 0x00, 0xE0]);
+        assert_eq!(cpu.take_dirty(), Some(DirtyRect::full()));
+        for _ in 0 .. 3 {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert!(!cpu.is_display_dirty());
+        // The 0 is 4 pixels wide, so it just fits before the edge.
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.take_dirty(), Some(DirtyRect { x: 60, y: 2, width: 4, height: 5 }));
+        assert_eq!(cpu.take_dirty(), None);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.take_dirty(), Some(DirtyRect { x: 0, y: 0, width: 64, height: 32 }));
+
+        let saved = cpu.save_state();
+        cpu.load_state(&saved).unwrap();
+        assert_eq!(cpu.take_dirty(), Some(DirtyRect::full()));
+    }
+
+    #[test]
+    fn test_dirty_rect_union() {
+        let a = DirtyRect { x: 2, y: 3, width: 4, height: 1 };
+        let b = DirtyRect { x: 0, y: 10, width: 1, height: 2 };
+        assert_eq!(a.union(b), DirtyRect { x: 0, y: 3, width: 6, height: 9 });
+    }
 }
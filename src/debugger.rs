@@ -0,0 +1,621 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use cpu::Cpu;
+use disasm;
+use error::ExecutionError;
+
+// Step over and step out give up after this many instructions, in case the
+// subroutine never returns.
+const MAX_STEP_CYCLES: usize = 1_000_000;
+
+// A register that can be inspected, modified or used in a condition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    V(usize),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+}
+
+impl Register {
+    pub fn parse(name: &str) -> Option<Register> {
+        let name = name.to_uppercase();
+        match name.as_str() {
+            "I" => Some(Register::I),
+            "PC" => Some(Register::Pc),
+            "SP" => Some(Register::Sp),
+            "DT" => Some(Register::Dt),
+            "ST" => Some(Register::St),
+            _ if name.len() == 2 && name.starts_with('V') =>
+                usize::from_str_radix(&name[1..], 16).ok().map(Register::V),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, cpu: &Cpu) -> u16 {
+        match *self {
+            Register::V(x) => cpu.v(x) as u16,
+            Register::I => cpu.i(),
+            Register::Pc => cpu.pc() as u16,
+            Register::Sp => cpu.sp() as u16,
+            Register::Dt => cpu.delay_timer() as u16,
+            Register::St => cpu.sound_timer() as u16,
+        }
+    }
+
+    // Sets the register, returning false for the stack pointer, which can only
+    // be changed by calling and returning.
+    pub fn set(&self, cpu: &mut Cpu, value: u16) -> bool {
+        match *self {
+            Register::V(x) => cpu.set_v(x, value as u8),
+            Register::I => cpu.set_i(value),
+            Register::Pc => cpu.set_pc(value as usize),
+            Register::Dt => cpu.set_delay_timer(value as u8),
+            Register::St => cpu.set_sound_timer(value as u8),
+            Register::Sp => return false,
+        }
+        true
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Register::V(x) => write!(f, "V{:X}", x),
+            Register::I => write!(f, "I"),
+            Register::Pc => write!(f, "PC"),
+            Register::Sp => write!(f, "SP"),
+            Register::Dt => write!(f, "DT"),
+            Register::St => write!(f, "ST"),
+        }
+    }
+}
+
+// A breakpoint on the value of a register, such as `V3 == 0x10`. It triggers
+// when the comparison becomes true, rather than on every instruction while it
+// stays true.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: &'static str,
+    pub value: u16,
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Option<Condition> {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        if words.len() != 3 {
+            return None;
+        }
+        let comparison = match words[1] {
+            "==" => "==", "!=" => "!=", "<" => "<", "<=" => "<=", ">" => ">", ">=" => ">=",
+            _ => return None,
+        };
+        Some(Condition {
+            register: Register::parse(words[0])?,
+            comparison: comparison,
+            value: parse_number(words[2])?,
+        })
+    }
+
+    pub fn holds(&self, cpu: &Cpu) -> bool {
+        let value = self.register.get(cpu);
+        match self.comparison {
+            "==" => value == self.value,
+            "!=" => value != self.value,
+            "<" => value < self.value,
+            "<=" => value <= self.value,
+            ">" => value > self.value,
+            _ => value >= self.value,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {:#X}", self.register, self.comparison, self.value)
+    }
+}
+
+// Which kinds of access a watchpoint stops on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+// Why the debugger stopped before running an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Breakpoint(usize),
+    // The instruction about to run reads or writes a watched address.
+    Watchpoint { address: usize, write: bool },
+    Condition(Condition),
+    // Step over or step out ran for MAX_STEP_CYCLES without finishing.
+    StepLimit,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StopReason::Breakpoint(address) => write!(f, "breakpoint at {:03X}", address),
+            StopReason::Watchpoint { address, write } =>
+                write!(f, "watchpoint: {} of {:03X}", if write { "write" } else { "read" }, address),
+            StopReason::Condition(condition) => write!(f, "condition {} is true", condition),
+            StopReason::StepLimit => write!(f, "gave up after {} instructions", MAX_STEP_CYCLES),
+        }
+    }
+}
+
+// What the caller should do once the console has finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleExit {
+    Continue,
+    Quit,
+}
+
+// Breakpoints, watchpoints and conditions checked around Cpu::emulate_cycle.
+// A frontend calls check() before each cycle and opens the console when it
+// returns a reason to stop.
+pub struct Debugger {
+    breakpoints: BTreeSet<usize>,
+    watchpoints: BTreeMap<usize, Watch>,
+    // Each condition with whether it held last time it was checked.
+    conditions: Vec<(Condition, bool)>,
+    // The timers are ticked after this many instructions run by the debugger,
+    // so stepping over code that waits on the delay timer still finishes.
+    pub cycles_per_frame: usize,
+    cycles: usize,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            conditions: Vec::new(),
+            cycles_per_frame: 10,
+            cycles: 0,
+        }
+    }
+
+    fn cycle(&mut self, cpu: &mut Cpu) -> Result<(), ExecutionError> {
+        cpu.emulate_cycle()?;
+        self.cycles += 1;
+        if self.cycles >= self.cycles_per_frame {
+            self.cycles = 0;
+            cpu.tick_timers();
+        }
+        Ok(())
+    }
+
+    pub fn add_breakpoint(&mut self, address: usize) { self.breakpoints.insert(address); }
+    pub fn remove_breakpoint(&mut self, address: usize) -> bool { self.breakpoints.remove(&address) }
+    pub fn add_watchpoint(&mut self, address: usize, watch: Watch) { self.watchpoints.insert(address, watch); }
+    pub fn remove_watchpoint(&mut self, address: usize) -> bool { self.watchpoints.remove(&address).is_some() }
+
+    pub fn add_condition(&mut self, condition: Condition, cpu: &Cpu) {
+        self.conditions.push((condition, condition.holds(cpu)));
+    }
+
+    pub fn remove_condition(&mut self, index: usize) -> bool {
+        if index < self.conditions.len() {
+            self.conditions.remove(index);
+            true
+        } else {
+            false
+        }
+    }
+
+    // Checks whether the instruction at the program counter should be stopped
+    // at before it runs.
+    pub fn check(&mut self, cpu: &Cpu) -> Option<StopReason> {
+        let mut stop = None;
+        for &mut (condition, ref mut held) in self.conditions.iter_mut() {
+            let holds = condition.holds(cpu);
+            if holds && !*held && stop.is_none() {
+                stop = Some(StopReason::Condition(condition));
+            }
+            *held = holds;
+        }
+        if stop.is_some() {
+            return stop;
+        }
+        if self.breakpoints.contains(&cpu.pc()) {
+            return Some(StopReason::Breakpoint(cpu.pc()));
+        }
+        if !self.watchpoints.is_empty() {
+            for (start, len, write) in memory_accesses(cpu) {
+                for address in start .. start + len {
+                    match self.watchpoints.get(&address) {
+                        Some(&Watch::ReadWrite) => return Some(StopReason::Watchpoint { address: address, write: write }),
+                        Some(&Watch::Write) if write => return Some(StopReason::Watchpoint { address: address, write: write }),
+                        Some(&Watch::Read) if !write => return Some(StopReason::Watchpoint { address: address, write: write }),
+                        _ => { }
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    // Runs instructions until check() says to stop. The first instruction is
+    // always run, so continuing from a breakpoint doesn't stop straight away.
+    pub fn run(&mut self, cpu: &mut Cpu, max_cycles: usize) -> Result<Option<StopReason>, ExecutionError> {
+        for cycle in 0 .. max_cycles {
+            if cycle > 0 {
+                if let Some(reason) = self.check(cpu) {
+                    return Ok(Some(reason));
+                }
+            }
+            self.cycle(cpu)?;
+        }
+        Ok(None)
+    }
+
+    // Runs one instruction, unless it's a CALL, in which case the whole
+    // subroutine is run until it returns.
+    pub fn step_over(&mut self, cpu: &mut Cpu) -> Result<Option<StopReason>, ExecutionError> {
+        if opcode_at(cpu, cpu.pc()) & 0xF000 != 0x2000 {
+            self.cycle(cpu)?;
+            return Ok(None);
+        }
+        let return_address = cpu.pc() + 2;
+        let depth = cpu.sp();
+        self.run_until(cpu, |cpu| cpu.pc() == return_address && cpu.sp() == depth)
+    }
+
+    // Runs until the current subroutine returns.
+    pub fn step_out(&mut self, cpu: &mut Cpu) -> Result<Option<StopReason>, ExecutionError> {
+        let depth = cpu.sp();
+        if depth == 0 {
+            self.cycle(cpu)?;
+            return Ok(None);
+        }
+        self.run_until(cpu, |cpu| cpu.sp() < depth)
+    }
+
+    fn run_until<F: Fn(&Cpu) -> bool>(&mut self, cpu: &mut Cpu, done: F) -> Result<Option<StopReason>, ExecutionError> {
+        self.cycle(cpu)?;
+        for _ in 1 .. MAX_STEP_CYCLES {
+            if done(cpu) {
+                return Ok(None);
+            }
+            if let Some(reason) = self.check(cpu) {
+                return Ok(Some(reason));
+            }
+            self.cycle(cpu)?;
+        }
+        Ok(Some(StopReason::StepLimit))
+    }
+
+    // Reads commands from `input` until told to continue or quit, or the input
+    // ends. Type `help` for the list of commands.
+    pub fn console<R: BufRead, W: Write>(&mut self, cpu: &mut Cpu, input: R, mut output: W) -> io::Result<ConsoleExit> {
+        print_position(cpu, &mut output)?;
+        write!(output, "(chip8) ")?;
+        output.flush()?;
+        for line in input.lines() {
+            let line = line?;
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if let Some(exit) = self.command(cpu, &words, &mut output)? {
+                return Ok(exit);
+            }
+            write!(output, "(chip8) ")?;
+            output.flush()?;
+        }
+        Ok(ConsoleExit::Quit)
+    }
+
+    fn command<W: Write>(&mut self, cpu: &mut Cpu, words: &[&str], out: &mut W) -> io::Result<Option<ConsoleExit>> {
+        let command = match words.first() {
+            Some(command) => *command,
+            None => return Ok(None),
+        };
+        let number = |index: usize| words.get(index).and_then(|word| parse_number(word));
+
+        match command {
+            "c" | "continue" => return Ok(Some(ConsoleExit::Continue)),
+            "q" | "quit" => return Ok(Some(ConsoleExit::Quit)),
+            "s" | "step" => {
+                for _ in 0 .. number(1).unwrap_or(1) {
+                    if let Err(e) = self.cycle(cpu) {
+                        writeln!(out, "{}", e)?;
+                        break;
+                    }
+                }
+                print_position(cpu, out)?;
+            }
+            "n" | "next" => {
+                let result = self.step_over(cpu);
+                print_step_result(result, out)?;
+                print_position(cpu, out)?;
+            }
+            "f" | "finish" => {
+                let result = self.step_out(cpu);
+                print_step_result(result, out)?;
+                print_position(cpu, out)?;
+            }
+            "b" | "break" => match number(1) {
+                Some(address) => self.add_breakpoint(address as usize),
+                None => writeln!(out, "usage: break ADDRESS")?,
+            },
+            "w" | "watch" => {
+                let watch = match words.get(2).cloned() {
+                    Some("r") => Watch::Read,
+                    Some("w") => Watch::Write,
+                    None | Some("rw") => Watch::ReadWrite,
+                    Some(_) => { writeln!(out, "usage: watch ADDRESS [r|w|rw]")?; return Ok(None); }
+                };
+                match number(1) {
+                    Some(address) => self.add_watchpoint(address as usize, watch),
+                    None => writeln!(out, "usage: watch ADDRESS [r|w|rw]")?,
+                }
+            }
+            "cond" => match Condition::parse(&words[1..].join(" ")) {
+                Some(condition) => self.add_condition(condition, cpu),
+                None => writeln!(out, "usage: cond REGISTER OP VALUE, e.g. cond V3 == 0x10")?,
+            },
+            "d" | "delete" => {
+                let removed = match (words.get(1).cloned(), number(2)) {
+                    (Some("break"), Some(address)) => self.remove_breakpoint(address as usize),
+                    (Some("watch"), Some(address)) => self.remove_watchpoint(address as usize),
+                    (Some("cond"), Some(index)) => self.remove_condition(index as usize),
+                    _ => { writeln!(out, "usage: delete break|watch|cond ADDRESS|INDEX")?; return Ok(None); }
+                };
+                if !removed {
+                    writeln!(out, "nothing to delete")?;
+                }
+            }
+            "l" | "list" => {
+                for address in self.breakpoints.iter() {
+                    writeln!(out, "break {:03X}", address)?;
+                }
+                for (address, watch) in self.watchpoints.iter() {
+                    writeln!(out, "watch {:03X} {:?}", address, watch)?;
+                }
+                for (index, &(condition, _)) in self.conditions.iter().enumerate() {
+                    writeln!(out, "cond {}: {}", index, condition)?;
+                }
+            }
+            "r" | "regs" => print_registers(cpu, out)?,
+            "stack" => {
+                for (depth, address) in cpu.stack()[.. cpu.sp()].iter().enumerate().rev() {
+                    writeln!(out, "#{} {:03X}", depth, address)?;
+                }
+            }
+            "x" | "mem" => match number(1) {
+                Some(address) => {
+                    let len = number(2).unwrap_or(16) as usize;
+                    print_memory(cpu, address as usize, len, out)?;
+                }
+                None => writeln!(out, "usage: mem ADDRESS [LENGTH]")?,
+            },
+            "set" => match (words.get(1).and_then(|word| Register::parse(word)), number(2)) {
+                (Some(register), Some(value)) => {
+                    if !register.set(cpu, value) {
+                        writeln!(out, "{} can't be set", register)?;
+                    }
+                }
+                _ => writeln!(out, "usage: set REGISTER VALUE")?,
+            },
+            "poke" => match number(1) {
+                Some(address) if words.len() > 2 => {
+                    for (offset, word) in words[2..].iter().enumerate() {
+                        let target = address as usize + offset;
+                        match parse_number(word) {
+                            Some(value) if target < cpu.memory().len() => cpu.memory_mut()[target] = value as u8,
+                            _ => { writeln!(out, "can't poke {} at {:X}", word, target)?; break; }
+                        }
+                    }
+                }
+                _ => writeln!(out, "usage: poke ADDRESS BYTE...")?,
+            },
+            "h" | "help" => writeln!(out, "{}", HELP)?,
+            _ => writeln!(out, "unknown command {}, try help", command)?,
+        }
+        Ok(None)
+    }
+}
+
+const HELP: &'static str = "\
+step [N]              run N instructions (s)
+next                  step over CALLs (n)
+finish                run until the current subroutine returns (f)
+continue              resume emulation (c)
+quit                  stop the emulator (q)
+break ADDRESS         stop at an address (b)
+watch ADDRESS [r|w]   stop on reads and/or writes of an address (w)
+cond REG OP VALUE     stop when a comparison becomes true, e.g. cond V3 == 0x10
+delete break|watch|cond ADDRESS|INDEX
+list                  show breakpoints, watchpoints and conditions (l)
+regs                  show registers and timers (r)
+stack                 show the call stack
+mem ADDRESS [LENGTH]  show memory (x)
+set REG VALUE         change V0-VF, I, PC, DT or ST
+poke ADDRESS BYTE...  change memory";
+
+// Parses numbers typed at the console: hex with 0x, # or $, binary with 0b,
+// and otherwise decimal.
+pub fn parse_number(text: &str) -> Option<u16> {
+    let lower = text.to_lowercase();
+    if lower.starts_with("0x") {
+        u16::from_str_radix(&lower[2..], 16).ok()
+    } else if lower.starts_with('#') || lower.starts_with('$') {
+        u16::from_str_radix(&lower[1..], 16).ok()
+    } else if lower.starts_with("0b") {
+        u16::from_str_radix(&lower[2..], 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+fn opcode_at(cpu: &Cpu, address: usize) -> u16 {
+    let memory = cpu.memory();
+    if address + 1 < memory.len() {
+        (memory[address] as u16) << 8 | memory[address + 1] as u16
+    } else {
+        0
+    }
+}
+
+// Works out which memory the instruction at the program counter will touch,
+// as (start, length, is a write).
+fn memory_accesses(cpu: &Cpu) -> Vec<(usize, usize, bool)> {
+    let opcode = opcode_at(cpu, cpu.pc());
+    let i = cpu.i() as usize;
+    let x = ((opcode & 0x0F00) >> 8) as usize;
+    let y = ((opcode & 0x00F0) >> 4) as usize;
+    let registers = if x > y { x - y } else { y - x } + 1;
+    match (opcode & 0xF000, opcode & 0x000F, opcode & 0x00FF) {
+        (0x5000, 2, _) => vec![(i, registers, true)],
+        (0x5000, 3, _) => vec![(i, registers, false)],
+        (0xD000, n, _) => {
+            let sprite_len = if n == 0 { 32 } else { n as usize };
+            let planes = cpu.selected_planes().count_ones() as usize;
+            vec![(i, sprite_len * planes, false)]
+        }
+        (0xF000, _, 0x02) if opcode == 0xF002 => vec![(i, 16, false)],
+        (0xF000, _, 0x33) => vec![(i, 3, true)],
+        (0xF000, _, 0x55) => vec![(i, x + 1, true)],
+        (0xF000, _, 0x65) => vec![(i, x + 1, false)],
+        _ => vec![],
+    }
+}
+
+fn print_position<W: Write>(cpu: &Cpu, out: &mut W) -> io::Result<()> {
+    let opcode = opcode_at(cpu, cpu.pc());
+    let text = disasm::decode(opcode, opcode_at(cpu, cpu.pc() + 2)).unwrap_or_else(|| "???".to_string());
+    writeln!(out, "{:03X}: {:04X}  {}", cpu.pc(), opcode, text)
+}
+
+fn print_step_result<W: Write>(result: Result<Option<StopReason>, ExecutionError>, out: &mut W) -> io::Result<()> {
+    match result {
+        Ok(Some(reason)) => writeln!(out, "stopped: {}", reason),
+        Ok(None) => Ok(()),
+        Err(e) => writeln!(out, "{}", e),
+    }
+}
+
+fn print_registers<W: Write>(cpu: &Cpu, out: &mut W) -> io::Result<()> {
+    for x in 0 .. 16 {
+        write!(out, "V{:X}={:02X}{}", x, cpu.v(x), if x % 8 == 7 { "\n" } else { " " })?;
+    }
+    writeln!(out, "I={:04X} PC={:03X} SP={:X} DT={:02X} ST={:02X}",
+             cpu.i(), cpu.pc(), cpu.sp(), cpu.delay_timer(), cpu.sound_timer())
+}
+
+fn print_memory<W: Write>(cpu: &Cpu, address: usize, len: usize, out: &mut W) -> io::Result<()> {
+    let memory = cpu.memory();
+    let end = (address + len).min(memory.len());
+    let mut row = address;
+    while row < end {
+        write!(out, "{:04X}:", row)?;
+        for byte in &memory[row .. (row + 16).min(end)] {
+            write!(out, " {:02X}", byte)?;
+        }
+        writeln!(out)?;
+        row += 16;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::cpu_with;
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("0x10"), Some(16));
+        assert_eq!(parse_number("#1F"), Some(31));
+        assert_eq!(parse_number("0b101"), Some(5));
+        assert_eq!(parse_number("42"), Some(42));
+        assert_eq!(parse_number("V3"), None);
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        // ADD V0 1, ADD V0 1, JP 200
+        let mut cpu = cpu_with(vec![0x70, 0x01, 0x70, 0x01, 0x12, 0x00]);
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(0x204);
+        assert_eq!(debugger.run(&mut cpu, 100), Ok(Some(StopReason::Breakpoint(0x204))));
+        assert_eq!(cpu.v(0), 2);
+        // Continuing runs past the breakpoint and round the loop back to it.
+        assert_eq!(debugger.run(&mut cpu, 100), Ok(Some(StopReason::Breakpoint(0x204))));
+        assert_eq!(cpu.v(0), 4);
+    }
+
+    #[test]
+    fn test_condition_triggers_once() {
+        let mut cpu = cpu_with(vec![0x70, 0x01, 0x12, 0x00]);
+        let mut debugger = Debugger::new();
+        debugger.add_condition(Condition::parse("V0 >= 3").unwrap(), &cpu);
+        let stop = debugger.run(&mut cpu, 100).unwrap();
+        assert_eq!(stop, Some(StopReason::Condition(Condition::parse("V0 >= 0x3").unwrap())));
+        assert_eq!(cpu.v(0), 3);
+        // Still true, so it doesn't stop again.
+        assert_eq!(debugger.run(&mut cpu, 100), Ok(None));
+    }
+
+    #[test]
+    fn test_write_watchpoint() {
+        // LD I 0x300, ADD V0 1, LD [I] V0
+        let mut cpu = cpu_with(vec![0xA3, 0x00, 0x70, 0x01, 0xF0, 0x55]);
+        let mut debugger = Debugger::new();
+        debugger.add_watchpoint(0x300, Watch::Write);
+        assert_eq!(debugger.run(&mut cpu, 100),
+                   Ok(Some(StopReason::Watchpoint { address: 0x300, write: true })));
+        assert_eq!(cpu.pc(), 0x204);
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        // CALL 300, LD V1 1 ... 0x300: LD V0 5, RET
+        let mut cpu = cpu_with(vec![0x23, 0x00, 0x61, 0x01]);
+        cpu.memory_mut()[0x300 .. 0x304].copy_from_slice(&[0x60, 0x05, 0x00, 0xEE]);
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.step_over(&mut cpu), Ok(None));
+        assert_eq!((cpu.pc(), cpu.v(0)), (0x202, 5));
+
+        let mut cpu = cpu_with(vec![0x23, 0x00, 0x61, 0x01]);
+        cpu.memory_mut()[0x300 .. 0x304].copy_from_slice(&[0x60, 0x05, 0x00, 0xEE]);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(debugger.step_out(&mut cpu), Ok(None));
+        assert_eq!((cpu.pc(), cpu.sp()), (0x202, 0));
+    }
+
+    #[test]
+    fn test_step_over_ticks_timers() {
+        // CALL 0x300, EXIT ... 0x300: LD V0 2, LD DT V0, LD V1 DT, SE V1 0, JP 0x304, RET
+        let mut cpu = cpu_with(vec![0x23, 0x00, 0x00, 0xFD]);
+        cpu.memory_mut()[0x300 .. 0x30C].copy_from_slice(
+            &[0x60, 0x02, 0xF0, 0x15, 0xF1, 0x07, 0x31, 0x00, 0x13, 0x04, 0x00, 0xEE]);
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.step_over(&mut cpu), Ok(None));
+        assert_eq!(cpu.pc(), 0x202);
+    }
+
+    #[test]
+    fn test_console_commands() {
+        let mut cpu = cpu_with(vec![0x70, 0x01, 0x70, 0x01]);
+        let mut debugger = Debugger::new();
+        let input = "set V2 0x42\npoke 0x300 1 2 3\nstep 2\nbreak 0x208\nregs\nmem 0x300 3\nc\n";
+        let mut output = Vec::new();
+        let exit = debugger.console(&mut cpu, input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert_eq!(exit, ConsoleExit::Continue);
+        assert_eq!(cpu.v(2), 0x42);
+        assert_eq!(cpu.v(0), 2);
+        assert_eq!(&cpu.memory()[0x300 .. 0x303], &[1, 2, 3]);
+        assert!(debugger.breakpoints.contains(&0x208));
+        assert!(output.contains("V2=42"));
+        assert!(output.contains("0300: 01 02 03"));
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::{BTreeMap, BTreeSet};
+use state;
+
+// Programs are loaded here unless `--start` says otherwise.
+pub const ORIGIN: usize = 0x200;
+
+// Data bytes are listed this many to a line.
+const DATA_PER_LINE: usize = 8;
+
+// How an instruction passes control on, for tracing which bytes are code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    Next,
+    // Carries on at the next instruction or the one after it.
+    Skip,
+    Jump(usize),
+    Call(usize),
+    // RET, EXIT and JP V0, where the next instruction can't be known.
+    Stop,
+}
+
+// Decodes one instruction into Cowgod style assembly, such as `LD V3, 0x10`.
+// `next` is the word after the opcode, which is only used by XO-CHIP's 4 byte
+// F000 nnnn. Returns None for words that aren't instructions.
+pub fn decode(opcode: u16, next: u16) -> Option<String> {
+    decode_with_labels(opcode, next, &BTreeMap::new())
+}
+
+// The length in bytes of the instruction starting with `opcode`.
+pub fn instruction_len(opcode: u16) -> usize {
+    if opcode == 0xF000 { 4 } else { 2 }
+}
+
+fn decode_with_labels(opcode: u16, next: u16, labels: &BTreeMap<usize, String>) -> Option<String> {
+    let x = (opcode & 0x0F00) >> 8;
+    let y = (opcode & 0x00F0) >> 4;
+    let n = opcode & 0x000F;
+    let kk = opcode & 0x00FF;
+    let nnn = (opcode & 0x0FFF) as usize;
+    let address = |address: usize| match labels.get(&address) {
+        Some(label) => label.clone(),
+        None => format!("0x{:03X}", address),
+    };
+
+    let text = match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => "CLS".to_string(),
+            0x00EE => "RET".to_string(),
+            0x00FB => "SCR".to_string(),
+            0x00FC => "SCL".to_string(),
+            0x00FD => "EXIT".to_string(),
+            0x00FE => "LOW".to_string(),
+            0x00FF => "HIGH".to_string(),
+            _ if opcode & 0xFFF0 == 0x00C0 => format!("SCD {}", n),
+            _ if opcode & 0xFFF0 == 0x00D0 => format!("SCU {}", n),
+            _ => return None,
+        },
+        0x1000 => format!("JP {}", address(nnn)),
+        0x2000 => format!("CALL {}", address(nnn)),
+        0x3000 => format!("SE V{:X}, 0x{:02X}", x, kk),
+        0x4000 => format!("SNE V{:X}, 0x{:02X}", x, kk),
+        0x5000 => match n {
+            0 => format!("SE V{:X}, V{:X}", x, y),
+            2 => format!("SAVE V{:X}, V{:X}", x, y),
+            3 => format!("LOAD V{:X}, V{:X}", x, y),
+            _ => return None,
+        },
+        0x6000 => format!("LD V{:X}, 0x{:02X}", x, kk),
+        0x7000 => format!("ADD V{:X}, 0x{:02X}", x, kk),
+        0x8000 => {
+            let mnemonic = match n {
+                0x0 => "LD", 0x1 => "OR", 0x2 => "AND", 0x3 => "XOR", 0x4 => "ADD",
+                0x5 => "SUB", 0x6 => "SHR", 0x7 => "SUBN", 0xE => "SHL",
+                _ => return None,
+            };
+            format!("{} V{:X}, V{:X}", mnemonic, x, y)
+        }
+        0x9000 if n == 0 => format!("SNE V{:X}, V{:X}", x, y),
+        0xA000 => format!("LD I, {}", address(nnn)),
+        0xB000 => format!("JP V0, {}", address(nnn)),
+        0xC000 => format!("RND V{:X}, 0x{:02X}", x, kk),
+        0xD000 => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        0xE000 => match kk {
+            0x9E => format!("SKP V{:X}", x),
+            0xA1 => format!("SKNP V{:X}", x),
+            _ => return None,
+        },
+        0xF000 => match (opcode, kk) {
+            (0xF000, _) => format!("LD I, long {}", match labels.get(&(next as usize)) {
+                Some(label) => label.clone(),
+                None => format!("0x{:04X}", next),
+            }),
+            (0xF002, _) => "AUDIO".to_string(),
+            (_, 0x01) => format!("PLANE {}", x),
+            (_, 0x07) => format!("LD V{:X}, DT", x),
+            (_, 0x0A) => format!("LD V{:X}, K", x),
+            (_, 0x15) => format!("LD DT, V{:X}", x),
+            (_, 0x18) => format!("LD ST, V{:X}", x),
+            (_, 0x1E) => format!("ADD I, V{:X}", x),
+            (_, 0x29) => format!("LD F, V{:X}", x),
+            (_, 0x30) => format!("LD HF, V{:X}", x),
+            (_, 0x33) => format!("LD B, V{:X}", x),
+            (_, 0x3A) => format!("PITCH V{:X}", x),
+            (_, 0x55) => format!("LD [I], V{:X}", x),
+            (_, 0x65) => format!("LD V{:X}, [I]", x),
+            (_, 0x75) => format!("LD R, V{:X}", x),
+            (_, 0x85) => format!("LD V{:X}, R", x),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(text)
+}
+
+fn flow(opcode: u16) -> Flow {
+    let nnn = (opcode & 0x0FFF) as usize;
+    match opcode & 0xF000 {
+        0x0000 if opcode == 0x00EE || opcode == 0x00FD => Flow::Stop,
+        0x1000 => Flow::Jump(nnn),
+        0x2000 => Flow::Call(nnn),
+        // SAVE and LOAD share the 5xxx prefix with SE Vx, Vy.
+        0x5000 if opcode & 0x000F != 0 => Flow::Next,
+        0x3000 | 0x4000 | 0x5000 | 0x9000 | 0xE000 => Flow::Skip,
+        0xB000 => Flow::Stop,
+        _ => Flow::Next,
+    }
+}
+
+fn word_at(rom: &[u8], offset: usize) -> u16 {
+    let high = rom.get(offset).cloned().unwrap_or(0) as u16;
+    let low = rom.get(offset + 1).cloned().unwrap_or(0) as u16;
+    high << 8 | low
+}
+
+// Follows every path through the program from its first instruction, at
+// `origin`, so that sprites and other data mixed in with the code aren't listed
+// as instructions.
+// Returns the addresses of the instructions found, and the targets of jumps and
+// calls, with whether each target is called.
+fn trace(rom: &[u8], origin: usize) -> (BTreeSet<usize>, BTreeMap<usize, bool>) {
+    let end = origin + rom.len();
+    let mut code = BTreeSet::new();
+    let mut targets = BTreeMap::new();
+    let mut pending = vec![origin];
+
+    while let Some(address) = pending.pop() {
+        if address < origin || address + 1 >= end || code.contains(&address) {
+            continue;
+        }
+        let opcode = word_at(rom, address - origin);
+        let len = instruction_len(opcode);
+        if address + len > end || decode(opcode, 0).is_none() {
+            continue;
+        }
+        code.insert(address);
+
+        match flow(opcode) {
+            Flow::Next => pending.push(address + len),
+            Flow::Skip => {
+                let next = address + 2;
+                pending.push(next);
+                if next + 1 < end {
+                    pending.push(next + instruction_len(word_at(rom, next - origin)));
+                }
+            }
+            Flow::Jump(target) => {
+                targets.entry(target).or_insert(false);
+                pending.push(target);
+            }
+            Flow::Call(target) => {
+                targets.insert(target, true);
+                pending.push(target);
+                pending.push(address + len);
+            }
+            Flow::Stop => { }
+        }
+    }
+    (code, targets)
+}
+
+// Disassembles a ROM into a listing that shows each instruction's address and
+// encoding in a comment, taking the ROM to be loaded at `origin`. Jump and call
+// targets are given labels, and anything that isn't reached from the start of
+// the program is listed as `db` data.
+pub fn disassemble(rom: &[u8], origin: usize) -> String {
+    let (code, targets) = trace(rom, origin);
+    let end = origin + rom.len();
+
+    // Split the ROM into lines: one per instruction, and runs of data broken
+    // at instructions and jump targets.
+    let mut lines = Vec::new();
+    let mut address = origin;
+    while address < end {
+        if code.contains(&address) {
+            let len = instruction_len(word_at(rom, address - origin));
+            lines.push((address, len, true));
+            address += len;
+        } else {
+            let start = address;
+            address += 1;
+            while address < end && address - start < DATA_PER_LINE
+                && !code.contains(&address) && !targets.contains_key(&address) {
+                address += 1;
+            }
+            lines.push((start, address - start, false));
+        }
+    }
+
+    // Targets in the middle of another instruction can't be labelled, so they
+    // are left as plain addresses.
+    let mut labels = BTreeMap::new();
+    for &(address, _, _) in &lines {
+        if let Some(&called) = targets.get(&address) {
+            let prefix = if called { "sub_" } else { "L" };
+            labels.insert(address, format!("{}{:03X}", prefix, address));
+        }
+    }
+
+    let mut listing = String::new();
+    for &(address, len, is_code) in &lines {
+        if let Some(label) = labels.get(&address) {
+            listing.push_str(&format!("{}:\n", label));
+        }
+        let bytes = &rom[address - origin .. address - origin + len];
+        let text = if is_code {
+            let opcode = word_at(rom, address - origin);
+            let next = word_at(rom, address - origin + 2);
+            decode_with_labels(opcode, next, &labels).unwrap_or_default()
+        } else {
+            let values: Vec<String> = bytes.iter().map(|byte| format!("0x{:02X}", byte)).collect();
+            format!("db {}", values.join(", "))
+        };
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        listing.push_str(&format!("    {:<32}; {:03X}: {}\n", text, address, hex.join(" ")));
+    }
+    listing
+}
+
+// The extension to CHIP-8 an instruction comes from, if any, and the pattern
+// it's written as.
+fn extension(opcode: u16) -> Option<(&'static str, String)> {
+    let n = opcode & 0x000F;
+    let low = opcode & 0x00FF;
+    let (name, pattern) = match opcode & 0xF000 {
+        0x0000 if opcode & 0xFFF0 == 0x00D0 => ("XO-CHIP", "00Dn".to_string()),
+        0x0000 if opcode & 0xFFF0 == 0x00C0 => ("SUPER-CHIP", "00Cn".to_string()),
+        0x0000 if opcode >= 0x00FB && opcode <= 0x00FF => ("SUPER-CHIP", format!("{:04X}", opcode)),
+        0x5000 if n == 2 || n == 3 => ("XO-CHIP", format!("5xy{}", n)),
+        0xD000 if n == 0 => ("SUPER-CHIP", "Dxy0".to_string()),
+        0xF000 if opcode == 0xF000 => ("XO-CHIP", "F000".to_string()),
+        0xF000 if opcode == 0xF002 => ("XO-CHIP", "F002".to_string()),
+        0xF000 if low == 0x01 => ("XO-CHIP", "Fn01".to_string()),
+        0xF000 if low == 0x3A => ("XO-CHIP", "Fx3A".to_string()),
+        0xF000 if low == 0x30 || low == 0x75 || low == 0x85 => ("SUPER-CHIP", format!("Fx{:02X}", low)),
+        _ => return None,
+    };
+    Some((name, pattern))
+}
+
+// Describes a ROM for `chip8 inspect`: its size and hash, how much of it is
+// code, and which extensions to CHIP-8 the code uses.
+pub fn inspect(rom: &[u8], origin: usize) -> String {
+    let (code, targets) = trace(rom, origin);
+    // Instructions can overlap when a jump lands in the middle of one, so count
+    // each byte once.
+    let mut covered = vec![false; rom.len()];
+    for &address in &code {
+        let start = address - origin;
+        let end = (start + instruction_len(word_at(rom, start))).min(rom.len());
+        for byte in &mut covered[start .. end] {
+            *byte = true;
+        }
+    }
+    let code_bytes = covered.iter().filter(|&&byte| byte).count();
+    let mut extensions: BTreeMap<&str, BTreeSet<String>> = BTreeMap::new();
+    for &address in &code {
+        if let Some((name, pattern)) = extension(word_at(rom, address - origin)) {
+            extensions.entry(name).or_insert_with(BTreeSet::new).insert(pattern);
+        }
+    }
+
+    let mut text = String::new();
+    let end = if rom.is_empty() { origin } else { origin + rom.len() - 1 };
+    text.push_str(&format!("Size:        {} bytes, 0x{:03X} to 0x{:03X}\n", rom.len(), origin, end));
+    text.push_str(&format!("Hash:        {:016X}\n", state::hash_rom(rom)));
+    text.push_str(&format!("Code:        {} bytes, {} instructions reachable from 0x{:03X}\n",
+                           code_bytes, code.len(), origin));
+    text.push_str(&format!("Data:        {} bytes\n", rom.len() - code_bytes));
+    text.push_str(&format!("Subroutines: {}\n", targets.values().filter(|&&called| called).count()));
+    if extensions.is_empty() {
+        text.push_str("Platform:    CHIP-8\n");
+    }
+    // XO-CHIP includes SUPER-CHIP, so it's the one to name if both are used.
+    for (name, patterns) in extensions.iter().rev() {
+        let patterns: Vec<&str> = patterns.iter().map(|pattern| pattern.as_str()).collect();
+        text.push_str(&format!("Platform:    {} (uses {})\n", name, patterns.join(", ")));
+    }
+    if extensions.len() == 1 && extensions.contains_key("SUPER-CHIP") {
+        text.push_str("Quirks:      try --quirks schip\n");
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(0x6310, 0), Some("LD V3, 0x10".to_string()));
+        assert_eq!(decode(0xD015, 0), Some("DRW V0, V1, 5".to_string()));
+        assert_eq!(decode(0x8AB6, 0), Some("SHR VA, VB".to_string()));
+        assert_eq!(decode(0xF265, 0), Some("LD V2, [I]".to_string()));
+        assert_eq!(decode(0x00C4, 0), Some("SCD 4".to_string()));
+        assert_eq!(decode(0xF000, 0x1234), Some("LD I, long 0x1234".to_string()));
+        assert_eq!(decode(0x8008, 0), None);
+        assert_eq!(decode(0x0000, 0), None);
+    }
+
+    #[test]
+    fn test_data_after_jump() {
+        // LD I 0x206, JP 0x204 (to itself) then a sprite.
+        let rom = [0xA2, 0x06, 0x12, 0x02, 0xF0, 0x90];
+        let listing = disassemble(&rom, ORIGIN);
+        assert!(listing.contains("LD I, 0x206"));
+        assert!(listing.contains("L202:\n    JP L202"));
+        assert!(listing.contains("db 0xF0, 0x90"));
+    }
+
+    #[test]
+    fn test_both_sides_of_skip_are_code() {
+        // SE V0 0, JP 0x206, CLS, RET
+        let rom = [0x30, 0x00, 0x12, 0x06, 0x00, 0xE0, 0x00, 0xEE];
+        let listing = disassemble(&rom, ORIGIN);
+        assert!(listing.contains("CLS"));
+        assert!(listing.contains("L206:\n    RET"));
+    }
+
+    #[test]
+    fn test_call_label() {
+        // CALL 0x204, EXIT, RET
+        let rom = [0x22, 0x04, 0x00, 0xFD, 0x00, 0xEE];
+        let listing = disassemble(&rom, ORIGIN);
+        assert!(listing.contains("CALL sub_204"));
+        assert!(listing.contains("sub_204:\n    RET"));
+        assert!(listing.contains("EXIT"));
+    }
+
+    #[test]
+    fn test_long_load_skipped_as_one() {
+        // SE V0 0, LD I long 0x1234, EXIT
+        let rom = [0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x00, 0xFD];
+        let listing = disassemble(&rom, ORIGIN);
+        assert!(listing.contains("LD I, long 0x1234"));
+        assert!(listing.contains("EXIT"));
+        assert!(!listing.contains("db"));
+    }
+
+    #[test]
+    fn test_inspect() {
+        // HIGH, LD I 0x20A, DRW V0 V1 0, CALL 0x20A, EXIT, then a RET.
+        let rom = [0x00, 0xFF, 0xA2, 0x0A, 0xD0, 0x10, 0x22, 0x0A, 0x00, 0xFD, 0x00, 0xEE, 0xAA];
+        let text = inspect(&rom, ORIGIN);
+        assert!(text.contains("Size:        13 bytes, 0x200 to 0x20C\n"));
+        assert!(text.contains("Code:        12 bytes, 6 instructions reachable from 0x200\n"));
+        assert!(text.contains("Data:        1 bytes\n"));
+        assert!(text.contains("Subroutines: 1\n"));
+        assert!(text.contains("Platform:    SUPER-CHIP (uses 00FD, 00FF, Dxy0)\n"));
+        assert!(text.contains("try --quirks schip"));
+        assert!(inspect(&[0x12, 0x00], ORIGIN).contains("Platform:    CHIP-8\n"));
+    }
+
+    #[test]
+    fn test_inspect_overlapping_instructions() {
+        // LD V0 0x12, then a jump into the middle of it, which reads as JP 0x212.
+        let text = inspect(&[0x60, 0x12, 0x12, 0x01], ORIGIN);
+        assert!(text.contains("Code:        4 bytes, 3 instructions reachable from 0x200\n"), "{}", text);
+        assert!(text.contains("Data:        0 bytes\n"));
+    }
+
+    #[test]
+    fn test_origin() {
+        // CALL 0x604, EXIT, RET, loaded at 0x600.
+        let rom = [0x26, 0x04, 0x00, 0xFD, 0x00, 0xEE];
+        let listing = disassemble(&rom, 0x600);
+        assert!(listing.contains("CALL sub_604"));
+        assert!(listing.contains("sub_604:\n    RET"));
+        let text = inspect(&rom, 0x600);
+        assert!(text.contains("Size:        6 bytes, 0x600 to 0x605\n"));
+        assert!(text.contains("Code:        6 bytes, 3 instructions reachable from 0x600\n"));
+    }
+}
@@ -0,0 +1,84 @@
+use std::error::Error;
+use std::fmt;
+
+// Errors that stop the CPU from executing any further. Each carries the address
+// of the instruction that caused it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionError {
+    // The opcode at `address` isn't part of any supported instruction set.
+    UnknownOpcode { opcode: u16, address: usize },
+    // A CALL was made with all 16 stack entries already in use.
+    StackOverflow { address: usize },
+    // A RET was made with nothing on the stack.
+    StackUnderflow { address: usize },
+    // An instruction at `address` tried to touch memory at `target`, which
+    // doesn't exist.
+    MemoryOutOfBounds { address: usize, target: usize },
+    // The program exited with 00FD.
+    Exited { address: usize },
+    // The program ran into a 0000, which usually means it went off into
+    // empty memory.
+    Halted { address: usize },
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExecutionError::UnknownOpcode { opcode, address } =>
+                write!(f, "unknown opcode {:04X} at {:03X}", opcode, address),
+            ExecutionError::StackOverflow { address } =>
+                write!(f, "stack overflow at {:03X}", address),
+            ExecutionError::StackUnderflow { address } =>
+                write!(f, "stack underflow at {:03X}", address),
+            ExecutionError::MemoryOutOfBounds { address, target } =>
+                write!(f, "out of bounds memory access to {:X} at {:03X}", target, address),
+            ExecutionError::Exited { address } =>
+                write!(f, "program exited at {:03X}", address),
+            ExecutionError::Halted { address } =>
+                write!(f, "program halted on 0000 at {:03X}", address),
+        }
+    }
+}
+
+impl Error for ExecutionError {
+    fn description(&self) -> &str {
+        match *self {
+            ExecutionError::UnknownOpcode { .. } => "unknown opcode",
+            ExecutionError::StackOverflow { .. } => "stack overflow",
+            ExecutionError::StackUnderflow { .. } => "stack underflow",
+            ExecutionError::MemoryOutOfBounds { .. } => "out of bounds memory access",
+            ExecutionError::Exited { .. } => "program exited",
+            ExecutionError::Halted { .. } => "program halted on 0000",
+        }
+    }
+}
+
+// Reasons a program can't be loaded at the address asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    // `start` is before FONT_END, so the program would overwrite the fonts.
+    StartInFonts { start: usize },
+    // The program is `len` bytes long and runs past the end of memory when
+    // loaded at `start`.
+    TooBig { start: usize, len: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::StartInFonts { start } =>
+                write!(f, "can't load at {:03X}, which is inside the fonts", start),
+            LoadError::TooBig { start, len } =>
+                write!(f, "{} bytes is too big to load at {:03X}", len, start),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn description(&self) -> &str {
+        match *self {
+            LoadError::StartInFonts { .. } => "load address inside the fonts",
+            LoadError::TooBig { .. } => "program too big for memory",
+        }
+    }
+}
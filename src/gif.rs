@@ -0,0 +1,200 @@
+// A small animated GIF encoder for recording the display. Frames use a four
+// colour palette, one for each value a pixel can have.
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+// Pixels are two bits, so LZW codes start at three.
+const MIN_CODE_SIZE: u8 = 2;
+const CLEAR_CODE: u16 = 1 << MIN_CODE_SIZE;
+const END_CODE: u16 = CLEAR_CODE + 1;
+const MAX_CODE: u16 = 4095;
+
+pub struct GifWriter<W: Write> {
+    writer: W,
+    width: u16,
+    height: u16,
+}
+
+impl<W: Write> GifWriter<W> {
+    // Writes the header of a GIF that loops forever.
+    pub fn new(mut writer: W, width: u16, height: u16, colors: &[[u8; 3]; 4]) -> io::Result<GifWriter<W>> {
+        writer.write_all(b"GIF89a")?;
+        writer.write_all(&[width as u8, (width >> 8) as u8, height as u8, (height >> 8) as u8])?;
+        // A global colour table of 4 colours, background colour 0, square
+        // pixels.
+        writer.write_all(&[0x91, 0, 0])?;
+        for color in colors.iter() {
+            writer.write_all(color)?;
+        }
+        // The Netscape extension, to loop forever.
+        writer.write_all(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00")?;
+        Ok(GifWriter { writer: writer, width: width, height: height })
+    }
+
+    // Writes a frame of palette indices, shown for `delay` hundredths of a
+    // second.
+    pub fn frame(&mut self, pixels: &[u8], delay: u16) -> io::Result<()> {
+        assert_eq!(pixels.len(), self.width as usize * self.height as usize, "frame doesn't match the image size");
+        // The graphic control extension, with the delay.
+        self.writer.write_all(&[0x21, 0xF9, 4, 0, delay as u8, (delay >> 8) as u8, 0, 0])?;
+        // The image descriptor, covering the whole image.
+        self.writer.write_all(&[0x2C, 0, 0, 0, 0])?;
+        self.writer.write_all(&[self.width as u8, (self.width >> 8) as u8, self.height as u8, (self.height >> 8) as u8, 0])?;
+        self.writer.write_all(&[MIN_CODE_SIZE])?;
+        // The data is split into blocks of up to 255 bytes, each after its
+        // length, and ends with an empty block.
+        for block in lzw(pixels).chunks(255) {
+            self.writer.write_all(&[block.len() as u8])?;
+            self.writer.write_all(block)?;
+        }
+        self.writer.write_all(&[0])
+    }
+
+    // Writes the trailer, and hands back the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.write_all(&[0x3B])?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+// Codes are packed into bytes from the lowest bit up.
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u32,
+    count: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u8) {
+        self.bits |= (code as u32) << self.count;
+        self.count += size;
+        while self.count >= 8 {
+            self.bytes.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.bits as u8);
+        }
+        self.bytes
+    }
+}
+
+// Compresses palette indices with GIF's variant of LZW.
+fn lzw(pixels: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter { bytes: Vec::new(), bits: 0, count: 0 };
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut size = MIN_CODE_SIZE + 1;
+    let mut last_code = END_CODE;
+    out.write(CLEAR_CODE, size);
+
+    let mut pixels = pixels.iter().map(|&pixel| pixel & 3);
+    let mut prefix = match pixels.next() {
+        Some(pixel) => pixel as u16,
+        None => {
+            out.write(END_CODE, size);
+            return out.finish();
+        }
+    };
+    for pixel in pixels {
+        if let Some(&code) = table.get(&(prefix, pixel)) {
+            prefix = code;
+            continue;
+        }
+        out.write(prefix, size);
+        last_code += 1;
+        table.insert((prefix, pixel), last_code);
+        if last_code >= 1 << size {
+            size += 1;
+        }
+        // When the table is full, start again.
+        if last_code == MAX_CODE {
+            out.write(CLEAR_CODE, size);
+            table.clear();
+            size = MIN_CODE_SIZE + 1;
+            last_code = END_CODE;
+        }
+        prefix = pixel as u16;
+    }
+    out.write(prefix, size);
+    out.write(END_CODE, size);
+    out.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decodes LZW data the way a GIF decoder does.
+    fn unlzw(data: &[u8]) -> Vec<u8> {
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let mut size = MIN_CODE_SIZE + 1;
+        let (mut bits, mut count, mut bytes) = (0u32, 0u8, data.iter());
+        let mut previous: Option<Vec<u8>> = None;
+        let mut out = Vec::new();
+        loop {
+            while count < size {
+                bits |= (*bytes.next().expect("no end code") as u32) << count;
+                count += 8;
+            }
+            let code = (bits & ((1 << size) - 1)) as u16;
+            bits >>= size;
+            count -= size;
+            if code == CLEAR_CODE {
+                table = (0 .. CLEAR_CODE).map(|pixel| vec![pixel as u8]).collect();
+                table.push(Vec::new());
+                table.push(Vec::new());
+                size = MIN_CODE_SIZE + 1;
+                previous = None;
+                continue;
+            }
+            if code == END_CODE {
+                return out;
+            }
+            let entry = match previous {
+                Some(ref previous) if code as usize == table.len() => {
+                    let mut entry = previous.clone();
+                    entry.push(previous[0]);
+                    entry
+                }
+                _ => table[code as usize].clone(),
+            };
+            if let Some(mut previous) = previous.take() {
+                previous.push(entry[0]);
+                table.push(previous);
+            }
+            if table.len() >= 1 << size && size < 12 {
+                size += 1;
+            }
+            out.extend_from_slice(&entry);
+            previous = Some(entry);
+        }
+    }
+
+    #[test]
+    fn test_lzw_round_trip() {
+        assert_eq!(unlzw(&lzw(&[])), vec![]);
+        assert_eq!(unlzw(&lzw(&[1])), vec![1]);
+        // Long runs fill the table, so it's cleared part way.
+        let mut pixels = vec![0; 20000];
+        pixels.extend((0 .. 30000).map(|i| ((i * 7 + i / 13) % 4) as u8));
+        assert_eq!(unlzw(&lzw(&pixels)), pixels);
+    }
+
+    #[test]
+    fn test_gif() {
+        let colors = [[0, 0, 0], [255, 255, 255], [255, 0, 0], [0, 0, 255]];
+        let mut gif = GifWriter::new(Vec::new(), 3, 2, &colors).unwrap();
+        gif.frame(&[0, 1, 2, 3, 2, 1], 5).unwrap();
+        let data = gif.finish().unwrap();
+        assert_eq!(&data[.. 13], b"GIF89a\x03\x00\x02\x00\x91\x00\x00");
+        assert_eq!(&data[13 .. 25], &[0, 0, 0, 255, 255, 255, 255, 0, 0, 0, 0, 255]);
+        // After the loop extension, the frame shows for 5/100 of a second.
+        assert_eq!(&data[44 .. 52], &[0x21, 0xF9, 4, 0, 5, 0, 0, 0]);
+        assert_eq!(data[data.len() - 1], 0x3B);
+    }
+}
@@ -0,0 +1,500 @@
+use std::fmt::{self, Write as FmtWrite};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use audio::{ToneGenerator, WavRecorder};
+use cli::RunOptions;
+use cpu::Cpu;
+use error::ExecutionError;
+use movie::Movie;
+use palette::Palette;
+use render;
+use video::{VideoFormat, VideoRecorder};
+
+// One change to the keypad, made at the start of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub frame: u64,
+    pub key: usize,
+    pub down: bool,
+}
+
+impl KeyEvent {
+    // Parses an event written as `<frame> <key> down|up`, with the key in hex.
+    pub fn parse(line: &str) -> Option<KeyEvent> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.len() != 3 {
+            return None;
+        }
+        let frame = words[0].parse().ok()?;
+        let key = usize::from_str_radix(words[1], 16).ok().filter(|&key| key < 16)?;
+        let down = match words[2] {
+            "down" => true,
+            "up" => false,
+            _ => return None,
+        };
+        Some(KeyEvent { frame: frame, key: key, down: down })
+    }
+}
+
+impl fmt::Display for KeyEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:X} {}", self.frame, self.key, if self.down { "down" } else { "up" })
+    }
+}
+
+// Key presses to feed a program as it runs, kept in frame order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyScript {
+    events: Vec<KeyEvent>,
+}
+
+impl KeyScript {
+    pub fn new() -> KeyScript {
+        KeyScript { events: Vec::new() }
+    }
+
+    // Parses a script with one event per line. Blank lines and lines starting
+    // with `#` are ignored.
+    pub fn parse(text: &str) -> Result<KeyScript, String> {
+        let mut script = KeyScript::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match KeyEvent::parse(line) {
+                Some(event) => script.add(event),
+                None => return Err(format!("line {}: expected <frame> <key 0-F> down|up", index + 1)),
+            }
+        }
+        Ok(script)
+    }
+
+    pub fn add(&mut self, event: KeyEvent) {
+        // Insert after any events for the same frame, so they happen in the
+        // order they were added.
+        let position = self.events.iter().position(|e| e.frame > event.frame).unwrap_or(self.events.len());
+        self.events.insert(position, event);
+    }
+
+    // Holds `key` down for `frames` frames, starting at `frame`.
+    pub fn press(&mut self, frame: u64, key: usize, frames: u64) {
+        self.add(KeyEvent { frame: frame, key: key, down: true });
+        self.add(KeyEvent { frame: frame + frames, key: key, down: false });
+    }
+
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+
+    // Applies the events for one frame to the keypad.
+    pub fn apply(&self, frame: u64, cpu: &mut Cpu) {
+        for event in self.events.iter().filter(|e| e.frame == frame) {
+            cpu.set_key(event.key, event.down);
+        }
+    }
+}
+
+// How long a headless run goes on for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Frames(u64),
+    Cycles(u64),
+}
+
+// How a headless run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    // The limit was reached.
+    Finished,
+    // The program exited with 00FD.
+    Exited,
+    // The limit was reached, but the program was expected to exit first.
+    TimedOut,
+    // An execution error, including running into a 0000, which is usually
+    // the program going off into empty memory rather than meaning to stop.
+    Failed(ExecutionError),
+}
+
+impl Outcome {
+    // The process exit code for this outcome: 0 for success, 1 for an
+    // execution error and 2 for a timeout.
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Outcome::Finished | Outcome::Exited => 0,
+            Outcome::Failed(_) => 1,
+            Outcome::TimedOut => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    pub outcome: Outcome,
+    pub frames: u64,
+    pub cycles: u64,
+}
+
+// Runs a program without a window, a frame of instructions at a time. The
+// timers tick at the end of every frame.
+pub struct Runner {
+    pub cycles_per_frame: u64,
+    pub limit: Limit,
+    // Treat reaching the limit as a timeout rather than success.
+    pub until_exit: bool,
+    pub script: KeyScript,
+}
+
+impl Runner {
+    pub fn new(cycles_per_frame: u64, limit: Limit) -> Runner {
+        Runner { cycles_per_frame: cycles_per_frame, limit: limit, until_exit: false, script: KeyScript::new() }
+    }
+
+    // Sets the runner up to play `movie` back: its speed, length and keys.
+    pub fn play(&mut self, movie: &Movie) {
+        self.cycles_per_frame = movie.cycles_per_frame;
+        self.limit = Limit::Frames(movie.frames);
+        self.script = movie.keys.clone();
+    }
+
+    pub fn run(&self, cpu: &mut Cpu) -> Report {
+        self.run_with(cpu, |_| { })
+    }
+
+    // Runs the program, calling `on_frame` after each whole frame of
+    // instructions and before the timers tick.
+    pub fn run_with<F: FnMut(&Cpu)>(&self, cpu: &mut Cpu, mut on_frame: F) -> Report {
+        let mut frames = 0;
+        let mut cycles = 0;
+        loop {
+            if self.limit_reached(frames, cycles) {
+                return self.report_limit(frames, cycles);
+            }
+            self.script.apply(frames, cpu);
+            for _ in 0 .. self.cycles_per_frame {
+                // Stop part way through a frame for a cycle limit.
+                if self.limit_reached(frames, cycles) {
+                    return self.report_limit(frames, cycles);
+                }
+                let outcome = match cpu.emulate_cycle() {
+                    Ok(()) => None,
+                    Err(ExecutionError::Exited { .. }) => Some(Outcome::Exited),
+                    Err(e) => Some(Outcome::Failed(e)),
+                };
+                if let Some(outcome) = outcome {
+                    return Report { outcome: outcome, frames: frames, cycles: cycles };
+                }
+                cycles += 1;
+            }
+            on_frame(cpu);
+            cpu.tick_timers();
+            frames += 1;
+        }
+    }
+
+    fn limit_reached(&self, frames: u64, cycles: u64) -> bool {
+        match self.limit {
+            Limit::Frames(limit) => frames >= limit,
+            Limit::Cycles(limit) => cycles >= limit,
+        }
+    }
+
+    fn report_limit(&self, frames: u64, cycles: u64) -> Report {
+        let outcome = if self.until_exit { Outcome::TimedOut } else { Outcome::Finished };
+        Report { outcome: outcome, frames: frames, cycles: cycles }
+    }
+}
+
+impl Report {
+    // A line saying how the run ended.
+    pub fn summary(&self) -> String {
+        match self.outcome {
+            Outcome::Finished => format!("Ran {} frames, {} instructions.", self.frames, self.cycles),
+            Outcome::Exited => format!("ROM exited after {} frames, {} instructions.", self.frames, self.cycles),
+            Outcome::TimedOut => format!("Timed out after {} frames without the ROM exiting.", self.frames),
+            Outcome::Failed(e) => format!("Error: {} after {} frames.", e, self.frames),
+        }
+    }
+}
+
+// The files a headless run writes, shared by chip8-headless and
+// `chip8 --headless`. A path of `-` means stdout.
+pub struct Outputs {
+    pub display: Option<String>,
+    pub registers: Option<String>,
+    pub memory: Option<String>,
+    pub wav: Option<String>,
+    pub screenshot: Option<String>,
+    pub capture: Option<String>,
+    // Image pixels per display pixel in screenshots, and per low resolution
+    // pixel, rounded down to an even number, in captures.
+    pub scale: u32,
+    pub palette: Palette,
+    pub generator: ToneGenerator,
+}
+
+impl Outputs {
+    pub fn new() -> Outputs {
+        Outputs {
+            display: None, registers: None, memory: None, wav: None, screenshot: None, capture: None,
+            scale: 10, palette: Palette::default(), generator: ToneGenerator::new(44100),
+        }
+    }
+
+    // Runs the program, recording sound and video as it goes, then writes the
+    // files asked for. With none asked for, the registers and display are
+    // written to stdout. Errors are for files that couldn't be written.
+    pub fn run(self, runner: &Runner, cpu: &mut Cpu) -> Result<Report, String> {
+        let Outputs { display, registers, memory, wav, screenshot, capture, scale, palette, generator } = self;
+        let mut recorder = wav.as_ref().map(|_| WavRecorder::new(generator));
+        let mut video = match capture {
+            Some(ref path) => Some(open_capture(path, scale, palette).map_err(|e| write_error(path, e))?),
+            None => None,
+        };
+        let mut video_error = None;
+        let report = runner.run_with(cpu, |cpu| {
+            if let Some(ref mut recorder) = recorder {
+                recorder.record_frame(cpu);
+            }
+            // Stop recording at the first error, and report it after the run.
+            if let Some(Err(e)) = video.as_mut().map(|video| video.record_frame(cpu)) {
+                video_error = Some(e);
+                video = None;
+            }
+        });
+        if let Some(ref path) = capture {
+            if let Some(e) = video_error {
+                return Err(write_error(path, e));
+            }
+            if let Some(video) = video {
+                video.finish().map_err(|e| write_error(path, e))?;
+            }
+        }
+
+        if display.is_none() && registers.is_none() && memory.is_none() && screenshot.is_none() && capture.is_none() {
+            print!("{}\n{}", registers_text(cpu), display_text(cpu));
+        }
+        if let Some(ref path) = display {
+            write_output(path, display_text(cpu).as_bytes())?;
+        }
+        if let Some(ref path) = registers {
+            write_output(path, registers_text(cpu).as_bytes())?;
+        }
+        if let Some(ref path) = memory {
+            write_output(path, cpu.memory())?;
+        }
+        if let (Some(path), Some(recorder)) = (wav, recorder) {
+            write_output(&path, &recorder.to_wav())?;
+        }
+        if let Some(ref path) = screenshot {
+            write_output(path, &render::screenshot(cpu, scale, &palette))?;
+        }
+        Ok(report)
+    }
+}
+
+// Runs the program the way `options` ask, for both chip8-headless and
+// `chip8 --headless`, playing back `movie` if there is one.
+pub fn run_options(options: &RunOptions, cpu: &mut Cpu, movie: Option<&Movie>) -> Result<Report, String> {
+    let limit = match options.cycles {
+        Some(cycles) => Limit::Cycles(cycles),
+        None => Limit::Frames(options.frames),
+    };
+    let mut runner = Runner::new(options.cycles_per_frame, limit);
+    runner.until_exit = options.until_exit;
+    runner.script = options.keys.clone();
+    for path in &options.key_files {
+        let script = KeyScript::parse(&read_text(path)?).map_err(|e| format!("{}: {}", path, e))?;
+        for &event in script.events() {
+            runner.script.add(event);
+        }
+    }
+    if let Some(movie) = movie {
+        runner.play(movie);
+    }
+    let mut outputs = Outputs::new();
+    outputs.display = options.dump_display.clone();
+    outputs.registers = options.dump_registers.clone();
+    outputs.memory = options.dump_memory.clone();
+    outputs.wav = options.wav.clone();
+    outputs.screenshot = options.screenshot.clone();
+    outputs.capture = options.capture.clone();
+    outputs.scale = options.scale;
+    outputs.palette = options.palette;
+    options.apply_sound(&mut outputs.generator);
+    outputs.run(&runner, cpu)
+}
+
+// Opens `path` to record video to, at `scale` image pixels per low resolution
+// pixel rounded down to an even number. `-` records to stdout.
+pub fn open_capture(path: &str, scale: u32, palette: Palette) -> io::Result<VideoRecorder> {
+    let writer: Box<dyn Write> = if path == "-" {
+        Box::new(io::stdout())
+    } else {
+        Box::new(BufWriter::new(File::create(path)?))
+    };
+    VideoRecorder::new(writer, VideoFormat::from_path(path), (scale / 2).max(1), palette)
+}
+
+fn write_output(path: &str, data: &[u8]) -> Result<(), String> {
+    let result = if path == "-" {
+        io::stdout().write_all(data)
+    } else {
+        File::create(path).and_then(|mut f| f.write_all(data))
+    };
+    result.map_err(|e| write_error(path, e))
+}
+
+fn write_error(path: &str, e: io::Error) -> String {
+    format!("Error writing {}: {}", path, e)
+}
+
+pub fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    File::open(path).and_then(|mut f| f.read_to_end(&mut data)).map_err(|e| format!("Error reading {}: {}", path, e))?;
+    Ok(data)
+}
+
+pub fn read_text(path: &str) -> Result<String, String> {
+    read_file(path).map(|data| String::from_utf8_lossy(&data).into_owned())
+}
+
+// Loads ROM `data`, read from the file `name`, at `start`, with an error
+// naming the file if it doesn't fit.
+pub fn load_rom(cpu: &mut Cpu, name: &str, data: Vec<u8>, start: usize) -> Result<(), String> {
+    Cpu::load_data_at(cpu, data, start).map_err(|e| format!("Error loading {}: {}", name, e))
+}
+
+// The active area of the display as text, one line per row. Pixels are `.`
+// when off, and `#`, `+` or `*` for XO-CHIP bitplanes 1, 2 and both.
+pub fn display_text(cpu: &Cpu) -> String {
+    let chars = ['.', '#', '+', '*'];
+    let mut text = String::new();
+    for row in cpu.display().iter().take(cpu.display_height()) {
+        for &pixel in row.iter().take(cpu.display_width()) {
+            text.push(chars[pixel as usize & 3]);
+        }
+        text.push('\n');
+    }
+    text
+}
+
+pub fn registers_text(cpu: &Cpu) -> String {
+    let mut text = String::new();
+    for x in 0 .. 16 {
+        write!(text, "V{:X}={:02X}{}", x, cpu.v(x), if x % 8 == 7 { "\n" } else { " " }).unwrap();
+    }
+    writeln!(text, "I={:04X} PC={:03X} SP={:X} DT={:02X} ST={:02X}",
+             cpu.i(), cpu.pc(), cpu.sp(), cpu.delay_timer(), cpu.sound_timer()).unwrap();
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::cpu_with;
+    use quirks::Quirks;
+
+    #[test]
+    fn test_parse_script() {
+        let script = KeyScript::parse("# comment\n10 a down\n\n12 A up\n5 0 down").unwrap();
+        assert_eq!(script.events(), &[
+            KeyEvent { frame: 5, key: 0, down: true },
+            KeyEvent { frame: 10, key: 0xA, down: true },
+            KeyEvent { frame: 12, key: 0xA, down: false },
+        ]);
+        assert_eq!(KeyScript::parse("1 10 down"), Err("line 1: expected <frame> <key 0-F> down|up".to_string()));
+    }
+
+    #[test]
+    fn test_exits() {
+        // ADD V0 1, EXIT
+        let mut cpu = cpu_with(vec![0x70, 0x01, 0x00, 0xFD]);
+        let report = Runner::new(10, Limit::Frames(5)).run(&mut cpu);
+        assert_eq!(report, Report { outcome: Outcome::Exited, frames: 0, cycles: 1 });
+        assert_eq!(report.outcome.exit_code(), 0);
+        assert_eq!(report.summary(), "ROM exited after 0 frames, 1 instructions.");
+    }
+
+    #[test]
+    fn test_0000_is_an_error() {
+        // ADD V0 1, then nothing.
+        let mut cpu = cpu_with(vec![0x70, 0x01, 0x00, 0x00]);
+        let report = Runner::new(10, Limit::Frames(5)).run(&mut cpu);
+        assert_eq!(report.outcome, Outcome::Failed(ExecutionError::Halted { address: 0x202 }));
+        assert_eq!(report.outcome.exit_code(), 1);
+        assert_eq!(report.summary(), "Error: program halted on 0000 at 202 after 0 frames.");
+    }
+
+    #[test]
+    fn test_limits() {
+        // ADD V0 1, JP 200
+        let mut cpu = cpu_with(vec![0x70, 0x01, 0x12, 0x00]);
+        let report = Runner::new(10, Limit::Cycles(15)).run(&mut cpu);
+        assert_eq!(report, Report { outcome: Outcome::Finished, frames: 1, cycles: 15 });
+        assert_eq!(cpu.v(0), 8);
+
+        let mut runner = Runner::new(10, Limit::Frames(3));
+        runner.until_exit = true;
+        let report = runner.run(&mut cpu);
+        assert_eq!(report, Report { outcome: Outcome::TimedOut, frames: 3, cycles: 30 });
+        assert_eq!(report.outcome.exit_code(), 2);
+    }
+
+    #[test]
+    fn test_timers_tick_per_frame() {
+        let mut cpu = cpu_with(vec![0x12, 0x00]);
+        cpu.set_delay_timer(5);
+        Runner::new(10, Limit::Frames(3)).run(&mut cpu);
+        assert_eq!(cpu.delay_timer(), 2);
+    }
+
+    #[test]
+    fn test_run_with_sees_each_frame() {
+        // LD V0 3, LD ST V0, JP 204
+        let mut cpu = cpu_with(vec![0x60, 0x03, 0xF0, 0x18, 0x12, 0x04]);
+        let mut sound = Vec::new();
+        Runner::new(10, Limit::Frames(5)).run_with(&mut cpu, |cpu| sound.push(cpu.sound_timer()));
+        assert_eq!(sound, vec![3, 2, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_execution_error() {
+        let mut cpu = cpu_with(vec![0x00, 0xEE]);
+        let report = Runner::new(10, Limit::Frames(3)).run(&mut cpu);
+        assert_eq!(report.outcome, Outcome::Failed(ExecutionError::StackUnderflow { address: 0x200 }));
+        assert_eq!(report.outcome.exit_code(), 1);
+    }
+
+    #[test]
+    fn test_scripted_key() {
+        // Wait for a key into V0, then EXIT.
+        let mut cpu = cpu_with(vec![0xF0, 0x0A, 0x00, 0xFD]);
+        let mut runner = Runner::new(10, Limit::Frames(100));
+        runner.script.press(20, 0xB, 2);
+        let report = runner.run(&mut cpu);
+        assert_eq!(report.outcome, Outcome::Exited);
+        assert_eq!(report.frames, 20);
+        assert_eq!(cpu.v(0), 0xB);
+    }
+
+    #[test]
+    fn test_load_rom_checks_size() {
+        let mut cpu = Cpu::new(Quirks::default());
+        assert_eq!(load_rom(&mut cpu, "big.ch8", vec![0; 0xFE01], 0x200),
+                   Err("Error loading big.ch8: 65025 bytes is too big to load at 200".to_string()));
+        assert_eq!(load_rom(&mut cpu, "full.ch8", vec![0x12; 0xFE00], 0x200), Ok(()));
+        assert_eq!(cpu.memory()[0xFFFF], 0x12);
+    }
+
+    #[test]
+    fn test_display_text() {
+        let mut cpu = cpu_with(vec![]);
+        cpu.disp_buff[0][1] = 1;
+        cpu.disp_buff[1][0] = 3;
+        let text = display_text(&cpu);
+        assert_eq!(text.lines().count(), 32);
+        assert!(text.starts_with(".#..."));
+        assert!(text.lines().nth(1).unwrap().starts_with("*..."));
+    }
+}
@@ -0,0 +1,400 @@
+use std::collections::BTreeMap;
+use toml::Value;
+
+// Which host keys press which CHIP-8 keys. Host keys are named the way piston
+// names them, such as `Q`, `1`, `Semicolon` or `Up`, and names are compared
+// without regard to case. Any number of host keys can press the same CHIP-8
+// key.
+//
+// Controller buttons and sticks are bound the same way, as `Button0` and so on
+// for buttons and `Axis0-` and `Axis0+` for each direction of an axis.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keymap {
+    // Host key names, lowercased, and the CHIP-8 key each one presses.
+    bindings: BTreeMap<String, usize>,
+    // How far a stick has to be pushed, from 0 to 1, before it counts.
+    pub dead_zone: f64,
+}
+
+// The CHIP-8 keypad as laid out on the COSMAC VIP, row by row. Each layout
+// below lists the host keys in the same positions.
+const KEYPAD: [usize; 16] = [0x1, 0x2, 0x3, 0xC,
+                             0x4, 0x5, 0x6, 0xD,
+                             0x7, 0x8, 0x9, 0xE,
+                             0xA, 0x0, 0xB, 0xF];
+
+const QWERTY: [&'static str; 16] = ["1", "2", "3", "4",
+                                    "Q", "W", "E", "R",
+                                    "A", "S", "D", "F",
+                                    "Z", "X", "C", "V"];
+
+const AZERTY: [&'static str; 16] = ["1", "2", "3", "4",
+                                    "A", "Z", "E", "R",
+                                    "Q", "S", "D", "F",
+                                    "W", "X", "C", "V"];
+
+const DVORAK: [&'static str; 16] = ["1", "2", "3", "4",
+                                    "Quote", "Comma", "Period", "P",
+                                    "A", "O", "E", "U",
+                                    "Semicolon", "Q", "J", "K"];
+
+// Controller bindings that come with every layout. The button numbers are
+// those SDL gives an Xbox style controller: the d-pad and left stick move with
+// 2, 4, 6 and 8, and A, B and X press 5, A and B.
+const GAMEPAD: [(&'static str, usize); 11] = [
+    ("Button11", 0x2), ("Button12", 0x8), ("Button13", 0x4), ("Button14", 0x6),
+    ("Axis1-", 0x2), ("Axis1+", 0x8), ("Axis0-", 0x4), ("Axis0+", 0x6),
+    ("Button0", 0x5), ("Button1", 0xA), ("Button2", 0xB),
+];
+
+const DEFAULT_DEAD_ZONE: f64 = 0.3;
+
+// The names accepted by Keymap::layout() and `layout = ...` in config files.
+pub const LAYOUTS: [&'static str; 3] = ["qwerty", "azerty", "dvorak"];
+
+// Host keys the frontend keeps for its own hotkeys, which keymaps can't bind.
+pub const RESERVED_KEYS: [&'static str; 20] = [
+    "M", "Backspace", "Pause", "PrintScreen",
+    "F1", "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9", "F10", "F11", "F12",
+    "LShift", "RShift", "LCtrl", "RCtrl",
+];
+
+// The name a controller button is bound by.
+pub fn button_name(button: u8) -> String {
+    format!("Button{}", button)
+}
+
+// An axis is bound as two inputs, one for each direction. Returns them both,
+// with whether each is held once `position` is put through the dead zone.
+pub fn axis_inputs(axis: u8, position: f64, dead_zone: f64) -> [(String, bool); 2] {
+    [(format!("Axis{}-", axis), position < -dead_zone),
+     (format!("Axis{}+", axis), position > dead_zone)]
+}
+
+impl Keymap {
+    // A keymap with nothing bound.
+    pub fn new() -> Keymap {
+        Keymap { bindings: BTreeMap::new(), dead_zone: DEFAULT_DEAD_ZONE }
+    }
+
+    // The keys in the same places as the VIP's keypad on a QWERTY keyboard:
+    // 1234, QWER, ASDF and ZXCV.
+    pub fn qwerty() -> Keymap {
+        Keymap::from_positions(&QWERTY)
+    }
+
+    pub fn azerty() -> Keymap {
+        let mut keymap = Keymap::from_positions(&AZERTY);
+        // The number row needs shift on AZERTY, so its unshifted symbols work
+        // too where piston has names for them.
+        keymap.bind("Ampersand", 0x1);
+        keymap.bind("Quotedbl", 0x3);
+        keymap.bind("Quote", 0xC);
+        keymap
+    }
+
+    pub fn dvorak() -> Keymap {
+        Keymap::from_positions(&DVORAK)
+    }
+
+    // Looks up a built-in layout by name.
+    pub fn layout(name: &str) -> Option<Keymap> {
+        match name.to_lowercase().as_str() {
+            "qwerty" => Some(Keymap::qwerty()),
+            "azerty" => Some(Keymap::azerty()),
+            "dvorak" => Some(Keymap::dvorak()),
+            _ => None,
+        }
+    }
+
+    fn from_positions(names: &[&str; 16]) -> Keymap {
+        let mut keymap = Keymap::new();
+        for (name, &key) in names.iter().zip(KEYPAD.iter()) {
+            keymap.bind(name, key);
+        }
+        for &(name, key) in GAMEPAD.iter() {
+            keymap.bind(name, key);
+        }
+        keymap
+    }
+
+    // Makes the host key press `key`, instead of whatever it pressed before.
+    pub fn bind(&mut self, host: &str, key: usize) {
+        self.bindings.insert(host.to_lowercase(), key);
+    }
+
+    // Removes every binding for `key`.
+    pub fn unbind(&mut self, key: usize) {
+        let hosts: Vec<String> = self.bindings.iter()
+            .filter(|&(_, &bound)| bound == key)
+            .map(|(host, _)| host.clone())
+            .collect();
+        for host in hosts {
+            self.bindings.remove(&host);
+        }
+    }
+
+    // The CHIP-8 key pressed by a host key.
+    pub fn lookup(&self, host: &str) -> Option<usize> {
+        self.bindings.get(&host.to_lowercase()).cloned()
+    }
+
+    // The host keys that press `key`, lowercased.
+    pub fn hosts(&self, key: usize) -> Vec<&str> {
+        self.bindings.iter().filter(|&(_, &bound)| bound == key).map(|(host, _)| host.as_str()).collect()
+    }
+
+    // The keypad with every key pressed by one of the `held` host keys down.
+    pub fn keypad<'a, I: IntoIterator<Item = &'a String>>(&self, held: I) -> [bool; 16] {
+        let mut keys = [false; 16];
+        for host in held {
+            if let Some(key) = self.lookup(host) {
+                keys[key] = true;
+            }
+        }
+        keys
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        Keymap::qwerty()
+    }
+}
+
+// A keymap config file. It picks a layout, rebinds keys on top of it, and can
+// do both differently for particular ROMs:
+//
+//   layout = "azerty"
+//
+//   [keys]
+//   5 = ["Z", "Up"]
+//   0 = "Space"
+//
+//   [rom."pong.ch8".keys]
+//   1 = ["Up", "Axis1-"]
+//   4 = ["Down", "Axis1+"]
+//
+// Listing a CHIP-8 key under `keys` replaces all of the layout's bindings for
+// it, controller ones included. ROMs are named by file name or by the hash
+// printed when they load. `dead_zone = 0.5` sets how far sticks have to move.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeymapConfig {
+    layout: Option<String>,
+    dead_zone: Option<f64>,
+    keys: Vec<(usize, Vec<String>)>,
+    roms: Vec<(String, KeymapConfig)>,
+}
+
+impl KeymapConfig {
+    pub fn parse(text: &str) -> Result<KeymapConfig, String> {
+        let value = text.parse::<Value>().map_err(|e| e.to_string())?;
+        KeymapConfig::from_table(&value, "", true)
+    }
+
+    fn from_table(value: &Value, path: &str, top_level: bool) -> Result<KeymapConfig, String> {
+        let mut config = KeymapConfig { layout: None, dead_zone: None, keys: Vec::new(), roms: Vec::new() };
+        let table = match value.as_table() {
+            Some(table) => table,
+            None => return Err(format!("{} should be a table", path)),
+        };
+        for (name, value) in table {
+            let name_path = format!("{}{}", path, name);
+            match name.as_str() {
+                "layout" => {
+                    let layout = value.as_str().ok_or(format!("{} should be a string", name_path))?;
+                    if Keymap::layout(layout).is_none() {
+                        return Err(format!("unknown layout {:?}, expected one of {}", layout, LAYOUTS.join(", ")));
+                    }
+                    config.layout = Some(layout.to_string());
+                }
+                "dead_zone" => {
+                    let dead_zone = match *value {
+                        Value::Float(n) => n,
+                        Value::Integer(n) => n as f64,
+                        _ => return Err(format!("{} should be a number", name_path)),
+                    };
+                    if dead_zone < 0.0 || dead_zone >= 1.0 {
+                        return Err(format!("{} should be at least 0 and less than 1", name_path));
+                    }
+                    config.dead_zone = Some(dead_zone);
+                }
+                "keys" => config.keys = parse_keys(value, &name_path)?,
+                "rom" if top_level => {
+                    let roms = value.as_table().ok_or(format!("{} should be a table", name_path))?;
+                    for (rom, value) in roms {
+                        let rom_path = format!("{}.{:?}.", name_path, rom);
+                        config.roms.push((rom.to_lowercase(), KeymapConfig::from_table(value, &rom_path, false)?));
+                    }
+                }
+                _ => return Err(format!("unknown setting {}", name_path)),
+            }
+        }
+        Ok(config)
+    }
+
+    // The keymap to use for a ROM, given its file name and hash. `layout`
+    // overrides any layout the config picks, such as one given on the
+    // command line.
+    pub fn keymap_for(&self, rom_name: &str, rom_hash: u64, layout: Option<&str>) -> Keymap {
+        let hash = format!("{:016x}", rom_hash);
+        let rom_name = rom_name.to_lowercase();
+        let rom = self.roms.iter()
+            .find(|&&(ref name, _)| *name == rom_name || *name == hash)
+            .map(|&(_, ref config)| config);
+
+        let layout = layout
+            .or_else(|| rom.and_then(|rom| rom.layout.as_ref()).map(|layout| layout.as_str()))
+            .or_else(|| self.layout.as_ref().map(|layout| layout.as_str()));
+        let mut keymap = layout.and_then(Keymap::layout).unwrap_or_default();
+        self.apply(&mut keymap);
+        if let Some(rom) = rom {
+            rom.apply(&mut keymap);
+        }
+        keymap
+    }
+
+    fn apply(&self, keymap: &mut Keymap) {
+        if let Some(dead_zone) = self.dead_zone {
+            keymap.dead_zone = dead_zone;
+        }
+        for &(key, ref hosts) in &self.keys {
+            keymap.unbind(key);
+            for host in hosts {
+                keymap.bind(host, key);
+            }
+        }
+    }
+}
+
+// Parses a `[keys]` table of CHIP-8 keys in hex, each with a host key name or
+// a list of them.
+fn parse_keys(value: &Value, path: &str) -> Result<Vec<(usize, Vec<String>)>, String> {
+    let table = value.as_table().ok_or(format!("{} should be a table", path))?;
+    let mut keys = Vec::new();
+    for (key, hosts) in table {
+        let key_number = usize::from_str_radix(key, 16).ok().filter(|&key| key < 16)
+            .ok_or(format!("{}: {:?} is not a CHIP-8 key, expected 0 to F", path, key))?;
+        let bad_hosts = || format!("{}.{} should be a key name or a list of them", path, key);
+        let hosts = match *hosts {
+            Value::String(ref host) => vec![host.clone()],
+            Value::Array(ref hosts) => {
+                let mut names = Vec::new();
+                for host in hosts {
+                    names.push(host.as_str().ok_or_else(&bad_hosts)?.to_string());
+                }
+                names
+            }
+            _ => return Err(bad_hosts()),
+        };
+        let reserved = |host: &&String| RESERVED_KEYS.iter().any(|name| name.eq_ignore_ascii_case(host));
+        if let Some(host) = hosts.iter().find(reserved) {
+            return Err(format!("{}.{}: {} is a hotkey and can't be bound", path, key, host));
+        }
+        keys.push((key_number, hosts));
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layouts() {
+        let qwerty = Keymap::default();
+        assert_eq!(qwerty.lookup("q"), Some(0x4));
+        assert_eq!(qwerty.lookup("V"), Some(0xF));
+        assert_eq!(qwerty.lookup("Up"), None);
+        let azerty = Keymap::layout("AZERTY").unwrap();
+        assert_eq!(azerty.lookup("A"), Some(0x4));
+        assert_eq!(azerty.lookup("W"), Some(0xA));
+        assert_eq!(azerty.hosts(0x1), vec!["1", "ampersand"]);
+        assert_eq!(Keymap::dvorak().lookup("Semicolon"), Some(0xA));
+        assert!(Keymap::layout("colemak").is_none());
+    }
+
+    #[test]
+    fn test_keypad_with_several_hosts() {
+        let mut keymap = Keymap::qwerty();
+        keymap.bind("Up", 0x5);
+        let held = vec!["Up".to_string(), "W".to_string(), "Space".to_string()];
+        let keys = keymap.keypad(&held);
+        assert!(keys[5]);
+        assert_eq!(keys.iter().filter(|&&down| down).count(), 1);
+        // Letting go of one of the keys for 5 leaves it held.
+        assert!(keymap.keypad(&held[1 ..])[5]);
+    }
+
+    #[test]
+    fn test_gamepad() {
+        let keymap = Keymap::dvorak();
+        assert_eq!(keymap.lookup(&button_name(11)), Some(0x2));
+        assert_eq!(keymap.lookup(&button_name(0)), Some(0x5));
+
+        let inputs = axis_inputs(0, -0.8, keymap.dead_zone);
+        assert_eq!(inputs, [("Axis0-".to_string(), true), ("Axis0+".to_string(), false)]);
+        let held: Vec<String> = inputs.iter().filter(|&&(_, down)| down).map(|&(ref name, _)| name.clone()).collect();
+        assert!(keymap.keypad(&held)[4]);
+        // Inside the dead zone, neither direction is held.
+        assert_eq!(axis_inputs(1, 0.2, keymap.dead_zone), [("Axis1-".to_string(), false), ("Axis1+".to_string(), false)]);
+    }
+
+    #[test]
+    fn test_config() {
+        let config = KeymapConfig::parse(r#"
+            layout = "azerty"
+
+            dead_zone = 0.5
+
+            [keys]
+            5 = ["Up", "Space"]
+            a = "Return"
+
+            [rom."pong.ch8"]
+            layout = "dvorak"
+            [rom."pong.ch8".keys]
+            1 = "Up"
+        "#).unwrap();
+        let keymap = config.keymap_for("other.ch8", 0, None);
+        assert_eq!(keymap.lookup("A"), Some(0x4));
+        assert_eq!(keymap.lookup("Z"), None);
+        assert_eq!(keymap.lookup("Space"), Some(0x5));
+        assert_eq!(keymap.lookup("W"), None);
+        assert_eq!(keymap.lookup("Return"), Some(0xA));
+        assert_eq!(keymap.lookup("Button1"), None);
+        assert_eq!(keymap.dead_zone, 0.5);
+
+        let keymap = config.keymap_for("Pong.ch8", 0, None);
+        assert_eq!(keymap.lookup("O"), Some(0x8));
+        assert_eq!(keymap.lookup("Up"), Some(0x1));
+        assert_eq!(keymap.lookup("Space"), Some(0x5));
+        // The command line's layout wins.
+        assert_eq!(config.keymap_for("other.ch8", 0, Some("qwerty")).lookup("Q"), Some(0x4));
+    }
+
+    #[test]
+    fn test_config_by_hash() {
+        let config = KeymapConfig::parse("[rom.00000000000000AB.keys]\nf = \"Tab\"").unwrap();
+        assert_eq!(config.keymap_for("x.ch8", 0xAB, None).lookup("Tab"), Some(0xF));
+        assert_eq!(config.keymap_for("x.ch8", 0xAC, None).lookup("Tab"), None);
+    }
+
+    #[test]
+    fn test_config_errors() {
+        assert_eq!(KeymapConfig::parse("layout = \"colemak\""),
+                   Err("unknown layout \"colemak\", expected one of qwerty, azerty, dvorak".to_string()));
+        assert_eq!(KeymapConfig::parse("[keys]\n10 = \"A\""),
+                   Err("keys: \"10\" is not a CHIP-8 key, expected 0 to F".to_string()));
+        assert_eq!(KeymapConfig::parse("[keys]\n1 = 5"),
+                   Err("keys.1 should be a key name or a list of them".to_string()));
+        assert_eq!(KeymapConfig::parse("[keys]\n1 = [\"Up\", \"m\"]"),
+                   Err("keys.1: m is a hotkey and can't be bound".to_string()));
+        assert_eq!(KeymapConfig::parse("[rom.\"pong.ch8\".keys]\n1 = \"F10\""),
+                   Err("rom.\"pong.ch8\".keys.1: F10 is a hotkey and can't be bound".to_string()));
+        assert_eq!(KeymapConfig::parse("speed = 5"), Err("unknown setting speed".to_string()));
+        assert_eq!(KeymapConfig::parse("dead_zone = 1"),
+                   Err("dead_zone should be at least 0 and less than 1".to_string()));
+        assert!(KeymapConfig::parse("[keys").is_err());
+    }
+}
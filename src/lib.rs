@@ -0,0 +1,30 @@
+extern crate rand;
+extern crate toml;
+
+pub mod asm;
+pub mod audio;
+pub mod cli;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod error;
+pub mod gif;
+pub mod headless;
+pub mod keymap;
+pub mod movie;
+pub mod palette;
+pub mod png;
+pub mod quirks;
+pub mod render;
+pub mod rewind;
+pub mod rng;
+pub mod state;
+pub mod video;
+
+pub use cpu::Cpu;
+pub use debugger::Debugger;
+pub use error::{ExecutionError, LoadError};
+pub use quirks::Quirks;
+pub use rewind::RewindBuffer;
+pub use rng::RandomSource;
+pub use state::StateError;
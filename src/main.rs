@@ -1,122 +1,848 @@
-mod cpu;
-
+extern crate chip8;
 extern crate piston_window;
 extern crate piston;
+extern crate cpal;
+extern crate image as im;
 
 use piston_window::*;
+use std::cmp;
+use std::collections::HashSet;
 use std::env;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read, Write};
 use std::process;
-use cpu::Cpu;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use chip8::{Cpu, Debugger, ExecutionError, RewindBuffer};
+use chip8::cli::{self, Command, RunOptions};
+use chip8::headless;
+use chip8::debugger::ConsoleExit;
+use chip8::{asm, disasm};
+use chip8::movie::{Movie, Player, Recorder};
+use chip8::audio::{SoundState, ToneGenerator};
+use chip8::keymap::{self, Keymap, KeymapConfig};
+use chip8::palette::Palette;
+use chip8::render::{self, Screen, Viewport};
+use chip8::video::VideoRecorder;
+use std::path::Path;
+
+// How many frames can be rewound: 30 seconds at 60 frames per second.
+const REWIND_FRAMES: usize = 30 * 60;
+// How many frames are run per update while fast forwarding.
+const TURBO_SPEED: usize = 4;
+
+// What to run next while paused.
+#[derive(Clone, Copy, PartialEq)]
+enum Advance {
+    Frame,
+    Instruction
+}
 
 struct Machine {
-    cpu: Cpu
+    cpu: Cpu,
+    rom_path: String,
+    shift_held: bool,
+    ctrl_held: bool,
+    rewind: RewindBuffer,
+    // Backspace is held, so frames are played backwards instead of running.
+    rewinding: bool,
+    // Instructions run per 60Hz frame, between timer ticks.
+    cycles_per_frame: usize,
+    // Instructions run so far in this frame. Stepping by instruction while
+    // paused can leave a frame part way through.
+    frame_cycles: usize,
+    paused: bool,
+    advance: Option<Advance>,
+    // Fast forwarding, at TURBO_SPEED frames per update.
+    turbo: bool,
+    palette: Palette,
+    // Image pixels per display pixel in screenshots.
+    scale: u32,
+    // Pixels are kept a whole number of window pixels across.
+    integer_scale: bool,
+    // Lines are drawn between pixels, to line sprites up by.
+    grid: bool,
+    // The frames drawn, kept at the end of each frame for the flicker
+    // filters.
+    screen: Screen,
+    // Only whole frames are drawn, from the screen, rather than the display as
+    // it is when the window is drawn.
+    vblank: bool,
+    // The screen as it was last uploaded, and its size in display pixels.
+    texture: Option<(G2dTexture, (usize, usize))>,
+    // F11 was pressed, so the window is reopened in or out of fullscreen.
+    fullscreen: bool,
+    debugger: Debugger,
+    // F12 was pressed, so open the debugger console before the next cycle.
+    break_requested: bool,
+    keymap: Keymap,
+    // The names of the mapped host keys and controller inputs being held.
+    held: HashSet<String>,
+    // The controller buttons already reported as unbound, by controller id
+    // and button, so each is only reported once.
+    unbound_reported: HashSet<(i32, u8)>,
+    // The keypad keys held on the keyboard or controller. They are passed to
+    // the cpu at the start of each frame, so input only ever changes between
+    // frames.
+    keys: [bool; 16],
+    // The movie being recorded, and where to save it.
+    recorder: Option<(Recorder, String)>,
+    player: Option<Player>,
+    // The video being recorded, and where to.
+    capture: Option<(VideoRecorder, String)>,
+    // None if there's no audio device.
+    speaker: Option<Arc<Mutex<Speaker>>>
 }
 
 impl Machine {
 
-    fn new() -> Machine {
-        Machine { cpu : Cpu::new() }
+    fn new(cpu: Cpu, cycles_per_frame: usize) -> Machine {
+        let mut debugger = Debugger::new();
+        debugger.cycles_per_frame = cycles_per_frame;
+        Machine {
+            cpu : cpu,
+            rom_path: String::new(),
+            shift_held: false,
+            ctrl_held: false,
+            rewind: RewindBuffer::new(REWIND_FRAMES),
+            rewinding: false,
+            cycles_per_frame: cycles_per_frame,
+            frame_cycles: 0,
+            paused: false,
+            advance: None,
+            turbo: false,
+            palette: Palette::default(),
+            scale: 10,
+            integer_scale: false,
+            grid: false,
+            screen: Screen::new(render::Filter::None),
+            vblank: false,
+            texture: None,
+            fullscreen: false,
+            debugger: debugger,
+            break_requested: false,
+            keymap: Keymap::default(),
+            held: HashSet::new(),
+            unbound_reported: HashSet::new(),
+            keys: [false; 16],
+            recorder: None,
+            player: None,
+            capture: None,
+            speaker: None
+        }
     }
 
-    fn load_rom(&mut self) {
-        let args: Vec<String> = env::args().collect();
-        let ref rom;
-        if args.len() > 1 {
-            rom = &args[1];
-        } else {
-            println!("Please provide a path to a chip8 rom as a command line argument.");
-            process::exit(0);
+    // Sets the keypad for the next frame, from the movie being played or else
+    // the keyboard.
+    fn apply_input(&mut self) {
+        let playing = match self.player {
+            Some(ref mut player) => player.apply_frame(&mut self.cpu),
+            None => false
+        };
+        if playing {
+            return;
         }
+        if self.player.take().is_some() {
+            println!("Movie finished, the keyboard is back in control.");
+        }
+        for key in 0 .. 16 {
+            self.cpu.set_key(key, self.keys[key]);
+        }
+        if let Some((ref mut recorder, _)) = self.recorder {
+            recorder.record_frame(&self.keys);
+        }
+    }
 
+    // Saves the movie being recorded, if there is one.
+    fn stop_recording(&mut self) {
+        if let Some((recorder, path)) = self.recorder.take() {
+            let movie = recorder.finish();
+            match File::create(&path).and_then(|mut f| f.write_all(movie.to_text().as_bytes())) {
+                Ok(()) => println!("Saved {} frame movie to {}.", movie.frames, path),
+                Err(e) => println!("Error writing {}: {}", path, e),
+            }
+        }
+    }
 
-        let file = File::open(rom);
-        let mut rom_data = Vec::new();
-        let read_result;
-        match file {
-            Ok(mut f) => { read_result = f.read_to_end(&mut rom_data) },
-            Err(e) => {
-                println!("Error reading file: {:?}", e);
-                process::exit(0);
+    // Starts recording video to `path`.
+    fn start_capture(&mut self, path: &str) {
+        match headless::open_capture(path, self.scale, self.palette) {
+            Ok(capture) => {
+                let (width, height) = capture.size();
+                println!("Recording {}x{} video to {}.", width, height, path);
+                self.capture = Some((capture, path.to_string()));
             }
+            Err(e) => println!("Error writing {}: {}", path, e),
         }
+    }
+
+    // Finishes the video being recorded, if there is one.
+    fn stop_capture(&mut self) {
+        if let Some((capture, path)) = self.capture.take() {
+            let frames = capture.frames();
+            match capture.finish() {
+                Ok(()) => println!("Saved {} frame video to {}.", frames, path),
+                Err(e) => println!("Error writing {}: {}", path, e),
+            }
+        }
+    }
+
+    // F10 starts recording a GIF next to the ROM, numbered from 1, and stops
+    // it again.
+    fn toggle_capture(&mut self) {
+        if self.capture.is_some() {
+            self.stop_capture();
+            return;
+        }
+        let path = (1 ..).map(|n| format!("{}.capture{}.gif", self.rom_path, n))
+            .find(|path| !Path::new(path).exists()).unwrap();
+        self.start_capture(&path);
+    }
 
-        match read_result {
-            Ok(_) => Cpu::load_data(&mut self.cpu, rom_data),
+    // Rewinding and loading states would leave a movie out of step with the
+    // game, so they are turned off while one is recording or playing.
+    fn movie_active(&self) -> bool {
+        self.recorder.is_some() || self.player.is_some()
+    }
+
+    // Passes what the frame just run wants to play to the audio thread.
+    fn update_sound(&self, sound: SoundState) {
+        if let Some(ref speaker) = self.speaker {
+            speaker.lock().unwrap().sound = sound;
+        }
+    }
+
+    fn toggle_mute(&self) {
+        match self.speaker {
+            Some(ref speaker) => {
+                let mut speaker = speaker.lock().unwrap();
+                speaker.generator.muted = !speaker.generator.muted;
+                println!("Sound {}.", if speaker.generator.muted { "muted" } else { "unmuted" });
+            }
+            None => println!("There is no audio device to play sound on."),
+        }
+    }
+
+    // Called 60 times a second. Runs one frame, or steps back one frame while
+    // rewinding. While paused, only runs what was asked for with the advance
+    // keys.
+    fn on_update(&mut self) {
+        if self.rewinding {
+            self.rewind.rewind(&mut self.cpu, 1);
+            self.frame_cycles = 0;
+            self.end_frame_screen();
+            self.update_sound(SoundState::silent());
+            return;
+        }
+        if self.paused {
+            match self.advance.take() {
+                Some(Advance::Frame) => self.run_frame(),
+                Some(Advance::Instruction) => {
+                    self.run_cycle();
+                    let pc = self.cpu.pc();
+                    let byte = |address: usize| self.cpu.memory().get(address).cloned().unwrap_or(0) as u16;
+                    let opcode = byte(pc) << 8 | byte(pc + 1);
+                    println!("Next: {:03X}: {}", pc, disasm::decode(opcode, 0).unwrap_or_else(|| "???".to_string()));
+                }
+                None => { }
+            }
+            self.update_sound(SoundState::silent());
+            return;
+        }
+        let frames = if self.turbo { TURBO_SPEED } else { 1 };
+        for _ in 0 .. frames {
+            self.run_frame();
+        }
+    }
+
+    // Runs the rest of the current frame.
+    fn run_frame(&mut self) {
+        self.run_cycle();
+        while self.frame_cycles > 0 {
+            self.run_cycle();
+        }
+    }
+
+    // Runs one instruction. Input is applied at the start of a frame, and the
+    // timers tick at the end of one.
+    fn run_cycle(&mut self) {
+        if self.frame_cycles == 0 {
+            self.apply_input();
+        }
+        if let Some(reason) = self.debugger.check(&self.cpu) {
+            println!("Stopped: {}", reason);
+            self.break_requested = true;
+        }
+        if self.break_requested {
+            self.break_requested = false;
+            self.open_console();
+        }
+        match self.cpu.emulate_cycle() {
+            Ok(()) => { }
+            Err(e @ ExecutionError::Exited { .. }) => {
+                println!("{}. Emulation terminated.", e);
+                self.stop_recording();
+                self.stop_capture();
+                process::exit(0);
+            }
             Err(e) => {
-                println!("Error reading rom: {:?}", e);
+                println!("Error: {}. Emulation terminated.", e);
+                self.stop_recording();
+                self.stop_capture();
+                process::exit(1);
+            }
+        }
+        self.frame_cycles += 1;
+        if self.frame_cycles >= self.cycles_per_frame {
+            self.frame_cycles = 0;
+            self.update_sound(SoundState::of(&self.cpu));
+            self.capture_frame();
+            self.end_frame_screen();
+            self.cpu.tick_timers();
+            self.rewind.push(&self.cpu);
+        }
+    }
+
+    // Adds the frame just run to the video being recorded.
+    fn capture_frame(&mut self) {
+        let result = match self.capture {
+            Some((ref mut capture, _)) => capture.record_frame(&self.cpu),
+            None => return
+        };
+        if let Err(e) = result {
+            let (_, path) = self.capture.take().unwrap();
+            println!("Error writing {}: {}. Stopped recording video.", path, e);
+        }
+    }
+
+    fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        self.advance = None;
+        if self.paused {
+            println!("Paused. Ctrl+N runs a frame and Ctrl+I an instruction.");
+        }
+    }
+
+    // Changes the instructions run per frame by about a tenth.
+    fn change_speed(&mut self, faster: bool) {
+        if self.movie_active() {
+            println!("The speed can't be changed while a movie is recording or playing.");
+            return;
+        }
+        let step = cmp::max(1, self.cycles_per_frame / 10);
+        self.cycles_per_frame = if faster {
+            self.cycles_per_frame + step
+        } else {
+            cmp::max(1, self.cycles_per_frame - step)
+        };
+        self.debugger.cycles_per_frame = self.cycles_per_frame;
+        self.frame_cycles = cmp::min(self.frame_cycles, self.cycles_per_frame - 1);
+    }
+
+    // The window title, with the current speed.
+    fn title(&self) -> String {
+        let speed = if self.paused {
+            "paused".to_string()
+        } else {
+            let frames = if self.turbo { TURBO_SPEED } else { 1 };
+            format!("{} instructions per frame, {} Hz{}", self.cycles_per_frame,
+                    self.cycles_per_frame * 60 * frames, if self.turbo { ", fast forward" } else { "" })
+        };
+        format!("chip8 emulator - {}", speed)
+    }
+
+    // Reads debugger commands from the terminal. The window stops responding
+    // until the console is told to continue.
+    fn open_console(&mut self) {
+        self.update_sound(SoundState::silent());
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        match self.debugger.console(&mut self.cpu, stdin.lock(), stdout.lock()) {
+            Ok(ConsoleExit::Continue) => { }
+            Ok(ConsoleExit::Quit) => {
+                self.stop_recording();
+                self.stop_capture();
                 process::exit(0);
             }
+            Err(e) => println!("Error in debugger console: {}", e),
         }
     }
 
-    fn on_update(&mut self) {
-        self.cpu.emulate_cycle();
+    // Adds the display to the screen, if it has changed or the filter needs
+    // every frame.
+    fn update_screen(&mut self) {
+        let dirty = self.cpu.take_dirty().is_some();
+        if dirty || self.screen.filter != render::Filter::None {
+            self.screen.push(&self.cpu);
+        }
+    }
+
+    // Called at the end of each frame, the CHIP-8's vertical blank. The
+    // filters and --vblank draw what the display was then, and otherwise it's
+    // drawn as it is whenever the window is.
+    fn end_frame_screen(&mut self) {
+        if self.vblank || self.screen.filter != render::Filter::None {
+            self.update_screen();
+        }
     }
 
-    fn on_draw<E: GenericEvent>(&mut self, w: &mut PistonWindow, e: &E) {
-        let black: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
-        let white: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
-        let square = rectangle::square(0.0, 0.0, 10.0);
+    // Uploads the screen to the texture if it has changed since the last time.
+    fn update_texture(&mut self, w: &mut PistonWindow) -> Result<(), String> {
+        let size = self.screen.size();
+        if !self.screen.take_changed() && self.texture.as_ref().map_or(false, |texture| texture.1 == size) {
+            return Ok(());
+        }
+        let image = im::RgbaImage::from_raw(size.0 as u32, size.1 as u32, self.screen.rgba(&self.palette)).unwrap();
+        if let Some((ref mut texture, texture_size)) = self.texture {
+            if texture_size == size {
+                return texture.update(&mut w.encoder, &image).map_err(|e| format!("{:?}", e));
+            }
+        }
+        // Nearest keeps the pixels sharp when they're scaled up.
+        let settings = TextureSettings::new().filter(Filter::Nearest);
+        let texture = Texture::from_image(&mut w.factory, &image, &settings).map_err(|e| format!("{:?}", e))?;
+        self.texture = Some((texture, size));
+        Ok(())
+    }
+
+    fn on_draw<E: GenericEvent>(&mut self, w: &mut PistonWindow, e: &E, args: &RenderArgs) {
+        if !self.vblank && self.screen.filter == render::Filter::None {
+            self.update_screen();
+        }
+        if let Err(e) = self.update_texture(w) {
+            println!("Error uploading the display: {}", e);
+            self.texture = None;
+            return;
+        }
+        // The display is drawn as one image, scaled to fit the window, so
+        // pixels get smaller in hires mode.
+        let (width, height) = self.screen.size();
+        let view = Viewport::fit(args.width as f64, args.height as f64, width, height, self.integer_scale);
+        let texture = &self.texture.as_ref().unwrap().0;
+        let grid = self.grid;
 
         w.draw_2d(e, |c, g| {
-            clear(black, g);
-            for (i, row) in self.cpu.disp_buff.iter().enumerate() {
-                for (ii, &pixel) in row.iter().enumerate() {
-                    let pixel_color;
-                    if pixel {
-                        pixel_color = white;
-                    } else {
-                        pixel_color = black;
-                    }
-
-                    let pix_loc = c.transform.trans((ii * 10) as f64, (i * 10) as f64);
-
-                    rectangle(pixel_color, square, pix_loc, g);
+            // The bars around the display are drawn in the background colour.
+            clear(self.palette.rgba(0), g);
+            let origin = c.transform.trans(view.x, view.y);
+            image(texture, origin.scale(view.pixel, view.pixel), g);
+            if grid {
+                let color = [0.5, 0.5, 0.5, 0.5];
+                let (right, bottom) = (width as f64 * view.pixel, height as f64 * view.pixel);
+                for column in 0 .. width + 1 {
+                    let x = column as f64 * view.pixel;
+                    line(color, 0.5, [x, 0.0, x, bottom], origin, g);
+                }
+                for row in 0 .. height + 1 {
+                    let y = row as f64 * view.pixel;
+                    line(color, 0.5, [0.0, y, right, y], origin, g);
                 }
             }
         });
     }
 
+    // Screenshots are saved next to the ROM, numbered from 1.
+    fn save_screenshot(&self) {
+        let path = (1 ..).map(|n| format!("{}.shot{}.png", self.rom_path, n))
+            .find(|path| !Path::new(path).exists()).unwrap();
+        let png = render::screenshot(&self.cpu, self.scale, &self.palette);
+        match File::create(&path).and_then(|mut f| f.write_all(&png)) {
+            Ok(()) => println!("Saved screenshot to {}.", path),
+            Err(e) => println!("Error writing {}: {}", path, e),
+        }
+    }
+
+    // Save states are kept next to the ROM, in one file per slot.
+    fn slot_path(&self, slot: u32) -> String {
+        format!("{}.state{}", self.rom_path, slot)
+    }
+
+    fn save_slot(&self, slot: u32) {
+        let path = self.slot_path(slot);
+        match File::create(&path).and_then(|mut f| f.write_all(&self.cpu.save_state())) {
+            Ok(()) => println!("Saved state to slot {}.", slot),
+            Err(e) => println!("Error writing {}: {}", path, e),
+        }
+    }
+
+    fn load_slot(&mut self, slot: u32) {
+        let path = self.slot_path(slot);
+        let mut data = Vec::new();
+        if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_end(&mut data)) {
+            println!("Error reading {}: {}", path, e);
+            return;
+        }
+        match self.cpu.load_state(&data) {
+            Ok(()) => {
+                self.rewind.clear();
+                self.frame_cycles = 0;
+                self.screen.clear();
+                self.screen.push(&self.cpu);
+                println!("Loaded state from slot {}.", slot);
+            }
+            Err(e) => println!("Error loading slot {}: {}", slot, e),
+        }
+    }
+
+    // F1 to F9 load the state in slots 1 to 9, and save to them with shift held.
+    fn on_slot_key(&mut self, key: Key) -> bool {
+        let slot = match key {
+            Key::F1 => 1, Key::F2 => 2, Key::F3 => 3,
+            Key::F4 => 4, Key::F5 => 5, Key::F6 => 6,
+            Key::F7 => 7, Key::F8 => 8, Key::F9 => 9,
+            _ => return false
+        };
+        if self.shift_held {
+            self.save_slot(slot);
+        } else if self.movie_active() {
+            println!("Loading states is turned off while a movie is recording or playing.");
+        } else {
+            self.load_slot(slot);
+        }
+        true
+    }
+
+    // Hotkeys are handled before the keymap. Keys used without ctrl have to be
+    // in keymap::RESERVED_KEYS, so keymaps can't bind them.
     fn on_input(&mut self, ba: &ButtonArgs) {
         let state = ba.state == ButtonState::Press;
+        if let Button::Keyboard(key) = ba.button {
+            if key == Key::LShift || key == Key::RShift {
+                self.shift_held = state;
+                return;
+            }
+            if key == Key::LCtrl || key == Key::RCtrl {
+                self.ctrl_held = state;
+                return;
+            }
+            if key == Key::Pause {
+                if state {
+                    self.toggle_pause();
+                }
+                return;
+            }
+            // Ctrl+P pauses, Ctrl+N and Ctrl+I advance a frame or an
+            // instruction while paused, Ctrl+T fast forwards, Ctrl+= and
+            // Ctrl+- change the instructions per frame, Ctrl+G shows the pixel
+            // grid, and Ctrl+S saves a screenshot, as PrintScreen does. Releases still go to
+            // the keypad, so keys held before Ctrl aren't stuck down.
+            if self.ctrl_held && state {
+                match key {
+                    Key::P => self.toggle_pause(),
+                    Key::N if self.paused => self.advance = Some(Advance::Frame),
+                    Key::I if self.paused => self.advance = Some(Advance::Instruction),
+                    Key::T => self.turbo = !self.turbo,
+                    Key::Equals | Key::NumPadPlus => self.change_speed(true),
+                    Key::Minus | Key::NumPadMinus => self.change_speed(false),
+                    Key::G => self.grid = !self.grid,
+                    Key::S => self.save_screenshot(),
+                    _ => { }
+                }
+                return;
+            }
+            if key == Key::Backspace {
+                if state && self.movie_active() {
+                    println!("Rewinding is turned off while a movie is recording or playing.");
+                    return;
+                }
+                self.rewinding = state;
+                return;
+            }
+            if key == Key::F12 {
+                self.break_requested |= state;
+                return;
+            }
+            if key == Key::PrintScreen {
+                if state {
+                    self.save_screenshot();
+                }
+                return;
+            }
+            if key == Key::F10 {
+                if state {
+                    self.toggle_capture();
+                }
+                return;
+            }
+            if key == Key::F11 {
+                if state {
+                    self.fullscreen = !self.fullscreen;
+                }
+                return;
+            }
+            if key == Key::M {
+                if state {
+                    self.toggle_mute();
+                }
+                return;
+            }
+            if state && self.on_slot_key(key) {
+                return;
+            }
+        }
         match ba.button {
-            Button::Keyboard(Key::D1) => { self.cpu.key_buff[1] = state }
-            Button::Keyboard(Key::D2) => { self.cpu.key_buff[2] = state }
-            Button::Keyboard(Key::D3) => { self.cpu.key_buff[3] = state }
-            Button::Keyboard(Key::D4) => { self.cpu.key_buff[0xC] = state }
-            Button::Keyboard(Key::Q) => { self.cpu.key_buff[4] = state }
-            Button::Keyboard(Key::W) => { self.cpu.key_buff[5] = state }
-            Button::Keyboard(Key::E) => { self.cpu.key_buff[6] = state }
-            Button::Keyboard(Key::R) => { self.cpu.key_buff[0xD] = state }
-            Button::Keyboard(Key::A) => { self.cpu.key_buff[7] = state }
-            Button::Keyboard(Key::S) => { self.cpu.key_buff[8] = state }
-            Button::Keyboard(Key::D) => { self.cpu.key_buff[9] = state }
-            Button::Keyboard(Key::F) => { self.cpu.key_buff[0xE] = state }
-            Button::Keyboard(Key::Z) => { self.cpu.key_buff[0xA] = state }
-            Button::Keyboard(Key::X) => { self.cpu.key_buff[0] = state }
-            Button::Keyboard(Key::C) => { self.cpu.key_buff[0xB] = state }
-            Button::Keyboard(Key::V) => { self.cpu.key_buff[0xF] = state }
+            Button::Keyboard(key) => self.set_held(key_name(key), state),
+            Button::Controller(button) => {
+                let name = keymap::button_name(button.button);
+                if state && self.keymap.lookup(&name).is_none()
+                    && self.unbound_reported.insert((button.id, button.button)) {
+                    // Controllers number their buttons differently, so say
+                    // what this one is called to make it easy to bind.
+                    println!("{} on controller {} isn't bound to a key.", name, button.id);
+                }
+                self.set_held(name, state);
+            }
             _ => { }
         }
     }
+
+    // Sticks press a key once pushed past the keymap's dead zone.
+    fn on_axis(&mut self, axis: &ControllerAxisArgs) {
+        for &(ref name, down) in keymap::axis_inputs(axis.axis, axis.position, self.keymap.dead_zone).iter() {
+            self.set_held(name.clone(), down);
+        }
+    }
+
+    // Marks a host key or controller input as held or let go, and works out
+    // the keypad from everything held.
+    fn set_held(&mut self, name: String, state: bool) {
+        if self.keymap.lookup(&name).is_none() {
+            return;
+        }
+        if state {
+            self.held.insert(name);
+        } else {
+            self.held.remove(&name);
+        }
+        self.keys = self.keymap.keypad(&self.held);
+    }
 }
 
+// The name a keymap uses for a piston key: its variant name, without the `D`
+// in front of the number keys.
+fn key_name(key: Key) -> String {
+    let name = format!("{:?}", key);
+    if name.len() == 2 && name.starts_with('D') {
+        name[1 ..].to_string()
+    } else {
+        name
+    }
+}
 
-fn main() {
+// The sound, shared with the audio thread: the generator, and what the last
+// frame wants played.
+struct Speaker {
+    generator: ToneGenerator,
+    sound: SoundState
+}
+
+// Copies mono samples to every channel of an output buffer.
+fn write_samples<T: cpal::Sample>(out: &mut [T], mono: &[f32], channels: usize) {
+    for (frame, sample) in out.chunks_mut(channels).zip(mono) {
+        for out in frame.iter_mut() {
+            *out = T::from(sample);
+        }
+    }
+}
 
-    let mut machine = Machine::new();
-    machine.load_rom();
+// Starts playing sound on the default output device, on its own thread.
+// Returns None if there's no device to play on, and the emulator runs silently.
+fn start_audio() -> Option<Arc<Mutex<Speaker>>> {
+    let device = match cpal::default_output_device() {
+        Some(device) => device,
+        None => {
+            println!("No audio device found, sound is off.");
+            return None;
+        }
+    };
+    let event_loop = cpal::EventLoop::new();
+    let stream = device.default_output_format().map_err(|e| e.to_string())
+        .and_then(|format| {
+            event_loop.build_output_stream(&device, &format).map(|stream| (stream, format)).map_err(|e| e.to_string())
+        });
+    let (stream, format) = match stream {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!("Error opening audio device: {}. Sound is off.", e);
+            return None;
+        }
+    };
+
+    let speaker = Arc::new(Mutex::new(Speaker {
+        generator: ToneGenerator::new(format.sample_rate.0),
+        sound: SoundState::silent()
+    }));
+    let shared = speaker.clone();
+    let channels = format.channels as usize;
+    thread::spawn(move || {
+        event_loop.play_stream(stream);
+        let mut mono = Vec::new();
+        event_loop.run(move |_, data| {
+            let buffer = match data {
+                cpal::StreamData::Output { buffer } => buffer,
+                _ => return
+            };
+            let len = match buffer {
+                cpal::UnknownTypeOutputBuffer::U16(ref b) => b.len(),
+                cpal::UnknownTypeOutputBuffer::I16(ref b) => b.len(),
+                cpal::UnknownTypeOutputBuffer::F32(ref b) => b.len()
+            };
+            mono.resize(len / channels, 0.0);
+            {
+                let mut speaker = shared.lock().unwrap();
+                let sound = speaker.sound;
+                speaker.generator.fill(&sound, &mut mono);
+            }
+            match buffer {
+                cpal::UnknownTypeOutputBuffer::U16(mut b) => write_samples(&mut b, &mono, channels),
+                cpal::UnknownTypeOutputBuffer::I16(mut b) => write_samples(&mut b, &mono, channels),
+                cpal::UnknownTypeOutputBuffer::F32(mut b) => write_samples(&mut b, &mono, channels)
+            }
+        });
+    });
+    Some(speaker)
+}
+
+// Prints an error and exits with `code`.
+fn fail(message: &str, code: i32) -> ! {
+    eprintln!("{}", message);
+    process::exit(code);
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    let mut data = Vec::new();
+    if let Err(e) = File::open(path).and_then(|mut f| f.read_to_end(&mut data)) {
+        fail(&format!("Error reading {}: {}", path, e), cli::EXIT_ERROR);
+    }
+    data
+}
+
+fn read_text(path: &str) -> String {
+    String::from_utf8_lossy(&read_file(path)).into_owned()
+}
+
+// `chip8 asm program.asm program.ch8` assembles a ROM.
+fn assemble_rom(source: &str, rom: &str) {
+    let rom_data = match asm::assemble_file(Path::new(source)) {
+        Ok(rom_data) => rom_data,
+        Err(e) => fail(&e.to_string(), cli::EXIT_ERROR)
+    };
+    if let Err(e) = File::create(rom).and_then(|mut f| f.write_all(&rom_data)) {
+        fail(&format!("Error writing {}: {}", rom, e), cli::EXIT_ERROR);
+    }
+}
+
+// Saves the display for --screenshot, at the end of a run.
+fn write_screenshot(cpu: &Cpu, options: &RunOptions) {
+    if let Some(ref path) = options.screenshot {
+        let png = render::screenshot(cpu, options.scale, &options.palette);
+        if let Err(e) = File::create(path).and_then(|mut f| f.write_all(&png)) {
+            fail(&format!("Error writing {}: {}", path, e), cli::EXIT_ERROR);
+        }
+    }
+}
 
-    let mut window: PistonWindow =
-        WindowSettings::new("chip8 emulator", (640, 320))
-        .exit_on_esc(true)
-        .build()
-        .unwrap();
+// `chip8 run --headless` runs the ROM without a window, the way chip8-headless
+// does.
+fn run_headless(mut cpu: Cpu, options: &RunOptions, movie: Option<Movie>) {
+    let report = headless::run_options(options, &mut cpu, movie.as_ref())
+        .unwrap_or_else(|e| fail(&e, cli::EXIT_ERROR));
+    eprintln!("{}", report.summary());
+    process::exit(report.outcome.exit_code());
+}
+
+// Opens the window, `size` across when it isn't fullscreen.
+fn open_window(size: (u32, u32), fullscreen: bool) -> Result<PistonWindow, String> {
+    let mut window: PistonWindow = WindowSettings::new("chip8 emulator", size)
+        .exit_on_esc(true).resizable(true).fullscreen(fullscreen).build()?;
+    // One update per 60Hz frame, so the timers run at the right speed. The
+    // display only changes between updates, so drawing more often than that
+    // would only take time from them.
+    window.set_ups(60);
+    window.set_max_fps(60);
+    Ok(window)
+}
+
+fn run(options: RunOptions) {
+    // A movie is played back on a cpu set up the way it was recorded.
+    let movie = options.read_movie().unwrap_or_else(|e| fail(&e, cli::EXIT_ERROR));
+    let cpu = options.load_cpu(movie.as_ref()).unwrap_or_else(|e| fail(&e, cli::EXIT_ERROR));
+    let cycles_per_frame = movie.as_ref().map_or(options.cycles_per_frame, |movie| movie.cycles_per_frame);
+    if options.headless {
+        run_headless(cpu, &options, movie);
+        return;
+    }
+
+    println!("Loaded {} ({:016X}).", options.rom, cpu.rom_hash());
+    let mut machine = Machine::new(cpu, cycles_per_frame as usize);
+    machine.rom_path = options.rom.clone();
+    machine.palette = options.palette;
+    machine.scale = options.scale;
+    if let Some(ref path) = options.capture {
+        machine.start_capture(path);
+    }
+    machine.integer_scale = options.integer_scale;
+    machine.grid = options.grid;
+    machine.screen = Screen::new(options.filter);
+    machine.screen.push(&machine.cpu);
+    machine.vblank = options.vblank;
+    machine.fullscreen = options.fullscreen;
+    if let Some(movie) = movie {
+        machine.player = Some(Player::new(movie));
+    }
+    if let Some(ref path) = options.record {
+        let recorder = Recorder::new(&machine.cpu, machine.cycles_per_frame as u64);
+        machine.recorder = Some((recorder, path.clone()));
+    }
+
+    let config = options.keymap.as_ref().map(|path| {
+        KeymapConfig::parse(&read_text(path)).unwrap_or_else(|e| {
+            fail(&format!("Error in keymap {}: {}", path, e), cli::EXIT_ERROR)
+        })
+    });
+    let rom_name = Path::new(&options.rom).file_name()
+        .map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let layout = options.layout.as_ref().map(|layout| layout.as_str());
+    machine.keymap = match config {
+        Some(config) => config.keymap_for(&rom_name, machine.cpu.rom_hash(), layout),
+        None => layout.and_then(Keymap::layout).unwrap_or_default()
+    };
+
+    machine.speaker = start_audio();
+    if let Some(ref speaker) = machine.speaker {
+        options.apply_sound(&mut speaker.lock().unwrap().generator);
+    }
+
+    let size = (64 * options.scale, 32 * options.scale);
+    let mut fullscreen = options.fullscreen;
+    let mut window = open_window(size, fullscreen).unwrap_or_else(|e| {
+        fail(&format!("Error opening a window: {}", e), cli::EXIT_ERROR)
+    });
+    let mut title = String::new();
     while let Some(e) = window.next() {
-        if let Some(_r) = e.render_args() {
-            machine.on_draw(&mut window, &e);
+        // Piston can't switch a window to fullscreen, so a new one is opened.
+        if machine.fullscreen != fullscreen {
+            match open_window(size, machine.fullscreen) {
+                Ok(new_window) => {
+                    window = new_window;
+                    fullscreen = machine.fullscreen;
+                    title.clear();
+                    // The texture belonged to the old window's context, so
+                    // it's made again, from the whole screen, on the next draw.
+                    machine.texture = None;
+                }
+                Err(e) => {
+                    println!("Error opening a window: {}", e);
+                    machine.fullscreen = fullscreen;
+                }
+            }
+        }
+        if machine.title() != title {
+            title = machine.title();
+            window.set_title(title.clone());
+        }
+        if let Some(r) = e.render_args() {
+            machine.on_draw(&mut window, &e, &r);
         }
         if let Some(_u) = e.update_args() {
             machine.on_update();
@@ -124,6 +850,26 @@ fn main() {
         if let Some(b) = e.button_args() {
             machine.on_input(&b);
         }
+        if let Some(a) = e.controller_axis_args() {
+            machine.on_axis(&a);
+        }
     }
+    machine.stop_recording();
+    machine.stop_capture();
+    write_screenshot(&machine.cpu, &options);
 }
 
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = match cli::parse(&args) {
+        Ok(command) => command,
+        Err(e) => fail(&format!("chip8: {}\nRun `chip8 --help` for the options.", e), cli::EXIT_BAD_ARGUMENTS)
+    };
+    match command {
+        Command::Help => println!("{}", cli::USAGE),
+        Command::Disasm { rom, start } => print!("{}", disasm::disassemble(&read_file(&rom), start)),
+        Command::Inspect { rom, start } => print!("{}", disasm::inspect(&read_file(&rom), start)),
+        Command::Asm { source, rom } => assemble_rom(&source, &rom),
+        Command::Run(options) => run(options)
+    }
+}
@@ -0,0 +1,285 @@
+use std::fmt::Write;
+use cpu::{Cpu, FONT_END, MEMORY_SIZE};
+use headless::{KeyEvent, KeyScript};
+use quirks::Quirks;
+
+// The first line of every movie file.
+const MOVIE_HEADER: &'static str = "chip8-movie 1";
+
+// A recording of every change to the keypad, by frame, from the moment a ROM
+// was loaded. Together with the ROM, where it was loaded, seed, quirks and
+// instructions per frame, that is everything needed to replay a run exactly.
+//
+// Movies are text, so they can be attached to bug reports and edited by hand:
+//
+//   chip8-movie 1
+//   rom 1F2E3D4C5B6A7988
+//   start 0x200
+//   seed 12345
+//   rng 0
+//   quirks 0x03
+//   ipf 10
+//   frames 600
+//   120 5 down
+//   130 5 up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_hash: u64,
+    // The address the ROM was loaded at. Movies from before this was recorded
+    // don't have it, and were all loaded at 0x200.
+    pub start: usize,
+    pub seed: u64,
+    // The kind of RandomSource used, from RandomSource::kind().
+    pub rng: u8,
+    pub quirks: Quirks,
+    pub cycles_per_frame: u64,
+    // How many frames long the recording is.
+    pub frames: u64,
+    pub keys: KeyScript,
+}
+
+impl Movie {
+    pub fn parse(text: &str) -> Result<Movie, String> {
+        let mut lines = text.lines().enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|&(_, line)| !line.is_empty() && !line.starts_with('#'));
+        match lines.next() {
+            Some((_, MOVIE_HEADER)) => { }
+            _ => return Err(format!("not a movie, the first line should be {:?}", MOVIE_HEADER)),
+        }
+
+        let mut movie = Movie {
+            rom_hash: 0, start: 0x200, seed: 0, rng: 0, quirks: Quirks::default(),
+            cycles_per_frame: 0, frames: 0, keys: KeyScript::new(),
+        };
+        let mut seen = Vec::new();
+        for (number, line) in lines {
+            if let Some(event) = KeyEvent::parse(line) {
+                movie.keys.add(event);
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let (name, value) = match (words.next(), words.next(), words.next()) {
+                (Some(name), Some(value), None) => (name, value),
+                _ => return Err(format!("line {}: expected a setting or <frame> <key> down|up", number)),
+            };
+            let bad_value = || format!("line {}: bad value for {}", number, name);
+            match name {
+                "rom" => movie.rom_hash = u64::from_str_radix(value, 16).map_err(|_| bad_value())?,
+                "start" => {
+                    let start = usize::from_str_radix(value.trim_start_matches("0x"), 16).map_err(|_| bad_value())?;
+                    if start < FONT_END || start >= MEMORY_SIZE {
+                        return Err(bad_value());
+                    }
+                    movie.start = start;
+                }
+                "seed" => movie.seed = value.parse().map_err(|_| bad_value())?,
+                "rng" => movie.rng = value.parse().map_err(|_| bad_value())?,
+                "quirks" => {
+                    let bits = u8::from_str_radix(value.trim_start_matches("0x"), 16).map_err(|_| bad_value())?;
+                    movie.quirks = Quirks::from_bits(bits);
+                }
+                "ipf" => movie.cycles_per_frame = value.parse().map_err(|_| bad_value())?,
+                "frames" => movie.frames = value.parse().map_err(|_| bad_value())?,
+                _ => return Err(format!("line {}: unknown setting {}", number, name)),
+            }
+            seen.push(name);
+        }
+        for name in &["rom", "seed", "ipf", "frames"] {
+            if !seen.contains(name) {
+                return Err(format!("movie is missing its {} setting", name));
+            }
+        }
+        Ok(movie)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        writeln!(text, "{}", MOVIE_HEADER).unwrap();
+        writeln!(text, "rom {:016X}", self.rom_hash).unwrap();
+        writeln!(text, "start 0x{:03X}", self.start).unwrap();
+        writeln!(text, "seed {}", self.seed).unwrap();
+        writeln!(text, "rng {}", self.rng).unwrap();
+        writeln!(text, "quirks 0x{:02X}", self.quirks.to_bits()).unwrap();
+        writeln!(text, "ipf {}", self.cycles_per_frame).unwrap();
+        writeln!(text, "frames {}", self.frames).unwrap();
+        for event in self.keys.events() {
+            writeln!(text, "{}", event).unwrap();
+        }
+        text
+    }
+
+    // Checks the movie was recorded with this ROM, loaded at the same address,
+    // and with this seed, generator and quirks. Any cpu made with
+    // Cpu::with_seed(movie.quirks, movie.seed) will do, once the ROM is loaded
+    // at movie.start.
+    pub fn check(&self, cpu: &Cpu) -> Result<(), String> {
+        if cpu.rom_hash() != self.rom_hash {
+            return Err(format!("movie was recorded with ROM {:016X}, but ROM {:016X} is loaded",
+                               self.rom_hash, cpu.rom_hash()));
+        }
+        if cpu.rom_start() != self.start {
+            return Err(format!("movie was recorded with the ROM loaded at 0x{:03X}, but it's loaded at 0x{:03X}",
+                               self.start, cpu.rom_start()));
+        }
+        if cpu.seed() != self.seed || cpu.rng_kind() != self.rng {
+            return Err("movie was recorded with a different random number generator".to_string());
+        }
+        if cpu.quirks() != self.quirks {
+            return Err("movie was recorded with different quirks".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Records a movie. Start it straight after loading the ROM, and call
+// record_frame() at the start of every frame with the keys that are held.
+pub struct Recorder {
+    movie: Movie,
+    keys: [bool; 16],
+}
+
+impl Recorder {
+    pub fn new(cpu: &Cpu, cycles_per_frame: u64) -> Recorder {
+        Recorder {
+            movie: Movie {
+                rom_hash: cpu.rom_hash(),
+                start: cpu.rom_start(),
+                seed: cpu.seed(),
+                rng: cpu.rng_kind(),
+                quirks: cpu.quirks(),
+                cycles_per_frame: cycles_per_frame,
+                frames: 0,
+                keys: KeyScript::new(),
+            },
+            keys: [false; 16],
+        }
+    }
+
+    pub fn record_frame(&mut self, keys: &[bool; 16]) {
+        for key in 0 .. 16 {
+            if keys[key] != self.keys[key] {
+                self.movie.keys.add(KeyEvent { frame: self.movie.frames, key: key, down: keys[key] });
+            }
+        }
+        self.keys = *keys;
+        self.movie.frames += 1;
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+// Plays a movie back. Call apply_frame() at the start of every frame instead
+// of applying the player's input.
+pub struct Player {
+    movie: Movie,
+    frame: u64,
+}
+
+impl Player {
+    pub fn new(movie: Movie) -> Player {
+        Player { movie: movie, frame: 0 }
+    }
+
+    // Sets the keypad for the next frame. Returns false, without changing
+    // anything, once the movie has ended.
+    pub fn apply_frame(&mut self, cpu: &mut Cpu) -> bool {
+        if self.is_finished() {
+            return false;
+        }
+        self.movie.keys.apply(self.frame, cpu);
+        self.frame += 1;
+        true
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Waits for a key, then ANDs a random number into V1 and draws it, for ever.
+    const PROGRAM: [u8; 10] = [0xF0, 0x0A, 0xC1, 0xFF, 0xF1, 0x29, 0xD0, 0x05, 0x12, 0x00];
+
+    fn cpu_with_seed(seed: u64) -> Cpu {
+        let mut cpu = Cpu::with_seed(Quirks::default(), seed);
+        Cpu::load_data(&mut cpu, PROGRAM.to_vec());
+        cpu
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let mut movie = Recorder::new(&cpu_with_seed(99), 10).finish();
+        movie.frames = 20;
+        movie.keys.press(3, 0xA, 5);
+        let text = movie.to_text();
+        assert!(text.contains("\n3 A down\n8 A up\n"));
+        assert_eq!(Movie::parse(&text), Ok(movie));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Movie::parse("hello").is_err());
+        assert_eq!(Movie::parse("chip8-movie 1\nrom 12\nseed 1\nipf 10"),
+                   Err("movie is missing its frames setting".to_string()));
+        assert_eq!(Movie::parse("chip8-movie 1\nipf ten"), Err("line 2: bad value for ipf".to_string()));
+        assert_eq!(Movie::parse("chip8-movie 1\nstart 0x10"), Err("line 2: bad value for start".to_string()));
+    }
+
+    #[test]
+    fn test_replay_matches_recording() {
+        let mut cpu = cpu_with_seed(1234);
+        let mut recorder = Recorder::new(&cpu, 10);
+        let mut keys = [false; 16];
+        for frame in 0 .. 60 {
+            keys[5] = frame % 20 < 3;
+            keys[0xC] = frame > 40;
+            for key in 0 .. 16 {
+                cpu.set_key(key, keys[key]);
+            }
+            recorder.record_frame(&keys);
+            cpu.run_frame(10).unwrap();
+        }
+        let recorded = cpu.save_state();
+        let movie = Movie::parse(&recorder.finish().to_text()).unwrap();
+
+        let mut replay = cpu_with_seed(movie.seed);
+        movie.check(&replay).unwrap();
+        let mut player = Player::new(movie);
+        while player.apply_frame(&mut replay) {
+            replay.run_frame(10).unwrap();
+        }
+        assert_eq!(replay.save_state(), recorded);
+    }
+
+    #[test]
+    fn test_check_rejects_other_seed() {
+        let movie = Recorder::new(&cpu_with_seed(1), 10).finish();
+        assert!(movie.check(&cpu_with_seed(2)).is_err());
+        assert!(movie.check(&cpu_with_seed(1)).is_ok());
+    }
+
+    #[test]
+    fn test_start_address() {
+        let mut cpu = Cpu::with_seed(Quirks::default(), 1);
+        Cpu::load_data_at(&mut cpu, PROGRAM.to_vec(), 0x600).unwrap();
+        let movie = Recorder::new(&cpu, 10).finish();
+        assert!(movie.to_text().contains("\nstart 0x600\n"));
+        assert_eq!(Movie::parse(&movie.to_text()).unwrap().start, 0x600);
+        assert!(movie.check(&cpu).is_ok());
+        assert!(movie.check(&cpu_with_seed(1)).is_err());
+
+        // Older movies, without a start, were loaded at 0x200.
+        let old = Movie::parse("chip8-movie 1\nrom 12\nseed 1\nipf 10\nframes 0").unwrap();
+        assert_eq!(old.start, 0x200);
+    }
+}
@@ -0,0 +1,139 @@
+// The colours the display is drawn in. Pixels hold one bit per XO-CHIP
+// bitplane, so there is a colour for each of the four values a pixel can have:
+// off, plane 1, plane 2 and both planes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    pub colors: [[u8; 3]; 4],
+}
+
+impl Palette {
+    // A palette of two colours, with XO-CHIP's second plane, and both planes
+    // together, in shades between them.
+    pub fn new(background: [u8; 3], foreground: [u8; 3]) -> Palette {
+        Palette {
+            colors: [background, foreground, mix(background, foreground, 0.6), mix(background, foreground, 0.3)],
+        }
+    }
+
+    // Looks up one of the built-in palettes, listed in PALETTES.
+    pub fn from_name(name: &str) -> Option<Palette> {
+        match name.to_lowercase().as_str() {
+            "default" => Some(Palette::default()),
+            "inverted" => Some(Palette::new([0xFF, 0xFF, 0xFF], [0x00, 0x00, 0x00])),
+            // The P1 phosphor of early green screen terminals.
+            "green" => Some(Palette::new([0x00, 0x14, 0x00], [0x33, 0xFF, 0x33])),
+            // The P3 phosphor of amber monitors.
+            "amber" => Some(Palette::new([0x14, 0x0A, 0x00], [0xFF, 0xB0, 0x00])),
+            // The greenish LCD of an original Game Boy.
+            "lcd" => Some(Palette {
+                colors: [[0x9B, 0xBC, 0x0F], [0x0F, 0x38, 0x0F], [0x30, 0x62, 0x30], [0x8B, 0xAC, 0x0F]],
+            }),
+            _ => None,
+        }
+    }
+
+    // Parses a palette given on the command line: the name of a built-in one,
+    // or two to four colours as hex, separated by commas, starting with the
+    // background.
+    pub fn parse(text: &str) -> Result<Palette, String> {
+        if let Some(palette) = Palette::from_name(text) {
+            return Ok(palette);
+        }
+        if !text.contains(',') {
+            return Err(format!("unknown palette {:?}, expected one of {} or colours like #000000,#33FF66",
+                               text, PALETTES.join(", ")));
+        }
+        let mut colors = Vec::new();
+        for part in text.split(',') {
+            colors.push(parse_color(part.trim()).ok_or(format!("{:?} isn't a colour like #33FF66", part))?);
+        }
+        let mut palette = match colors.len() {
+            2 | 3 | 4 => Palette::new(colors[0], colors[1]),
+            _ => return Err("a palette needs two to four colours, starting with the background".to_string()),
+        };
+        for (index, &color) in colors.iter().enumerate().skip(2) {
+            palette.colors[index] = color;
+        }
+        Ok(palette)
+    }
+
+    // The colour of a pixel value at `brightness`, from 0 for the background
+    // to 1 for the full colour.
+    pub fn shade(&self, pixel: u8, brightness: f32) -> [u8; 3] {
+        mix(self.colors[0], self.colors[pixel as usize & 3], brightness)
+    }
+
+    // The colour of a pixel value, as RGBA from 0 to 1.
+    pub fn rgba(&self, pixel: u8) -> [f32; 4] {
+        let color = self.colors[pixel as usize & 3];
+        [color[0] as f32 / 255.0, color[1] as f32 / 255.0, color[2] as f32 / 255.0, 1.0]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::new([0x00, 0x00, 0x00], [0xFF, 0xFF, 0xFF])
+    }
+}
+
+// The names accepted by Palette::from_name().
+pub const PALETTES: [&'static str; 5] = ["default", "inverted", "green", "amber", "lcd"];
+
+// Parses `#RRGGBB`, `RRGGBB` or `#RGB`.
+fn parse_color(text: &str) -> Option<[u8; 3]> {
+    let hex = text.trim_start_matches('#');
+    if !hex.chars().all(|c| c.is_digit(16)) {
+        return None;
+    }
+    let channel = |index: usize, len: usize| {
+        let value = u8::from_str_radix(&hex[index * len .. (index + 1) * len], 16).unwrap();
+        // A single digit is repeated, so F is FF.
+        if len == 1 { value * 0x11 } else { value }
+    };
+    match hex.len() {
+        6 => Some([channel(0, 2), channel(1, 2), channel(2, 2)]),
+        3 => Some([channel(0, 1), channel(1, 1), channel(2, 1)]),
+        _ => None,
+    }
+}
+
+// Blends `amount` of the way from one colour to another.
+fn mix(from: [u8; 3], to: [u8; 3], amount: f32) -> [u8; 3] {
+    let blend = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * amount).round() as u8;
+    [blend(from[0], to[0]), blend(from[1], to[1]), blend(from[2], to[2])]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default() {
+        let palette = Palette::default();
+        assert_eq!(palette.colors, [[0, 0, 0], [255, 255, 255], [153, 153, 153], [77, 77, 77]]);
+        assert_eq!(palette.rgba(1), [1.0, 1.0, 1.0, 1.0]);
+        assert_eq!(Palette::parse("DEFAULT"), Ok(palette));
+    }
+
+    #[test]
+    fn test_named() {
+        for name in PALETTES.iter() {
+            assert!(Palette::from_name(name).is_some(), "{}", name);
+        }
+        assert_eq!(Palette::parse("Amber").unwrap().colors[1], [0xFF, 0xB0, 0x00]);
+        assert!(Palette::from_name("purple").is_none());
+    }
+
+    #[test]
+    fn test_parse() {
+        let palette = Palette::parse("#102030, 0F0").unwrap();
+        assert_eq!(palette.colors[0], [0x10, 0x20, 0x30]);
+        assert_eq!(palette.colors[1], [0x00, 0xFF, 0x00]);
+        let palette = Palette::parse("000000,FFFFFF,FF0000,0000FF").unwrap();
+        assert_eq!(palette.colors[2], [0xFF, 0x00, 0x00]);
+        assert_eq!(palette.colors[3], [0x00, 0x00, 0xFF]);
+        assert!(Palette::parse("#000000").is_err());
+        assert!(Palette::parse("#000000,#GGGGGG").is_err());
+        assert!(Palette::parse("#000000,#12345").is_err());
+    }
+}
@@ -0,0 +1,135 @@
+// A small PNG encoder for screenshots. The image data isn't compressed, just
+// wrapped in zlib's stored blocks, which keeps this short and is fine for the
+// few hundred kilobytes a screenshot takes at most.
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+// The most a stored block can hold.
+const BLOCK_SIZE: usize = 0xFFFF;
+
+// Encodes an image of 8-bit RGB pixels, row by row from the top left.
+pub fn encode(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    assert_eq!(rgb.len(), width as usize * height as usize * 3, "image data doesn't match its size");
+
+    let mut header = Vec::with_capacity(13);
+    push_u32(&mut header, width);
+    push_u32(&mut header, height);
+    // 8 bits per channel, RGB, and the only compression, filter and interlace
+    // methods there are.
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    // Each row starts with its filter type, 0 for none.
+    let mut raw = Vec::with_capacity(rgb.len() + height as usize);
+    if width > 0 {
+        for row in rgb.chunks(width as usize * 3) {
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+    }
+
+    let mut out = SIGNATURE.to_vec();
+    write_chunk(&mut out, b"IHDR", &header);
+    write_chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+fn push_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&[(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]);
+}
+
+// A chunk is its length, type, data and a CRC of the type and data.
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    push_u32(out, data.len() as u32);
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start ..]);
+    push_u32(out, crc);
+}
+
+// Wraps data in a zlib stream without compressing it.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let blocks = data.len() / BLOCK_SIZE + 1;
+    let mut out = Vec::with_capacity(data.len() + blocks * 5 + 6);
+    // Deflate with a 32K window, and no preset dictionary.
+    out.extend_from_slice(&[0x78, 0x01]);
+    let mut chunks = data.chunks(BLOCK_SIZE).peekable();
+    if chunks.peek().is_none() {
+        // An empty stream still needs one final block.
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        let len = chunk.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+        out.extend_from_slice(chunk);
+    }
+    push_u32(&mut out, adler32(data));
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0 .. 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn test_encode() {
+        let png = encode(2, 1, &[255, 0, 0, 0, 0, 255]);
+        assert_eq!(&png[.. 8], &SIGNATURE);
+        // IHDR comes first, with the size.
+        assert_eq!(&png[8 .. 16], &[0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+        assert_eq!(&png[16 .. 24], &[0, 0, 0, 2, 0, 0, 0, 1]);
+        // And IEND, which is always the same, last.
+        assert_eq!(&png[png.len() - 12 ..], &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+    }
+
+    #[test]
+    fn test_stored_blocks() {
+        let data: Vec<u8> = (0 .. BLOCK_SIZE + 10).map(|i| i as u8).collect();
+        let stream = zlib_stored(&data);
+        // Undo the blocks by hand.
+        let mut unpacked = Vec::new();
+        let mut pos = 2;
+        loop {
+            let last = stream[pos] == 1;
+            let len = stream[pos + 1] as usize | (stream[pos + 2] as usize) << 8;
+            assert_eq!(len ^ 0xFFFF, stream[pos + 3] as usize | (stream[pos + 4] as usize) << 8);
+            unpacked.extend_from_slice(&stream[pos + 5 .. pos + 5 + len]);
+            pos += 5 + len;
+            if last {
+                break;
+            }
+        }
+        assert_eq!(unpacked, data);
+        assert_eq!(stream.len(), pos + 4);
+        assert_eq!(zlib_stored(&[]), vec![0x78, 0x01, 1, 0, 0, 0xFF, 0xFF, 0, 0, 0, 1]);
+    }
+}
@@ -0,0 +1,126 @@
+// Behaviours that differ between CHIP-8 interpreters. ROMs were written against
+// whichever interpreter their author had, so no single choice runs them all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    // 8xy6/8xyE shift Vy and store the result in Vx. When false, Vx is shifted
+    // in place and Vy is ignored.
+    pub shift_uses_vy: bool,
+    // Fx55/Fx65 leave I pointing just past the last register stored or loaded.
+    // When false, I is unchanged.
+    pub load_store_increments_i: bool,
+    // Bnnn jumps to nnn plus Vx, where x is the high nibble of nnn, instead of
+    // nnn plus V0.
+    pub jump_uses_vx: bool,
+    // 8xy1/8xy2/8xy3 set VF to 0.
+    pub vf_reset: bool,
+    // Sprites drawn past the edge of the screen wrap around to the other side.
+    // When false, they are clipped.
+    pub wrap_sprites: bool,
+    // Dxyn waits for the next 60Hz timer tick before drawing, limiting a program
+    // to one sprite per frame.
+    pub display_wait: bool,
+}
+
+impl Quirks {
+    // The original interpreter on the RCA COSMAC VIP.
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            vf_reset: true,
+            wrap_sprites: false,
+            display_wait: true,
+        }
+    }
+
+    // CHIP-48 on the HP-48 calculators.
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: true,
+            jump_uses_vx: true,
+            vf_reset: false,
+            wrap_sprites: false,
+            display_wait: false,
+        }
+    }
+
+    // SUPER-CHIP 1.1.
+    pub fn superchip() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: true,
+            vf_reset: false,
+            wrap_sprites: false,
+            display_wait: false,
+        }
+    }
+
+    // Packs the quirks into one bit each, for save states.
+    pub fn to_bits(&self) -> u8 {
+        (self.shift_uses_vy as u8)
+            | (self.load_store_increments_i as u8) << 1
+            | (self.jump_uses_vx as u8) << 2
+            | (self.vf_reset as u8) << 3
+            | (self.wrap_sprites as u8) << 4
+            | (self.display_wait as u8) << 5
+    }
+
+    pub fn from_bits(bits: u8) -> Quirks {
+        Quirks {
+            shift_uses_vy: bits & 1 != 0,
+            load_store_increments_i: bits & 1 << 1 != 0,
+            jump_uses_vx: bits & 1 << 2 != 0,
+            vf_reset: bits & 1 << 3 != 0,
+            wrap_sprites: bits & 1 << 4 != 0,
+            display_wait: bits & 1 << 5 != 0,
+        }
+    }
+
+    // Looks up a preset by name, as given on the command line.
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name.to_lowercase().as_str() {
+            "default" => Some(Quirks::default()),
+            "vip" | "cosmac-vip" | "chip8" => Some(Quirks::cosmac_vip()),
+            "chip48" | "chip-48" => Some(Quirks::chip48()),
+            "schip" | "superchip" | "super-chip" => Some(Quirks::superchip()),
+            _ => None,
+        }
+    }
+}
+
+// The behaviour this emulator has always had: the VIP's shifts, loads and
+// jumps, but without the VF reset or waiting for the display.
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            vf_reset: false,
+            wrap_sprites: false,
+            display_wait: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bits_round_trip() {
+        for quirks in &[Quirks::default(), Quirks::cosmac_vip(), Quirks::chip48(), Quirks::superchip()] {
+            assert_eq!(Quirks::from_bits(quirks.to_bits()), *quirks);
+        }
+    }
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(Quirks::from_name("SCHIP"), Some(Quirks::superchip()));
+        assert_eq!(Quirks::from_name("vip"), Some(Quirks::cosmac_vip()));
+        assert_eq!(Quirks::from_name("nonsense"), None);
+    }
+}
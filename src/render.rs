@@ -0,0 +1,328 @@
+// Helpers for drawing the display, kept out of the frontend so they can be
+// tested without a window.
+use std::collections::VecDeque;
+use cpu::Cpu;
+use palette::Palette;
+use png;
+
+// Where the display is drawn in a window: the top left corner, and the size of
+// one CHIP-8 pixel, all in window coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    pub x: f64,
+    pub y: f64,
+    pub pixel: f64,
+}
+
+impl Viewport {
+    // Fits a display of `columns` by `rows` pixels into a window as large as
+    // it will go without stretching it, centred, with bars on the other axis.
+    // With `integer` set, pixels are a whole number of window pixels across, so
+    // they all come out the same size.
+    pub fn fit(window_width: f64, window_height: f64, columns: usize, rows: usize, integer: bool) -> Viewport {
+        let mut pixel = (window_width / columns as f64).min(window_height / rows as f64);
+        // A window too small for whole pixels gets fractional ones rather than
+        // nothing at all.
+        if integer && pixel >= 1.0 {
+            pixel = pixel.floor();
+        }
+        Viewport {
+            x: ((window_width - pixel * columns as f64) / 2.0).floor(),
+            y: ((window_height - pixel * rows as f64) / 2.0).floor(),
+            pixel: pixel,
+        }
+    }
+}
+
+// Ways of drawing the display that hide the flicker of sprites being erased and
+// drawn again, as they often are every frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    None,
+    // Pixels lit in any of the last this many frames are drawn.
+    Blend(usize),
+    // Pixels fade out like the phosphor of a CRT, keeping this much of their
+    // brightness each frame.
+    Phosphor(f32),
+}
+
+impl Filter {
+    // Parses `none`, `blend[:FRAMES]` or `phosphor[:PERSISTENCE]`.
+    pub fn parse(text: &str) -> Result<Filter, String> {
+        let mut parts = text.splitn(2, ':');
+        let name = parts.next().unwrap_or_default().to_lowercase();
+        let value = parts.next();
+        match (name.as_str(), value) {
+            ("none", None) => Ok(Filter::None),
+            ("blend", None) => Ok(Filter::Blend(2)),
+            ("blend", Some(frames)) => {
+                frames.parse().ok().filter(|&n| n >= 1 && n <= 60).map(Filter::Blend)
+                    .ok_or(format!("blend needs a number of frames from 1 to 60, not {:?}", frames))
+            }
+            ("phosphor", None) => Ok(Filter::Phosphor(0.5)),
+            ("phosphor", Some(kept)) => {
+                kept.parse().ok().filter(|&n: &f32| n >= 0.0 && n < 1.0).map(Filter::Phosphor)
+                    .ok_or(format!("phosphor needs how much brightness is kept from 0 to 1, not {:?}", kept))
+            }
+            _ => Err(format!("unknown filter {:?}, expected none, blend[:FRAMES] or phosphor[:PERSISTENCE]", text)),
+        }
+    }
+}
+
+// A phosphor pixel this dim is treated as dark.
+const DARK: f32 = 1.0 / 64.0;
+
+// The frames the filters work from, as they were at the end of each 60Hz
+// frame, in the vertical blank. The core's display isn't changed.
+pub struct Screen {
+    pub filter: Filter,
+    width: usize,
+    height: usize,
+    // The latest frame first.
+    history: VecDeque<Vec<u8>>,
+    // For the phosphor filter, the value each pixel was last lit with, and
+    // how brightly it still glows.
+    glow: Vec<(u8, f32)>,
+    // What's drawn has changed since take_changed() was last called.
+    changed: bool,
+}
+
+impl Screen {
+    pub fn new(filter: Filter) -> Screen {
+        Screen { filter: filter, width: 0, height: 0, history: VecDeque::new(), glow: Vec::new(), changed: true }
+    }
+
+    // The width and height of the frames, in display pixels.
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    // Adds the display as it is now as the latest frame.
+    pub fn push(&mut self, cpu: &Cpu) {
+        let (width, height, frame) = display_indices(cpu, 1);
+        let (width, height) = (width as usize, height as usize);
+        // Switching resolution starts again.
+        if (width, height) != (self.width, self.height) {
+            self.clear();
+            self.width = width;
+            self.height = height;
+        }
+        if self.glow.len() != frame.len() {
+            self.glow = vec![(0, 0.0); frame.len()];
+        }
+        let kept = match self.filter { Filter::Phosphor(kept) => kept, _ => 0.0 };
+        for (glow, &pixel) in self.glow.iter_mut().zip(frame.iter()) {
+            let next = if pixel != 0 {
+                (pixel, 1.0)
+            } else if glow.1 * kept > DARK {
+                (glow.0, glow.1 * kept)
+            } else {
+                (0, 0.0)
+            };
+            self.changed |= next != *glow;
+            *glow = next;
+        }
+        // Blending frames that are all the same gives the same frame again.
+        self.changed |= self.history.is_empty() || self.history.iter().any(|old| *old != frame);
+        let frames = match self.filter { Filter::Blend(frames) => frames, _ => 1 };
+        self.history.push_front(frame);
+        self.history.truncate(frames);
+    }
+
+    pub fn clear(&mut self) {
+        self.history.clear();
+        self.glow.clear();
+        self.changed = true;
+    }
+
+    // Whether what's drawn has changed since this was last called, so it only
+    // needs drawing again when it has.
+    pub fn take_changed(&mut self) -> bool {
+        let changed = self.changed;
+        self.changed = false;
+        changed
+    }
+
+    // The value of each pixel to draw, row by row, and how brightly, from 0
+    // for not at all to 1.
+    pub fn pixels(&self) -> Vec<(u8, f32)> {
+        match self.filter {
+            Filter::Phosphor(_) => self.glow.clone(),
+            _ => {
+                let mut pixels = vec![0; self.width * self.height];
+                for frame in self.history.iter() {
+                    for (pixel, &value) in pixels.iter_mut().zip(frame.iter()) {
+                        *pixel |= value;
+                    }
+                }
+                pixels.iter().map(|&pixel| (pixel, if pixel != 0 { 1.0 } else { 0.0 })).collect()
+            }
+        }
+    }
+
+    // What's drawn as 8-bit RGBA, for uploading as a texture. Dim pixels are
+    // blended with the background.
+    pub fn rgba(&self, palette: &Palette) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(self.width * self.height * 4);
+        for (pixel, brightness) in self.pixels() {
+            rgba.extend_from_slice(&palette.shade(pixel, brightness));
+            rgba.push(255);
+        }
+        rgba
+    }
+}
+
+// The display at its current resolution as palette indices, with each pixel
+// drawn `scale` pixels across. Returns the width, the height and the pixels.
+pub fn display_indices(cpu: &Cpu, scale: u32) -> (u32, u32, Vec<u8>) {
+    let scale = scale as usize;
+    let (columns, rows) = (cpu.display_width(), cpu.display_height());
+    let mut pixels = Vec::with_capacity(columns * rows * scale * scale);
+    for row in cpu.display().iter().take(rows) {
+        let mut line = Vec::with_capacity(columns * scale);
+        for &pixel in row.iter().take(columns) {
+            for _ in 0 .. scale {
+                line.push(pixel & 3);
+            }
+        }
+        for _ in 0 .. scale {
+            pixels.extend_from_slice(&line);
+        }
+    }
+    ((columns * scale) as u32, (rows * scale) as u32, pixels)
+}
+
+// The same as 8-bit RGB.
+pub fn display_rgb(cpu: &Cpu, scale: u32, palette: &Palette) -> (u32, u32, Vec<u8>) {
+    let (width, height, pixels) = display_indices(cpu, scale);
+    let mut rgb = Vec::with_capacity(pixels.len() * 3);
+    for &pixel in pixels.iter() {
+        rgb.extend_from_slice(&palette.colors[pixel as usize]);
+    }
+    (width, height, rgb)
+}
+
+// The display as a PNG file.
+pub fn screenshot(cpu: &Cpu, scale: u32, palette: &Palette) -> Vec<u8> {
+    let (width, height, rgb) = display_rgb(cpu, scale, palette);
+    png::encode(width, height, &rgb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quirks::Quirks;
+
+    #[test]
+    fn test_fit_exact() {
+        assert_eq!(Viewport::fit(640.0, 320.0, 64, 32, true), Viewport { x: 0.0, y: 0.0, pixel: 10.0 });
+        assert_eq!(Viewport::fit(640.0, 320.0, 128, 64, true), Viewport { x: 0.0, y: 0.0, pixel: 5.0 });
+    }
+
+    #[test]
+    fn test_fit_keeps_aspect() {
+        // A tall window gets bars above and below.
+        assert_eq!(Viewport::fit(640.0, 480.0, 64, 32, false), Viewport { x: 0.0, y: 80.0, pixel: 10.0 });
+        // A wide one gets them at the sides.
+        assert_eq!(Viewport::fit(1000.0, 320.0, 64, 32, false), Viewport { x: 180.0, y: 0.0, pixel: 10.0 });
+    }
+
+    #[test]
+    fn test_fit_integer() {
+        assert_eq!(Viewport::fit(700.0, 350.0, 64, 32, false).pixel, 10.9375);
+        assert_eq!(Viewport::fit(700.0, 350.0, 64, 32, true), Viewport { x: 30.0, y: 15.0, pixel: 10.0 });
+        assert_eq!(Viewport::fit(32.0, 16.0, 64, 32, true).pixel, 0.5);
+    }
+
+    #[test]
+    fn test_display_rgb() {
+        let mut cpu = Cpu::new(Quirks::default());
+        cpu.disp_buff[0][1] = 1;
+        let palette = Palette::parse("#000000,#FF0000").unwrap();
+        let (width, height, rgb) = display_rgb(&cpu, 2, &palette);
+        assert_eq!((width, height, rgb.len()), (128, 64, 128 * 64 * 3));
+        // The lit pixel covers columns 2 and 3 of the first two rows.
+        assert_eq!(&rgb[.. 15], &[0, 0, 0, 0, 0, 0, 255, 0, 0, 255, 0, 0, 0, 0, 0]);
+        assert_eq!(&rgb[128 * 3 .. 128 * 3 + 15], &rgb[.. 15]);
+
+        let png = screenshot(&cpu, 3, &palette);
+        assert_eq!(&png[16 .. 24], &[0, 0, 0, 192, 0, 0, 0, 96]);
+    }
+
+    #[test]
+    fn test_filter_parse() {
+        assert_eq!(Filter::parse("none"), Ok(Filter::None));
+        assert_eq!(Filter::parse("Blend"), Ok(Filter::Blend(2)));
+        assert_eq!(Filter::parse("blend:4"), Ok(Filter::Blend(4)));
+        assert_eq!(Filter::parse("phosphor:0.75"), Ok(Filter::Phosphor(0.75)));
+        assert!(Filter::parse("blend:0").is_err());
+        assert!(Filter::parse("phosphor:1.5").is_err());
+        assert!(Filter::parse("none:1").is_err());
+        assert!(Filter::parse("scanlines").is_err());
+    }
+
+    #[test]
+    fn test_blend() {
+        let mut cpu = Cpu::new(Quirks::default());
+        let mut screen = Screen::new(Filter::Blend(2));
+        cpu.disp_buff[0][0] = 1;
+        screen.push(&cpu);
+        // The sprite is erased, and drawn one pixel over.
+        cpu.disp_buff[0][0] = 0;
+        cpu.disp_buff[0][1] = 2;
+        screen.push(&cpu);
+        assert_eq!(screen.size(), (64, 32));
+        assert_eq!(&screen.pixels()[.. 3], &[(1, 1.0), (2, 1.0), (0, 0.0)]);
+        screen.push(&cpu);
+        assert_eq!(&screen.pixels()[.. 3], &[(0, 0.0), (2, 1.0), (0, 0.0)]);
+    }
+
+    #[test]
+    fn test_phosphor() {
+        let mut cpu = Cpu::new(Quirks::default());
+        let mut screen = Screen::new(Filter::Phosphor(0.5));
+        cpu.disp_buff[0][0] = 1;
+        screen.push(&cpu);
+        cpu.disp_buff[0][0] = 0;
+        screen.push(&cpu);
+        assert_eq!(screen.pixels()[0], (1, 0.5));
+        screen.push(&cpu);
+        assert_eq!(screen.pixels()[0], (1, 0.25));
+        for _ in 0 .. 4 {
+            screen.push(&cpu);
+        }
+        // Too dim to see.
+        assert_eq!(screen.pixels()[0], (0, 0.0));
+    }
+
+    #[test]
+    fn test_changed() {
+        let mut cpu = Cpu::new(Quirks::default());
+        let mut screen = Screen::new(Filter::None);
+        screen.push(&cpu);
+        assert!(screen.take_changed());
+        screen.push(&cpu);
+        assert!(!screen.take_changed());
+        cpu.disp_buff[1][2] = 1;
+        screen.push(&cpu);
+        assert!(screen.take_changed());
+        let rgba = screen.rgba(&Palette::default());
+        assert_eq!(rgba.len(), 64 * 32 * 4);
+        assert_eq!(&rgba[(64 + 2) * 4 .. (64 + 3) * 4], &[255, 255, 255, 255]);
+        assert_eq!(&rgba[.. 4], &[0, 0, 0, 255]);
+
+        // A fading pixel changes every frame until it's gone.
+        let mut screen = Screen::new(Filter::Phosphor(0.5));
+        screen.push(&cpu);
+        cpu.disp_buff[1][2] = 0;
+        screen.push(&cpu);
+        assert!(screen.take_changed());
+        assert_eq!(&screen.rgba(&Palette::default())[(64 + 2) * 4 .. (64 + 3) * 4], &[128, 128, 128, 255]);
+        for _ in 0 .. 6 {
+            screen.push(&cpu);
+        }
+        screen.take_changed();
+        screen.push(&cpu);
+        assert!(!screen.take_changed());
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+use cpu::Cpu;
+
+// Keeps the last `capacity` frames of a running cpu so it can be stepped
+// backwards. Only the newest frame is stored whole, as a save state. Each older
+// frame is stored as the difference to the frame after it, which is tiny since
+// most of memory doesn't change from one frame to the next.
+pub struct RewindBuffer {
+    capacity: usize,
+    latest: Option<Vec<u8>>,
+    // Oldest first. Applying the last delta to `latest` gives the frame before it.
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl RewindBuffer {
+    pub fn new(capacity: usize) -> RewindBuffer {
+        RewindBuffer { capacity: capacity, latest: None, deltas: VecDeque::new() }
+    }
+
+    // Records the cpu's current state as the newest frame, dropping the oldest
+    // frame if the buffer is full.
+    pub fn push(&mut self, cpu: &Cpu) {
+        let state = cpu.save_state();
+        if let Some(previous) = self.latest.take() {
+            self.deltas.push_back(encode_delta(&state, &previous));
+            if self.deltas.len() > self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.latest = Some(state);
+    }
+
+    // The number of frames that can be rewound.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+    }
+
+    // Puts the cpu back to how it was `frames` frames before the newest one, or
+    // as far back as the buffer goes. The frames rewound over are forgotten.
+    // Returns how many frames were rewound.
+    pub fn rewind(&mut self, cpu: &mut Cpu, frames: usize) -> usize {
+        let mut rewound = 0;
+        let mut loaded = true;
+        if let Some(ref mut latest) = self.latest {
+            while rewound < frames {
+                match self.deltas.pop_back() {
+                    Some(delta) => apply_delta(latest, &delta),
+                    None => break,
+                }
+                rewound += 1;
+            }
+            // The states were all made by this cpu, so this can only fail if
+            // another ROM has been loaded since.
+            loaded = cpu.load_state(latest).is_ok();
+        }
+        if !loaded {
+            self.clear();
+            return 0;
+        }
+        rewound
+    }
+}
+
+// Encodes the bytes that differ between two equal length states as runs of
+// [unchanged count: u16][changed count: u16][changed bytes XORed together].
+fn encode_delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut position = 0;
+    while position < from.len() {
+        let start = position;
+        while position < from.len() && position - start < 0xFFFF && from[position] == to[position] {
+            position += 1;
+        }
+        let unchanged = position - start;
+
+        let changed_start = position;
+        while position < from.len() && position - changed_start < 0xFFFF && from[position] != to[position] {
+            position += 1;
+        }
+        let changed = position - changed_start;
+
+        delta.push((unchanged >> 8) as u8);
+        delta.push(unchanged as u8);
+        delta.push((changed >> 8) as u8);
+        delta.push(changed as u8);
+        for i in changed_start .. position {
+            delta.push(from[i] ^ to[i]);
+        }
+    }
+    delta
+}
+
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+    let mut position = 0;
+    let mut d = 0;
+    while d + 4 <= delta.len() {
+        let unchanged = (delta[d] as usize) << 8 | delta[d + 1] as usize;
+        let changed = (delta[d + 2] as usize) << 8 | delta[d + 3] as usize;
+        d += 4;
+        position += unchanged;
+        for i in 0 .. changed {
+            state[position + i] ^= delta[d + i];
+        }
+        position += changed;
+        d += changed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quirks::Quirks;
+
+    #[test]
+    fn test_delta_round_trip() {
+        let from = vec![0, 1, 2, 3, 4, 5, 6, 7];
+        let to = vec![0, 9, 2, 3, 4, 5, 8, 8];
+        let delta = encode_delta(&from, &to);
+        let mut state = from.clone();
+        apply_delta(&mut state, &delta);
+        assert_eq!(state, to);
+    }
+
+    #[test]
+    fn test_delta_of_long_unchanged_run() {
+        let from = vec![0; 0x20000];
+        let mut to = from.clone();
+        to[0x1FFFF] = 1;
+        let delta = encode_delta(&from, &to);
+        assert!(delta.len() < 16);
+        let mut state = from.clone();
+        apply_delta(&mut state, &delta);
+        assert_eq!(state, to);
+    }
+
+    #[test]
+    fn test_rewind_frames() {
+        let mut cpu = Cpu::new(Quirks::default());
+        // ADD V0 1, JP 200
+        Cpu::load_data(&mut cpu, vec![0x70, 0x01, 0x12, 0x00]);
+        let mut rewind = RewindBuffer::new(100);
+        rewind.push(&cpu);
+        for _ in 0..5 {
+            cpu.emulate_cycle().unwrap();
+            cpu.emulate_cycle().unwrap();
+            rewind.push(&cpu);
+        }
+        assert_eq!(cpu.v(0), 5);
+        assert_eq!(rewind.len(), 5);
+
+        assert_eq!(rewind.rewind(&mut cpu, 2), 2);
+        assert_eq!(cpu.v(0), 3);
+        assert_eq!(rewind.rewind(&mut cpu, 1), 1);
+        assert_eq!(cpu.v(0), 2);
+        assert_eq!(rewind.rewind(&mut cpu, 10), 2);
+        assert_eq!(cpu.v(0), 0);
+        assert_eq!(rewind.rewind(&mut cpu, 1), 0);
+    }
+
+    #[test]
+    fn test_capacity_drops_oldest() {
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![0x70, 0x01, 0x12, 0x00]);
+        let mut rewind = RewindBuffer::new(3);
+        rewind.push(&cpu);
+        for _ in 0..5 {
+            cpu.emulate_cycle().unwrap();
+            cpu.emulate_cycle().unwrap();
+            rewind.push(&cpu);
+        }
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.rewind(&mut cpu, 10), 3);
+        assert_eq!(cpu.v(0), 2);
+    }
+}
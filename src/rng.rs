@@ -0,0 +1,133 @@
+// Where Cxkk gets its random numbers from. The generator is owned by the Cpu
+// and saved in save states, so a program given the same seed and input always
+// draws the same numbers.
+pub trait RandomSource {
+    // The next random byte.
+    fn next_byte(&mut self) -> u8;
+    // Identifies the kind of generator in save states. XorShift is 0 and
+    // VipStyleRandom is 1.
+    fn kind(&self) -> u8;
+    // The generator's complete state, for save states.
+    fn state(&self) -> u64;
+    fn set_state(&mut self, state: u64);
+}
+
+// The default generator: 64 bit xorshift*, which is fast, small and far more
+// random than any CHIP-8 program needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    pub fn new(seed: u64) -> XorShift {
+        // Xorshift gets stuck on zero, so that seed is swapped for another.
+        XorShift { state: if seed == 0 { 0x9E3779B97F4A7C15 } else { seed } }
+    }
+}
+
+impl RandomSource for XorShift {
+    fn next_byte(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545F4914F6CDD1D) >> 56) as u8
+    }
+
+    fn kind(&self) -> u8 { 0 }
+    fn state(&self) -> u64 { self.state }
+    fn set_state(&mut self, state: u64) { self.state = state; }
+}
+
+// A generator in the style of the COSMAC VIP interpreter's: it steps a pointer
+// through a table of bytes, adding each byte it reads to the last number it
+// returned. The VIP read its own interpreter code at 0x100, which this
+// emulator doesn't have, so it reads a fixed scrambled table instead. It is not
+// the VIP's generator, and doesn't give the numbers a VIP would.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VipStyleRandom {
+    pointer: u8,
+    last: u8,
+}
+
+impl VipStyleRandom {
+    // The generator only has 16 bits of state, so the seed is folded into
+    // them. Seeds below 256 just set where the pointer starts.
+    pub fn new(seed: u64) -> VipStyleRandom {
+        let folded = (seed ^ (seed >> 16) ^ (seed >> 32) ^ (seed >> 48)) as u16;
+        VipStyleRandom { pointer: folded as u8, last: (folded >> 8) as u8 }
+    }
+}
+
+impl RandomSource for VipStyleRandom {
+    fn next_byte(&mut self) -> u8 {
+        self.pointer = self.pointer.wrapping_add(1);
+        self.last = self.last.wrapping_add(table_byte(self.pointer));
+        self.last
+    }
+
+    fn kind(&self) -> u8 { 1 }
+    fn state(&self) -> u64 { (self.pointer as u64) << 8 | self.last as u64 }
+    fn set_state(&mut self, state: u64) {
+        self.pointer = (state >> 8) as u8;
+        self.last = state as u8;
+    }
+}
+
+// The byte at `offset` in the table VipStyleRandom reads.
+fn table_byte(offset: u8) -> u8 {
+    let mut x = (offset as u32 + 1).wrapping_mul(0x9E37_79B1);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x85EB_CA6B);
+    x ^= x >> 13;
+    (x >> 24) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_seed_same_numbers() {
+        let mut a = XorShift::new(1234);
+        let mut b = XorShift::new(1234);
+        let mut c = XorShift::new(4321);
+        let a_bytes: Vec<u8> = (0..16).map(|_| a.next_byte()).collect();
+        let b_bytes: Vec<u8> = (0..16).map(|_| b.next_byte()).collect();
+        let c_bytes: Vec<u8> = (0..16).map(|_| c.next_byte()).collect();
+        assert_eq!(a_bytes, b_bytes);
+        assert!(a_bytes != c_bytes);
+    }
+
+    #[test]
+    fn test_zero_seed() {
+        let mut rng = XorShift::new(0);
+        assert!((0..16).any(|_| rng.next_byte() != 0));
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut rng = XorShift::new(99);
+        rng.next_byte();
+        let mut copy = XorShift::new(1);
+        copy.set_state(rng.state());
+        assert_eq!(rng.next_byte(), copy.next_byte());
+    }
+
+    #[test]
+    fn test_vip_style_random_sums_table_bytes() {
+        let mut rng = VipStyleRandom::new(0);
+        let bytes: Vec<u8> = (0..16).map(|_| rng.next_byte()).collect();
+        assert_eq!(bytes[0], table_byte(1));
+        assert_eq!(bytes[1], table_byte(1).wrapping_add(table_byte(2)));
+        assert!(bytes.iter().any(|&byte| byte != bytes[0]));
+        assert_eq!(rng.state() >> 8, 16);
+    }
+
+    #[test]
+    fn test_vip_style_random_uses_whole_seed() {
+        assert_eq!(VipStyleRandom::new(7).state(), 7 << 8);
+        assert_ne!(VipStyleRandom::new(0x1_0000_0007).state(), VipStyleRandom::new(7).state());
+        assert_ne!(VipStyleRandom::new(0x100).state(), VipStyleRandom::new(0).state());
+    }
+}
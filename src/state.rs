@@ -0,0 +1,176 @@
+use std::error::Error;
+use std::fmt;
+
+// Every save state starts with these bytes, followed by the format version.
+pub const STATE_MAGIC: &'static [u8; 4] = b"C8ST";
+pub const STATE_VERSION: u16 = 2;
+
+// Reasons a save state can't be loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    // The data doesn't start with STATE_MAGIC, so isn't a save state.
+    BadMagic,
+    // The state was written by a version of the format this build can't read.
+    UnsupportedVersion(u16),
+    // The state was saved while running a different ROM.
+    RomMismatch { expected: u64, found: u64 },
+    // The state's random number generator is a different kind to the cpu's.
+    RngMismatch { expected: u8, found: u8 },
+    // The data ended early or has bytes left over.
+    WrongLength,
+    // The program counter or stack pointer is outside of memory or the stack.
+    BadRegisters,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) =>
+                write!(f, "unsupported save state version {}", version),
+            StateError::RomMismatch { expected, found } =>
+                write!(f, "save state is for ROM {:016X}, but ROM {:016X} is loaded", found, expected),
+            StateError::RngMismatch { expected, found } =>
+                write!(f, "save state uses random number generator {}, but the cpu uses {}", found, expected),
+            StateError::WrongLength => write!(f, "save state has the wrong length"),
+            StateError::BadRegisters => write!(f, "save state has its PC or SP out of range"),
+        }
+    }
+}
+
+impl Error for StateError {
+    fn description(&self) -> &str {
+        match *self {
+            StateError::BadMagic => "not a save state",
+            StateError::UnsupportedVersion(_) => "unsupported save state version",
+            StateError::RomMismatch { .. } => "save state is for a different ROM",
+            StateError::RngMismatch { .. } => "save state uses a different random number generator",
+            StateError::WrongLength => "save state has the wrong length",
+            StateError::BadRegisters => "save state has its PC or SP out of range",
+        }
+    }
+}
+
+// Identifies a ROM in save states. This is 64 bit FNV-1a, which is plenty to
+// tell ROMs apart and doesn't need a dependency.
+pub fn hash_rom(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Appends values to a save state. Multi-byte values are big endian, like
+// CHIP-8 itself.
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn u8(&mut self, value: u8) { self.data.push(value); }
+    pub fn bool(&mut self, value: bool) { self.data.push(value as u8); }
+    pub fn u16(&mut self, value: u16) {
+        self.data.push((value >> 8) as u8);
+        self.data.push(value as u8);
+    }
+    pub fn u32(&mut self, value: u32) {
+        self.u16((value >> 16) as u16);
+        self.u16(value as u16);
+    }
+    pub fn u64(&mut self, value: u64) {
+        self.u32((value >> 32) as u32);
+        self.u32(value as u32);
+    }
+    pub fn bytes(&mut self, values: &[u8]) { self.data.extend_from_slice(values); }
+
+    pub fn finish(self) -> Vec<u8> { self.data }
+}
+
+// Reads back values written by StateWriter, in the same order.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data: data, position: 0 }
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        let value = *self.data.get(self.position).ok_or(StateError::WrongLength)?;
+        self.position += 1;
+        Ok(value)
+    }
+    pub fn bool(&mut self) -> Result<bool, StateError> { Ok(self.u8()? != 0) }
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok((self.u8()? as u16) << 8 | self.u8()? as u16)
+    }
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok((self.u16()? as u32) << 16 | self.u16()? as u32)
+    }
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok((self.u32()? as u64) << 32 | self.u32()? as u64)
+    }
+    pub fn bytes(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        let end = self.position + out.len();
+        if end > self.data.len() {
+            return Err(StateError::WrongLength);
+        }
+        out.copy_from_slice(&self.data[self.position .. end]);
+        self.position = end;
+        Ok(())
+    }
+
+    // Checks that every byte has been read.
+    pub fn finish(self) -> Result<(), StateError> {
+        if self.position == self.data.len() { Ok(()) } else { Err(StateError::WrongLength) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_values_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.u8(0x12);
+        writer.bool(true);
+        writer.u16(0x3456);
+        writer.u32(0x789ABCDE);
+        writer.u64(0x0123456789ABCDEF);
+        writer.bytes(&[1, 2, 3]);
+        let data = writer.finish();
+        assert_eq!(&data[2..4], &[0x34, 0x56]);
+
+        let mut reader = StateReader::new(&data);
+        assert_eq!(reader.u8(), Ok(0x12));
+        assert_eq!(reader.bool(), Ok(true));
+        assert_eq!(reader.u16(), Ok(0x3456));
+        assert_eq!(reader.u32(), Ok(0x789ABCDE));
+        assert_eq!(reader.u64(), Ok(0x0123456789ABCDEF));
+        let mut bytes = [0; 3];
+        reader.bytes(&mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2, 3]);
+        assert_eq!(reader.finish(), Ok(()));
+    }
+
+    #[test]
+    fn test_reading_past_end() {
+        let mut reader = StateReader::new(&[1]);
+        assert_eq!(reader.u16(), Err(StateError::WrongLength));
+    }
+
+    #[test]
+    fn test_hash_rom() {
+        assert_eq!(hash_rom(&[]), 0xcbf29ce484222325);
+        assert!(hash_rom(&[0x00, 0xE0]) != hash_rom(&[0x00, 0xEE]));
+    }
+}
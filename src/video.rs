@@ -0,0 +1,206 @@
+// Recording the display, one frame for each 60Hz frame run, as an animated GIF
+// or as raw frames to pipe into a video encoder.
+use std::io::{self, Write};
+use cpu::{Cpu, DISPLAY_WIDTH};
+use gif::GifWriter;
+use palette::Palette;
+use render;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoFormat {
+    Gif,
+    // 24-bit RGB frames one after another, with nothing in between, the way
+    // `ffmpeg -f rawvideo -pixel_format rgb24` reads them.
+    Raw,
+}
+
+impl VideoFormat {
+    // GIF for a file ending in .gif, and raw frames for anything else.
+    pub fn from_path(path: &str) -> VideoFormat {
+        if path.to_lowercase().ends_with(".gif") { VideoFormat::Gif } else { VideoFormat::Raw }
+    }
+}
+
+enum Output {
+    // GIF delays are in hundredths of a second, which 60Hz frames don't fit,
+    // and most viewers slow down frames shorter than 2/100, so a frame is held
+    // back until the next different one, to work out how long it was shown.
+    // Changes quicker than that replace it.
+    Gif { gif: GifWriter<Box<dyn Write>>, pending: Option<(Vec<u8>, u64)> },
+    Raw(Box<dyn Write>),
+}
+
+pub struct VideoRecorder {
+    output: Output,
+    // Image pixels per high resolution pixel. Frames are always the size of
+    // the high resolution display, so low resolution pixels are doubled.
+    scale: u32,
+    palette: Palette,
+    frames: u64,
+}
+
+impl VideoRecorder {
+    pub fn new(writer: Box<dyn Write>, format: VideoFormat, scale: u32, palette: Palette) -> io::Result<VideoRecorder> {
+        let output = match format {
+            VideoFormat::Gif => {
+                let (width, height) = frame_size(scale);
+                let gif = GifWriter::new(writer, width as u16, height as u16, &palette.colors)?;
+                Output::Gif { gif: gif, pending: None }
+            }
+            VideoFormat::Raw => Output::Raw(writer),
+        };
+        Ok(VideoRecorder { output: output, scale: scale, palette: palette, frames: 0 })
+    }
+
+    // The width and height of the frames.
+    pub fn size(&self) -> (u32, u32) {
+        frame_size(self.scale)
+    }
+
+    // The number of frames recorded so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // Records the display as it is at the end of a frame.
+    pub fn record_frame(&mut self, cpu: &Cpu) -> io::Result<()> {
+        let scale = self.scale * (DISPLAY_WIDTH / cpu.display_width()) as u32;
+        let frame = self.frames;
+        self.frames += 1;
+        match self.output {
+            Output::Raw(ref mut writer) => {
+                let (_, _, rgb) = render::display_rgb(cpu, scale, &self.palette);
+                writer.write_all(&rgb)
+            }
+            Output::Gif { ref mut gif, ref mut pending } => {
+                let (_, _, pixels) = render::display_indices(cpu, scale);
+                match pending.take() {
+                    None => *pending = Some((pixels, frame)),
+                    Some((shown, start)) => {
+                        if shown == pixels || centiseconds(frame) - centiseconds(start) < 2 {
+                            // Hold the frame longer, or replace it.
+                            *pending = Some((pixels, start));
+                        } else {
+                            write_gif_frame(gif, &shown, centiseconds(frame) - centiseconds(start))?;
+                            *pending = Some((pixels, frame));
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Writes the last frame and the end of the file.
+    pub fn finish(self) -> io::Result<()> {
+        match self.output {
+            Output::Raw(mut writer) => writer.flush(),
+            Output::Gif { mut gif, pending } => {
+                if let Some((shown, start)) = pending {
+                    let delay = centiseconds(self.frames) - centiseconds(start);
+                    write_gif_frame(&mut gif, &shown, delay.max(2))?;
+                }
+                gif.finish().map(|_| ())
+            }
+        }
+    }
+}
+
+fn frame_size(scale: u32) -> (u32, u32) {
+    (128 * scale, 64 * scale)
+}
+
+// When a 60Hz frame starts, in hundredths of a second.
+fn centiseconds(frame: u64) -> u64 {
+    frame * 100 / 60
+}
+
+// Writes a frame, split up if it's shown for longer than a GIF delay can be.
+fn write_gif_frame(gif: &mut GifWriter<Box<dyn Write>>, pixels: &[u8], mut delay: u64) -> io::Result<()> {
+    while delay > 0xFFFF {
+        gif.frame(pixels, 0xFFFF)?;
+        delay -= 0xFFFF;
+    }
+    gif.frame(pixels, delay as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quirks::Quirks;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // A writer that can still be read after the recorder has taken it.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(data);
+            Ok(data.len())
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(VideoFormat::from_path("run.GIF"), VideoFormat::Gif);
+        assert_eq!(VideoFormat::from_path("run.rgb"), VideoFormat::Raw);
+        assert_eq!(VideoFormat::from_path("-"), VideoFormat::Raw);
+    }
+
+    #[test]
+    fn test_raw() {
+        let out = Shared::default();
+        let mut recorder = VideoRecorder::new(Box::new(out.clone()), VideoFormat::Raw, 1, Palette::default()).unwrap();
+        let mut cpu = Cpu::new(Quirks::default());
+        Cpu::load_data(&mut cpu, vec![0x00, 0xFF]);
+        cpu.disp_buff[0][0] = 1;
+        recorder.record_frame(&cpu).unwrap();
+        // 00FF switches to high resolution.
+        cpu.emulate_cycle().unwrap();
+        cpu.disp_buff[0][0] = 1;
+        recorder.record_frame(&cpu).unwrap();
+        recorder.finish().unwrap();
+        let data = out.0.borrow();
+        // Both frames are the same size, with low resolution pixels doubled.
+        let frame = 128 * 64 * 3;
+        assert_eq!(data.len(), 2 * frame);
+        assert_eq!(&data[.. 9], &[255, 255, 255, 255, 255, 255, 0, 0, 0]);
+        assert_eq!(&data[128 * 3 .. 128 * 3 + 9], &data[.. 9]);
+        assert_eq!(&data[frame .. frame + 6], &[255, 255, 255, 0, 0, 0]);
+    }
+
+    // The delays of the frames in a GIF, from their graphic control extensions.
+    fn delays(data: &[u8]) -> Vec<u16> {
+        let mut delays = Vec::new();
+        for i in 0 .. data.len() - 8 {
+            if data[i .. i + 4] == [0x21, 0xF9, 4, 0] && data[i + 6 .. i + 8] == [0, 0] {
+                delays.push(data[i + 4] as u16 | (data[i + 5] as u16) << 8);
+            }
+        }
+        delays
+    }
+
+    #[test]
+    fn test_gif_timing() {
+        let out = Shared::default();
+        let mut recorder = VideoRecorder::new(Box::new(out.clone()), VideoFormat::Gif, 1, Palette::default()).unwrap();
+        let mut cpu = Cpu::new(Quirks::default());
+        // A second of one frame, then a change lasting only one frame, which
+        // is replaced by the next, then half a second more.
+        for _ in 0 .. 60 {
+            recorder.record_frame(&cpu).unwrap();
+        }
+        cpu.disp_buff[0][0] = 1;
+        recorder.record_frame(&cpu).unwrap();
+        cpu.disp_buff[0][1] = 1;
+        for _ in 0 .. 30 {
+            recorder.record_frame(&cpu).unwrap();
+        }
+        assert_eq!(recorder.frames(), 91);
+        recorder.finish().unwrap();
+        assert_eq!(delays(&out.0.borrow()), vec![100, 51]);
+    }
+}